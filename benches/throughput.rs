@@ -0,0 +1,80 @@
+//! End to end throughput (criterion): a synthetic csv file of N transactions is parsed
+//! & processed by [Accounts], throughput is reported in transactions per second
+//!
+//! `cargo bench --features strategies` (N: 100 000, or the `BENCH_TRANSACTIONS` env variable),
+//! then e.g. `cargo bench --features strategies -- --save-baseline main` & `--baseline main`
+//! to compare with a previous run
+
+use std::env;
+use std::iter;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use proptest::strategy::{Strategy, ValueTree};
+use proptest::test_runner::TestRunner;
+
+use homework_toy_pay::accounts::Accounts;
+use homework_toy_pay::csv_reader::{write_transactions, CsvReader, Transaction};
+use homework_toy_pay::strategies::{self, TransactionsBuilder};
+
+/// Default number of generated transactions (see `BENCH_TRANSACTIONS`)
+const TRANSACTIONS: usize = 100_000;
+
+/// Generate a csv file of `count` transactions over `clients` clients (see [strategies::step],
+/// deterministic RNG): mostly deposits & withdrawals, a few disputes, resolves & chargebacks
+fn generate_csv(count: usize, clients: u16) -> Vec<u8> {
+    let mut runner = TestRunner::deterministic();
+    let step = strategies::step(clients);
+    let mut builder = TransactionsBuilder::new(clients);
+    let transactions: Vec<Transaction> = iter::repeat_with(|| {
+        step.new_tree(&mut runner)
+            .expect("Cannot generate a transaction")
+            .current()
+    })
+    .filter_map(|step| builder.build(step))
+    .take(count)
+    .collect();
+
+    let mut csv: Vec<u8> = Vec::new();
+    write_transactions(&transactions, &mut csv).expect("Cannot write csv");
+    csv
+}
+
+fn throughput(c: &mut Criterion) {
+    let count = env::var("BENCH_TRANSACTIONS")
+        .ok()
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(TRANSACTIONS);
+    let csv = generate_csv(count, 10_000);
+
+    let mut group = c.benchmark_group("throughput");
+    group.throughput(Throughput::Elements(count as u64));
+    // A run takes a while (whole csv file): fewer samples than the default 100
+    group.sample_size(10);
+
+    group.bench_function("csv parsing", |b| {
+        b.iter(|| {
+            let csv_reader = CsvReader::from_reader(csv.as_slice()).expect("Invalid csv");
+            assert_eq!(csv_reader.count(), count);
+        })
+    });
+
+    for (case, max_retained_tx) in [
+        ("processing", None),
+        ("processing (retain 10 000)", Some(10_000)),
+    ] {
+        group.bench_function(case, |b| {
+            b.iter(|| {
+                let mut accounts = Accounts::builder().max_retained_tx(max_retained_tx).build();
+                for transaction in CsvReader::from_reader(csv.as_slice()).expect("Invalid csv") {
+                    let _ = accounts.handle_transaction(transaction.expect("Invalid csv record"));
+                }
+                black_box(accounts)
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, throughput);
+criterion_main!(benches);
@@ -1,14 +1,16 @@
 // std
-use std::collections::HashMap;
-use std::io::Write;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, Read, Write};
+use std::str::FromStr;
 use std::{error, fmt};
 // third party libs
-use serde::Serialize;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize, Serializer};
 // internal
-use crate::csv_reader::{Transaction, TransactionKind};
+use crate::csv_reader::{Currency, Transaction, TransactionKind};
 
 /// An account for a client
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
 struct Account {
     available: f64,
     held: f64,
@@ -25,25 +27,84 @@ impl Account {
             locked: false,
         }
     }
+
+    /// Check accounting identity: available + held == total
+    /// (with a relative tolerance for f64 rounding errors)
+    fn validate(&self) -> Result<(), String> {
+        let diff = (self.available + self.held - self.total).abs();
+        if diff > ACCOUNT_TOLERANCE * self.total.abs().max(1.0) || diff.is_nan() {
+            return Err(format!(
+                "available ({}) + held ({}) != total ({})",
+                self.available, self.held, self.total
+            ));
+        }
+        Ok(())
+    }
+
+    /// No funds at all & not locked (e.g. only created by a rejected transaction)
+    fn is_empty(&self) -> bool {
+        self.available == 0.0 && self.held == 0.0 && self.total == 0.0 && !self.locked
+    }
 }
 
 /// An error retrieved via [Accounts::handle_transaction]
 #[derive(Debug, Clone)]
 pub enum TransactionError {
-    /// Client is unknown (this should never happen)
+    /// Client is unknown (e.g. a withdrawal before any deposit,
+    /// see [AccountsBuilder::require_known_client])
     UnknownClient(u16),
     /// Transaction is unknown (e.g. a Dispute with an unknown tx)
     UnknownTransaction(u32),
-    /// Invalid amount (e.g. a deposit with infinite amount)
+    /// Invalid amount (e.g. a deposit with a negative or infinite amount)
     InvalidAmount(f64),
     /// Account has reached the f64 limits (should never happen?)
     AccountAmountTooLarge,
     /// Reject a resolve / chargeback transaction because it is not disputed
     TxNonDisputed(u32),
-    /// Account is locked thus cannot deposit nor withdraw
+    /// Account is locked (after a chargeback) thus cannot accept any other transaction:
+    /// deposit, withdrawal, but also dispute, resolve or chargeback (of any of its tx)
     AccountLocked(u16),
     /// Invalid transaction (e.g. non unique tx?)
     InvalidTransaction(u32),
+    /// Amount is required (e.g. a deposit without amount)
+    MissingAmount(u32),
+    /// Reject a dispute on a withdrawal (strict mode only)
+    CannotDisputeWithdrawal(u32),
+    /// Not enough available funds (e.g. to reverse a deposit)
+    InsufficientFunds(u32),
+    /// Transaction has been reversed (thus cannot be disputed nor reversed again)
+    TxReversed(u32),
+    /// Reject a dispute older than the transaction it references (timestamps)
+    OutOfOrder(u32),
+    /// Reject a dispute (or resolve, chargeback, reversal) in another currency than its transaction
+    CurrencyMismatch(u32),
+    /// Reject a dispute (or resolve, chargeback) on a non Deposit transaction
+    /// (see [AccountsBuilder::dispute_withdrawals]), including the tx id of a Fee or Close
+    /// or a tx id only seen so far in dispute, resolve or chargeback rows (e.g. a dispute of
+    /// a resolve's tx id), unless [AccountsBuilder::max_retained_tx] is set
+    NonDisputableKind(u32),
+    /// Reject a deposit exceeding the configured limits
+    /// (see [AccountsBuilder::max_transaction_amount] & [AccountsBuilder::max_account_total])
+    AmountExceedsLimit(u32),
+    /// Reject a chargeback that would drive the account total below zero
+    /// (e.g. a disputed deposit whose funds have been withdrawn in the meantime)
+    NegativeTotal(u32),
+    /// Account has been closed thus cannot accept any transaction
+    AccountClosed(u16),
+    /// Reject the close of an account with remaining (available or held) funds
+    AccountNotEmpty(u16),
+    /// Reject a deposit or withdrawal with a (non zero) amount below the configured minimum
+    /// (see [AccountsBuilder::min_amount])
+    BelowMinimum(f64),
+    /// Reject a dispute exceeding the max number of open disputes of a client
+    /// (see [AccountsBuilder::max_open_disputes])
+    TooManyDisputes(u16),
+    /// Reject a zero (or -0) amount, e.g. a deposit or withdrawal with a present but zero amount
+    ZeroAmount(u32),
+    /// Reject a dispute of a transaction already under dispute
+    TxAlreadyDisputed(u32),
+    /// Reject a dispute (or resolve, chargeback, reversal) of a transaction of another client
+    ClientMismatch(u32),
 }
 
 impl fmt::Display for TransactionError {
@@ -70,603 +131,5946 @@ impl fmt::Display for TransactionError {
             TransactionError::InvalidTransaction(tx) => {
                 write!(f, "Invalid or non unique transaction (tx: {})", tx)
             }
+            TransactionError::MissingAmount(tx) => {
+                write!(f, "Missing amount (tx: {})", tx)
+            }
+            TransactionError::CannotDisputeWithdrawal(tx) => {
+                write!(f, "Cannot dispute a withdrawal (tx: {})", tx)
+            }
+            TransactionError::InsufficientFunds(tx) => {
+                write!(f, "Insufficient available funds (tx: {})", tx)
+            }
+            TransactionError::TxReversed(tx) => {
+                write!(f, "Transaction {} has been reversed", tx)
+            }
+            TransactionError::OutOfOrder(tx) => {
+                write!(f, "Dispute is older than transaction {}", tx)
+            }
+            TransactionError::CurrencyMismatch(tx) => {
+                write!(f, "Currency differs from transaction {}", tx)
+            }
+            TransactionError::NonDisputableKind(tx) => {
+                write!(f, "Transaction {} cannot be disputed (not a deposit)", tx)
+            }
+            TransactionError::AmountExceedsLimit(tx) => {
+                write!(f, "Transaction {} exceeds amount limits", tx)
+            }
+            TransactionError::NegativeTotal(tx) => {
+                write!(
+                    f,
+                    "Chargeback of transaction {} would make total negative",
+                    tx
+                )
+            }
+            TransactionError::AccountClosed(c) => {
+                write!(f, "Account is closed (client id: {})", c)
+            }
+            TransactionError::AccountNotEmpty(c) => {
+                write!(f, "Cannot close a non empty account (client id: {})", c)
+            }
+            TransactionError::BelowMinimum(a) => {
+                write!(f, "Amount is below the minimum: {}", a)
+            }
+            TransactionError::TooManyDisputes(c) => {
+                write!(f, "Too many open disputes (client id: {})", c)
+            }
+            TransactionError::ZeroAmount(tx) => {
+                write!(f, "Amount is zero (tx: {})", tx)
+            }
+            TransactionError::TxAlreadyDisputed(tx) => {
+                write!(f, "Transaction {} is already disputed", tx)
+            }
+            TransactionError::ClientMismatch(tx) => {
+                write!(f, "Transaction {} belongs to another client", tx)
+            }
         }
     }
 }
 
 impl error::Error for TransactionError {}
 
-/// An opaque data holding all accounts information
-pub struct Accounts {
-    inner: HashMap<u16, Account>,  // k: client id, v: Account data
-    tx: HashMap<u32, Transaction>, // k: tx (aka transaction IDs), v: Transaction struct
+/// Default number of decimal places of amounts in [Accounts::output_as_csv]
+const AMOUNT_DECIMALS: usize = 4;
+
+/// Max number of decimal places of amounts (see [Accounts::set_scale])
+pub const MAX_AMOUNT_SCALE: usize = 12;
+
+/// Relative tolerance used to detect a tie (e.g. 0.00005 at scale 4) in [RoundingMode::round]
+const ROUNDING_TIE_TOLERANCE: f64 = 1e-9;
+
+/// Relative tolerance used by [Accounts::validate]
+const ACCOUNT_TOLERANCE: f64 = 1e-9;
+
+/// Flush [Accounts::output_as_csv] output every N account lines
+const OUTPUT_FLUSH_LINES: usize = 1024;
+
+/// Csv header of [Accounts::output_as_csv] (fields of an account line)
+const ACCOUNT_CSV_FIELDS: [&str; 7] = [
+    "client",
+    "currency",
+    "available",
+    "held",
+    "total",
+    "locked",
+    "disputed_count",
+];
+
+/// Csv header line of [Accounts::output_as_csv] (without line ending), e.g. to check an output
+pub const ACCOUNT_CSV_HEADER: &str = "client,currency,available,held,total,locked,disputed_count";
+
+/// Effect of a transaction on its client account (see [Accounts::handle_transaction_with_outcome])
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransactionOutcome {
+    pub client: u16,
+    pub currency: Option<Currency>,
+    pub kind: TransactionKind,
+    /// change of available amount (new minus previous)
+    pub available_delta: f64,
+    /// change of held amount (new minus previous)
+    pub held_delta: f64,
+    /// change of total amount (new minus previous)
+    pub total_delta: f64,
+    /// new available amount
+    pub available: f64,
+    /// new held amount
+    pub held: f64,
+    /// new total amount
+    pub total: f64,
+    /// new locked state
+    pub locked: bool,
+    /// identical replay of an already applied transaction (thus skipped, nothing changed)
+    pub replay: bool,
 }
 
-impl Accounts {
-    pub fn new() -> Self {
+impl TransactionOutcome {
+    fn new(
+        key: AccountKey,
+        kind: TransactionKind,
+        before: &Account,
+        after: &Account,
+        replay: bool,
+    ) -> Self {
         Self {
-            inner: HashMap::new(),
-            tx: HashMap::new(),
+            client: key.0,
+            currency: key.1,
+            kind,
+            available_delta: after.available - before.available,
+            held_delta: after.held - before.held,
+            total_delta: after.total - before.total,
+            available: after.available,
+            held: after.held,
+            total: after.total,
+            locked: after.locked,
+            replay,
         }
     }
+}
 
-    #[doc(hidden)]
-    fn add_client(&mut self, client_id: u16) {
+/// Human readable account summary (see [Accounts::report])
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AccountReport {
+    pub client: u16,
+    pub currency: Option<Currency>,
+    #[serde(serialize_with = "serialize_amount")]
+    pub available: f64,
+    #[serde(serialize_with = "serialize_amount")]
+    pub held: f64,
+    #[serde(serialize_with = "serialize_amount")]
+    pub total: f64,
+    pub locked: bool,
+    /// number of (retained) deposits & withdrawals
+    pub tx_count: usize,
+    /// number of transactions currently under dispute
+    pub disputed_count: usize,
+}
 
-        self.inner
-            .entry(client_id)
-            .or_insert_with(|| Account::new());
+impl AccountReport {
+    /// Column titles, aligned with the [fmt::Display] output of a report line
+    pub fn header() -> String {
+        format!(
+            "{:>6} {:>8} {:>16} {:>16} {:>16} {:>6} {:>8} {:>8}",
+            "client", "currency", "available", "held", "total", "locked", "tx", "disputed"
+        )
     }
 
-    #[doc(hidden)]
-    fn get_client_account(&self, client_id: u16) -> Option<&Account> {
-        self.inner.get(&client_id)
+    /// Any dispute still open (neither resolved nor charged back)?
+    pub fn has_open_dispute(&self) -> bool {
+        self.disputed_count > 0
     }
+}
 
-    #[doc(hidden)]
-    fn try_get_client_account(&mut self, client_id: u16) -> Result<&mut Account, TransactionError> {
-        self.inner
-            .get_mut(&client_id)
-            .ok_or(TransactionError::UnknownClient(client_id))
+impl fmt::Display for AccountReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:>6} {:>8} {:>16.*} {:>16.*} {:>16.*} {:>6} {:>8} {:>8}",
+            self.client,
+            self.currency.map(|c| c.to_string()).unwrap_or_default(),
+            AMOUNT_DECIMALS,
+            self.available,
+            AMOUNT_DECIMALS,
+            self.held,
+            AMOUNT_DECIMALS,
+            self.total,
+            self.locked,
+            self.tx_count,
+            self.disputed_count
+        )
     }
+}
 
-    #[doc(hidden)]
-    fn get_transaction(&self, tx: u32) -> Option<&Transaction> {
-        self.tx.get(&tx)
+/// Platform totals over all accounts (see [Accounts::aggregate])
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Aggregate {
+    pub available: f64,
+    pub held: f64,
+    pub total: f64,
+    /// number of distinct clients
+    pub clients: usize,
+    /// number of locked accounts
+    pub locked_count: usize,
+    /// see [Accounts::orphan_disputes]
+    pub orphan_disputes: usize,
+}
+
+impl fmt::Display for Aggregate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "clients: {}, locked: {}, available: {:.*}, held: {:.*}, total: {:.*}, orphan disputes: {}",
+            self.clients,
+            self.locked_count,
+            AMOUNT_DECIMALS,
+            self.available,
+            AMOUNT_DECIMALS,
+            self.held,
+            AMOUNT_DECIMALS,
+            self.total,
+            self.orphan_disputes
+        )
     }
+}
 
-    #[doc(hidden)]
-    fn get_transaction_mut(&mut self, tx: u32) -> Option<&mut Transaction> {
-        self.tx.get_mut(&tx)
+/// A retained transaction with its dispute / reversal state (see [AccountsSnapshot])
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+struct TransactionState {
+    transaction: Transaction,
+    under_dispute: bool,
+    disputed_amount: Option<f64>,
+    reversed: bool,
+}
+
+/// State of [Accounts] (balances, locks, retained tx & open disputes) to resume processing later
+///
+/// Behavior options and history are not part of the snapshot (see [Accounts::restore])
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct AccountsSnapshot {
+    accounts: Vec<(AccountKey, Account)>, // sorted by client id (then currency)
+    transactions: Vec<TransactionState>,  // sorted by tx (then client id)
+    tx_order: Vec<TxKey>,                 // only used with max_retained_tx
+    overdrafts: Vec<(u16, f64)>,          // sorted by client id
+    #[serde(default)]
+    closed: Vec<AccountKey>, // sorted by client id (then currency)
+    #[serde(default)]
+    finalized: Vec<(TxKey, TransactionKind)>, // sorted by tx key, only used with idempotent
+}
+
+/// Kind of a row of a csv state file (see [AccountsSnapshot::write_csv])
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum StateRecord {
+    Account,
+    Tx,
+    Order,
+    Overdraft,
+    Closed,
+    Finalized,
+}
+
+/// A row of a csv state file: fields not relevant to its record kind are empty
+#[derive(Debug, Deserialize, Serialize)]
+struct StateRow {
+    record: StateRecord,
+    client: u16,
+    currency: Option<Currency>,
+    tx: Option<u32>,
+    #[serde(rename = "type")]
+    kind: Option<TransactionKind>,
+    amount: Option<f64>,
+    timestamp: Option<u64>,
+    available: Option<f64>,
+    held: Option<f64>,
+    total: Option<f64>,
+    locked: Option<bool>,
+    under_dispute: Option<bool>,
+    disputed_amount: Option<f64>,
+    reversed: Option<bool>,
+    limit: Option<f64>,
+}
+
+impl StateRow {
+    fn new(record: StateRecord, client: u16) -> Self {
+        Self {
+            record,
+            client,
+            currency: None,
+            tx: None,
+            kind: None,
+            amount: None,
+            timestamp: None,
+            available: None,
+            held: None,
+            total: None,
+            locked: None,
+            under_dispute: None,
+            disputed_amount: None,
+            reversed: None,
+            limit: None,
+        }
     }
 
-    /// Generate csv for all accounts (header: client, available, held, total, locked)
-    pub fn output_as_csv<W>(&self, into: Option<&mut W>) -> Result<(), csv::Error>
-    where
-        W: Write,
-    {
-        #[derive(Debug, Serialize)]
-        struct AccountLine {
-            client: u16,
-            available: f64,
-            held: f64,
-            total: f64,
-            locked: bool,
+    /// Value of a field required by the record kind
+    fn required<T>(&self, value: Option<T>, field: &str) -> Result<T, csv::Error> {
+        value.ok_or_else(|| {
+            csv::Error::from(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "state: missing {} in {:?} row (client id: {})",
+                    field, self.record, self.client
+                ),
+            ))
+        })
+    }
+}
+
+impl AccountsSnapshot {
+    /// Write the snapshot as csv (1 row per account, retained tx, ...), e.g. to a state file
+    /// to resume processing in a later run (see [AccountsSnapshot::read_csv])
+    ///
+    /// Amounts are written at full precision (not rounded like in [Accounts::output_as_csv])
+    pub fn write_csv<W: Write>(&self, into: &mut W) -> Result<(), csv::Error> {
+        let mut wtr = csv::Writer::from_writer(into);
+        for (key, account) in &self.accounts {
+            let mut row = StateRow::new(StateRecord::Account, key.0);
+            row.currency = key.1;
+            row.available = Some(account.available);
+            row.held = Some(account.held);
+            row.total = Some(account.total);
+            row.locked = Some(account.locked);
+            wtr.serialize(row)?;
+        }
+        for state in &self.transactions {
+            let t = &state.transaction;
+            let mut row = StateRow::new(StateRecord::Tx, t.client);
+            row.currency = t.currency;
+            row.tx = Some(t.tx);
+            row.kind = Some(t.kind);
+            row.amount = t.amount;
+            row.timestamp = t.timestamp;
+            row.under_dispute = Some(state.under_dispute);
+            row.disputed_amount = state.disputed_amount;
+            row.reversed = Some(state.reversed);
+            wtr.serialize(row)?;
         }
+        for (client_id, tx) in &self.tx_order {
+            let mut row = StateRow::new(StateRecord::Order, *client_id);
+            row.tx = Some(*tx);
+            wtr.serialize(row)?;
+        }
+        for (client_id, limit) in &self.overdrafts {
+            let mut row = StateRow::new(StateRecord::Overdraft, *client_id);
+            row.limit = Some(*limit);
+            wtr.serialize(row)?;
+        }
+        for key in &self.closed {
+            let mut row = StateRow::new(StateRecord::Closed, key.0);
+            row.currency = key.1;
+            wtr.serialize(row)?;
+        }
+        for ((client_id, tx), kind) in &self.finalized {
+            let mut row = StateRow::new(StateRecord::Finalized, *client_id);
+            row.tx = Some(*tx);
+            row.kind = Some(*kind);
+            wtr.serialize(row)?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
 
-        impl AccountLine {
-            fn from_account(client: u16, account: &Account) -> Self {
-                // Create a AccountLine from a client id and its account
-                Self {
-                    client,
-                    available: account.available,
-                    held: account.held,
-                    total: account.total,
-                    locked: account.locked,
+    /// Read a snapshot written by [AccountsSnapshot::write_csv]
+    /// (an empty input is an empty snapshot, an account must appear in at most 1 row)
+    pub fn read_csv<R: Read>(from: R) -> Result<Self, csv::Error> {
+        let mut snapshot = AccountsSnapshot {
+            accounts: vec![],
+            transactions: vec![],
+            tx_order: vec![],
+            overdrafts: vec![],
+            closed: vec![],
+            finalized: vec![],
+        };
+
+        for row in csv::Reader::from_reader(from).deserialize() {
+            let row: StateRow = row?;
+            match row.record {
+                StateRecord::Account => {
+                    let account = Account {
+                        available: row.required(row.available, "available")?,
+                        held: row.required(row.held, "held")?,
+                        total: row.required(row.total, "total")?,
+                        locked: row.required(row.locked, "locked")?,
+                    };
+                    snapshot
+                        .accounts
+                        .push(((row.client, row.currency), account));
+                }
+                StateRecord::Tx => {
+                    let mut transaction = Transaction::new(
+                        row.required(row.kind, "type")?,
+                        row.client,
+                        row.required(row.tx, "tx")?,
+                        row.amount,
+                    );
+                    transaction.timestamp = row.timestamp;
+                    transaction.currency = row.currency;
+                    transaction.under_dispute = row.required(row.under_dispute, "under_dispute")?;
+                    transaction.disputed_amount = row.disputed_amount;
+                    transaction.reversed = row.required(row.reversed, "reversed")?;
+                    snapshot.transactions.push(TransactionState {
+                        under_dispute: transaction.under_dispute,
+                        disputed_amount: transaction.disputed_amount,
+                        reversed: transaction.reversed,
+                        transaction,
+                    });
+                }
+                StateRecord::Order => {
+                    snapshot
+                        .tx_order
+                        .push((row.client, row.required(row.tx, "tx")?));
+                }
+                StateRecord::Overdraft => {
+                    snapshot
+                        .overdrafts
+                        .push((row.client, row.required(row.limit, "limit")?));
+                }
+                StateRecord::Closed => {
+                    snapshot.closed.push((row.client, row.currency));
+                }
+                StateRecord::Finalized => {
+                    snapshot.finalized.push((
+                        (row.client, row.required(row.tx, "tx")?),
+                        row.required(row.kind, "type")?,
+                    ));
                 }
             }
         }
+        check_unique_accounts(snapshot.accounts.iter().map(|(key, _)| *key))?;
+        Ok(snapshot)
+    }
+}
 
-        let mut wtr = csv::Writer::from_writer(into.unwrap());
+/// Behavior toggles of [Accounts] (see [AccountsBuilder])
+#[derive(Debug, Clone, Default)]
+struct AccountsOptions {
+    max_retained_tx: Option<usize>, // max number of tx kept in memory (None: no limit)
+    strict: bool,                   // reject disputes on withdrawals
+    dispute_withdrawals: bool,      // withdrawals can be disputed (default: deposits only)
+    scale: Option<usize>,           // decimal places in csv output (None: AMOUNT_DECIMALS)
+    per_client_tx: bool,            // tx ids are only unique per client
+    rounding: RoundingMode,         // rounding of amounts in csv output
+    max_transaction_amount: Option<f64>, // max amount of a deposit (None: no limit)
+    max_account_total: Option<f64>, // max total of an account after a deposit (None: no limit)
+    require_known_client: bool,     // only a deposit creates an account
+    min_amount: Option<f64>,        // min amount of a deposit or withdrawal (None: no minimum)
+    auto_dispute_on_chargeback: bool, // a chargeback implies the dispute of its transaction
+    quote_style: QuoteStyle,        // quoting of fields in csv output
+    minor_units: bool,              // amounts in csv output as integers (10^-scale units)
+    max_open_disputes: Option<u32>, // max open disputes per client (None: no limit)
+    chargeback_on_locked: bool,     // a locked account accepts a chargeback of an open dispute
+    freeze_on_chargeback: bool,     // a chargeback freezes every account of its client
+    tx_id_scope: TxIdScope,         // scope of deposit & withdrawal tx ids uniqueness
+    crlf: bool,                     // csv output lines end with CRLF (default: LF)
+    metadata_columns: Vec<String>,  // extra csv columns echoed in output (per client)
+    idempotent: bool,               // a duplicated resolve / chargeback is a replay
+    suppress_empty: bool,           // empty (zero, non locked) accounts are not in csv output
+}
 
-        let res: Result<Vec<()>, csv::Error> = self
-            .inner
-            .iter()
-            .map(|(client, a)| wtr.serialize(AccountLine::from_account(*client, a)))
-            .collect();
+/// Scope in which a Deposit or Withdrawal tx id must be unique (see [AccountsBuilder::tx_id_scope])
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TxIdScope {
+    /// Unique over the whole run (all input files)
+    #[default]
+    Global,
+    /// Unique in an input file only (see [Accounts::start_file])
+    File,
+}
 
-        res?;
-        wtr.flush()?;
-        Ok(())
+impl FromStr for TxIdScope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "global" => Ok(TxIdScope::Global),
+            "file" => Ok(TxIdScope::File),
+            _ => Err(format!("Invalid tx id scope: {}", s)),
+        }
     }
+}
 
-    /// Handle a transaction, returning a [TransactionError] if it fails
-    pub fn handle_transaction(&mut self, transaction: Transaction) -> Result<(), TransactionError> {
+/// Quoting of fields in csv output (see [AccountsBuilder::quote_style])
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QuoteStyle {
+    /// Quote a field only if required (e.g. it contains a comma), the csv crate default
+    #[default]
+    Necessary,
+    /// Quote every field
+    Always,
+    /// Never quote a field (output may then be invalid csv)
+    Never,
+}
 
-        self.inner
-            .entry(transaction.client)
-            .or_insert_with(|| Account::new());
+impl QuoteStyle {
+    #[doc(hidden)]
+    fn to_csv(self) -> csv::QuoteStyle {
+        match self {
+            QuoteStyle::Necessary => csv::QuoteStyle::Necessary,
+            QuoteStyle::Always => csv::QuoteStyle::Always,
+            QuoteStyle::Never => csv::QuoteStyle::Never,
+        }
+    }
+}
 
-        let amount = get_amount(&transaction)?;
+impl FromStr for QuoteStyle {
+    type Err = String;
 
-        match transaction.kind {
-            TransactionKind::Deposit => {
-                if self.tx.contains_key(&transaction.tx) {
-                    return Err(TransactionError::InvalidTransaction(transaction.tx));
-                }
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "necessary" => Ok(QuoteStyle::Necessary),
+            "always" => Ok(QuoteStyle::Always),
+            "never" => Ok(QuoteStyle::Never),
+            _ => Err(format!("Invalid quote style: {}", s)),
+        }
+    }
+}
 
-                let account = self.try_get_client_account(transaction.client)?;
+/// How an amount is rounded to a given number of decimal places (see [AccountsBuilder::rounding])
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RoundingMode {
+    /// Round half to even (aka banker's rounding): 0.00005 -> 0.0000, 0.00015 -> 0.0002
+    #[default]
+    HalfEven,
+    /// Round half away from zero: 0.00005 -> 0.0001, 0.00015 -> 0.0002
+    HalfUp,
+}
 
-                if account.locked {
-                    return Err(TransactionError::AccountLocked(transaction.client));
+impl RoundingMode {
+    /// Round amount to `scale` decimal places
+    ///
+    /// As amounts are f64, a tie is detected with a small tolerance
+    /// (0.00005 is actually stored as 0.0000500000000000000004...)
+    pub fn round(&self, amount: f64, scale: usize) -> f64 {
+        let factor = 10f64.powi(scale as i32);
+        let scaled = amount.abs() * factor;
+        let floor = scaled.floor();
+
+        let is_tie = ((scaled - floor) - 0.5).abs() <= ROUNDING_TIE_TOLERANCE * scaled.max(1.0);
+        let rounded = match self {
+            RoundingMode::HalfEven if is_tie => {
+                if floor % 2.0 == 0.0 {
+                    floor
+                } else {
+                    floor + 1.0
                 }
+            }
+            RoundingMode::HalfUp if is_tie => floor + 1.0,
+            _ => scaled.round(),
+        };
+
+        (rounded / factor).copysign(amount)
+    }
+}
 
-                let account_avail = account.available;
-                let account_total = account.total;
+/// Key of an account: (client id, currency)
+///
+/// A client has 1 account per currency (None: the single default currency)
+type AccountKey = (u16, Option<Currency>);
+
+/// Key of a retained transaction: (client id, tx)
+///
+/// Tx ids are globally unique by default: the client id part is then always 0
+/// (see [AccountsBuilder::per_client_tx])
+type TxKey = (u16, u32);
+
+/// A builder for a configured [Accounts] (see [Accounts::builder])
+///
+/// Example: `Accounts::builder().strict(true).max_retained_tx(Some(1000)).build()`
+#[derive(Debug, Default)]
+pub struct AccountsBuilder {
+    clients_capacity: usize,
+    txs_capacity: usize,
+    history: bool,
+    options: AccountsOptions,
+}
 
-                account.available += amount;
-                account.total += amount;
+impl AccountsBuilder {
+    /// Pre-allocate memory for `clients` accounts & `txs` transactions
+    pub fn capacity(mut self, clients: usize, txs: usize) -> Self {
+        self.clients_capacity = clients;
+        self.txs_capacity = txs;
+        self
+    }
 
-                if ((account.available == account_avail) || (account.total == account_total))
-                    && amount != 0.0
-                {
-                    return Err(TransactionError::AccountAmountTooLarge);
-                }
+    /// Keep track of every applied transaction per client (see [Accounts::history]),
+    /// at the cost of extra memory
+    pub fn history(mut self, history: bool) -> Self {
+        self.history = history;
+        self
+    }
 
-                // keep track of our transaction
-                self.tx.insert(transaction.tx, transaction);
-            }
-            TransactionKind::Withdrawal => {
-                if self.tx.contains_key(&transaction.tx) {
-                    return Err(TransactionError::InvalidTransaction(transaction.tx));
-                }
+    /// Keep at most `max_retained_tx` transactions in memory (None: no limit)
+    ///
+    /// When the limit is reached, the oldest transactions (not currently disputed) are evicted.
+    /// A Dispute (or Resolve / Chargeback) referencing an evicted transaction returns
+    /// [TransactionError::UnknownTransaction], and the tx id of an evicted transaction
+    /// is not considered as already used anymore.
+    pub fn max_retained_tx(mut self, max_retained_tx: Option<usize>) -> Self {
+        self.options.max_retained_tx = max_retained_tx;
+        self
+    }
 
-                let account = self.try_get_client_account(transaction.client)?;
+    /// Strict mode: a Dispute on a Withdrawal is rejected
+    /// with [TransactionError::CannotDisputeWithdrawal]
+    ///
+    /// Note: only relevant with [AccountsBuilder::dispute_withdrawals], as a Dispute
+    /// on a Withdrawal is otherwise rejected with [TransactionError::NonDisputableKind]
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.options.strict = strict;
+        self
+    }
 
-                if account.locked {
-                    return Err(TransactionError::AccountLocked(transaction.client));
-                }
+    /// Allow a Dispute (then a Resolve / Chargeback) on a Withdrawal
+    ///
+    /// By default, only a Deposit can be disputed: a Dispute, Resolve or Chargeback
+    /// referencing any other transaction is rejected with [TransactionError::NonDisputableKind]
+    pub fn dispute_withdrawals(mut self, dispute_withdrawals: bool) -> Self {
+        self.options.dispute_withdrawals = dispute_withdrawals;
+        self
+    }
 
-                if amount > account.available {
-                    return Err(TransactionError::InvalidAmount(amount));
-                }
-                account.available -= amount;
-                account.total -= amount;
+    /// Tx ids are only unique per client (instead of globally unique): transactions
+    /// are keyed by (client id, tx), thus distinct clients can reuse the same tx id
+    ///
+    /// A Dispute (or Resolve / Chargeback / Reversal) then references a transaction
+    /// of its own client only.
+    pub fn per_client_tx(mut self, per_client_tx: bool) -> Self {
+        self.options.per_client_tx = per_client_tx;
+        self
+    }
 
-                // keep track of our transaction
-                self.tx.insert(transaction.tx, transaction);
-            }
-            TransactionKind::Dispute => {
-                let matching_transaction = self
-                    .get_transaction(transaction.tx)
-                    .ok_or(TransactionError::UnknownTransaction(transaction.tx))?;
-                let amount_of_matching_tr = get_amount(matching_transaction)?;
+    /// Rounding of amounts in csv output (default: [RoundingMode::HalfEven])
+    ///
+    /// Only formatting changes: amounts are still stored at full precision
+    pub fn rounding(mut self, rounding: RoundingMode) -> Self {
+        self.options.rounding = rounding;
+        self
+    }
 
-                let account = self.try_get_client_account(transaction.client)?;
+    /// Reject a Deposit with an amount greater than `max_transaction_amount` (None: no limit)
+    /// with [TransactionError::AmountExceedsLimit]
+    pub fn max_transaction_amount(mut self, max_transaction_amount: Option<f64>) -> Self {
+        self.options.max_transaction_amount = max_transaction_amount;
+        self
+    }
 
-                account.available -= amount_of_matching_tr;
-                account.held += amount_of_matching_tr;
+    /// Reject a Deposit that would bring an account total above `max_account_total`
+    /// (None: no limit) with [TransactionError::AmountExceedsLimit]
+    pub fn max_account_total(mut self, max_account_total: Option<f64>) -> Self {
+        self.options.max_account_total = max_account_total;
+        self
+    }
 
-                // XXX: not a fan of this... :-/
-                let matching_transaction = self
-                    .get_transaction_mut(transaction.tx)
-                    .ok_or(TransactionError::UnknownTransaction(transaction.tx))?;
+    /// Reject a Deposit or Withdrawal with an amount lower than `min_amount` (None: no minimum)
+    /// with [TransactionError::BelowMinimum], e.g. `Some(0.01)` against dust transactions
+    ///
+    /// Note: a zero amount is still rejected with [TransactionError::ZeroAmount]
+    pub fn min_amount(mut self, min_amount: Option<f64>) -> Self {
+        self.options.min_amount = min_amount;
+        self
+    }
 
-                matching_transaction.under_dispute = true;
-            }
-            TransactionKind::Resolve => {
-                let matching_transaction = self
-                    .get_transaction(transaction.tx)
-                    .ok_or(TransactionError::UnknownTransaction(transaction.tx))?;
+    /// A Chargeback of a known but non disputed transaction first disputes it (in the same
+    /// atomic step: on error, the account & transaction are left untouched)
+    ///
+    /// By default, such a Chargeback is rejected with [TransactionError::TxNonDisputed]
+    pub fn auto_dispute_on_chargeback(mut self, auto_dispute_on_chargeback: bool) -> Self {
+        self.options.auto_dispute_on_chargeback = auto_dispute_on_chargeback;
+        self
+    }
 
-                if !matching_transaction.under_dispute {
-                    return Err(TransactionError::TxNonDisputed(transaction.tx));
-                }
+    /// A locked account (e.g. after a first Chargeback) accepts the Chargeback of a dispute
+    /// opened before its lock (the account stays locked)
+    ///
+    /// By default, a locked account is frozen: such a Chargeback is rejected with
+    /// [TransactionError::AccountLocked] (and the funds stay held)
+    pub fn chargeback_on_locked(mut self, chargeback_on_locked: bool) -> Self {
+        self.options.chargeback_on_locked = chargeback_on_locked;
+        self
+    }
 
-                let amount_of_matching_tr = get_amount(matching_transaction)?;
+    /// Quoting of fields in csv output (default: [QuoteStyle::Necessary])
+    pub fn quote_style(mut self, quote_style: QuoteStyle) -> Self {
+        self.options.quote_style = quote_style;
+        self
+    }
 
-                let account = self.try_get_client_account(transaction.client)?;
+    /// Extra columns (e.g. `region`) appended to csv output: their values are taken from the
+    /// first transaction of each client (see [crate::csv_reader::CsvFormat::extra_columns]),
+    /// empty if missing
+    pub fn metadata_columns(mut self, metadata_columns: Vec<String>) -> Self {
+        self.options.metadata_columns = metadata_columns;
+        self
+    }
 
-                account.held -= amount_of_matching_tr;
-                account.available += amount_of_matching_tr;
-            }
-            TransactionKind::Chargeback => {
-                let matching_transaction = self
-                    .get_transaction(transaction.tx)
-                    .ok_or(TransactionError::UnknownTransaction(transaction.tx))?;
-                if !matching_transaction.under_dispute {
-                    return Err(TransactionError::TxNonDisputed(transaction.tx));
-                }
+    /// Leave out of csv output an empty account: zero available, held & total, and not locked
+    /// (e.g. a client only seen in a rejected transaction). By default, it is written
+    pub fn suppress_empty(mut self, suppress_empty: bool) -> Self {
+        self.options.suppress_empty = suppress_empty;
+        self
+    }
 
-                let amount_of_matching_tr = get_amount(matching_transaction)?;
+    /// End csv output lines with CRLF (`\r\n`, e.g. for Windows tools) instead of LF (`\n`)
+    pub fn crlf(mut self, crlf: bool) -> Self {
+        self.options.crlf = crlf;
+        self
+    }
 
-                let account = self.try_get_client_account(transaction.client)?;
+    /// Write amounts in csv output as integer minor units: amounts are multiplied by
+    /// 10^scale (see [Accounts::set_scale]), e.g. `25.1100` -> `251100` (default scale: 4)
+    ///
+    /// Amounts are rounded to the scale first (see [AccountsBuilder::rounding]), then only
+    /// the decimal point is dropped: no further f64 arithmetic is involved
+    pub fn minor_units(mut self, minor_units: bool) -> Self {
+        self.options.minor_units = minor_units;
+        self
+    }
 
-                account.held -= amount_of_matching_tr;
-                account.total -= amount_of_matching_tr;
-                account.locked = true;
-            }
-        }
+    /// Reject a Dispute when its client already has `max_open_disputes` open disputes
+    /// (not resolved nor charged back yet) with [TransactionError::TooManyDisputes]
+    /// (None: no limit)
+    pub fn max_open_disputes(mut self, max_open_disputes: Option<u32>) -> Self {
+        self.options.max_open_disputes = max_open_disputes;
+        self
+    }
 
-        Ok(())
+    /// A Chargeback freezes its client: any later transaction of this client, on any of its
+    /// accounts (e.g. in another currency), is rejected with [TransactionError::AccountLocked]
+    ///
+    /// By default, only the charged back account is locked
+    pub fn freeze_on_chargeback(mut self, freeze_on_chargeback: bool) -> Self {
+        self.options.freeze_on_chargeback = freeze_on_chargeback;
+        self
     }
-}
 
-/// Get amount of money for a given [Transaction], returning 0.0 on None
-fn get_amount(transaction: &Transaction) -> Result<f64, TransactionError> {
-    match transaction.amount {
-        Some(a) => {
-            if a > 0.0 {
-                Ok(a)
-            } else {
-                Err(TransactionError::InvalidAmount(a))
-            }
+    /// Scope in which a Deposit or Withdrawal tx id must be unique (default: [TxIdScope::Global])
+    ///
+    /// With [TxIdScope::File], a tx id of a previous input file (see [Accounts::start_file]) can
+    /// be reused: the new transaction then replaces the previous one (which can no longer be
+    /// disputed), unless the previous one is under dispute. A Dispute (or Resolve, ...) always
+    /// references the latest transaction with its tx id.
+    pub fn tx_id_scope(mut self, tx_id_scope: TxIdScope) -> Self {
+        self.options.tx_id_scope = tx_id_scope;
+        self
+    }
+
+    /// A Resolve (or Chargeback) of a transaction whose dispute has already been resolved
+    /// (or charged back) is a replay (e.g. when processing overlapping files): it is silently
+    /// skipped, like a replayed Deposit or Withdrawal
+    ///
+    /// By default, it is rejected ([TransactionError::TxNonDisputed], or
+    /// [TransactionError::AccountLocked] for a second Chargeback)
+    pub fn idempotent(mut self, idempotent: bool) -> Self {
+        self.options.idempotent = idempotent;
+        self
+    }
+
+    /// Only a Deposit creates an account: any other transaction for an unknown client
+    /// (or currency) is rejected with [TransactionError::UnknownClient]
+    ///
+    /// By default, any transaction creates the account of its client (e.g. a Withdrawal for
+    /// an unknown client leaves an empty account, then is rejected for insufficient funds)
+    pub fn require_known_client(mut self, require_known_client: bool) -> Self {
+        self.options.require_known_client = require_known_client;
+        self
+    }
+
+    /// Build the configured [Accounts]
+    pub fn build(self) -> Accounts {
+        Accounts {
+            inner: HashMap::with_capacity(self.clients_capacity),
+            tx: HashMap::with_capacity(self.txs_capacity),
+            history: self.history.then(HashMap::new),
+            tx_order: VecDeque::new(),
+            overdrafts: HashMap::new(),
+            closed: HashSet::new(),
+            charged_back: HashSet::new(),
+            earlier_file_tx: HashSet::new(),
+            non_monetary_tx: HashMap::new(),
+            metadata: HashMap::new(),
+            finalized: HashMap::new(),
+            orphan_disputes: 0,
+            open_disputes: HashMap::new(),
+            options: self.options,
         }
-        None => Ok(0.0),
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::error::Error;
+/// An opaque data holding all accounts information
+pub struct Accounts {
+    inner: HashMap<AccountKey, Account>, // k: (client id, currency), v: Account data
+    tx: HashMap<TxKey, Transaction>,     // k: (client id, tx) (see TxKey), v: Transaction struct
+    history: Option<HashMap<u16, Vec<Transaction>>>, // k: client id, v: applied transactions
+    tx_order: VecDeque<TxKey>,           // tx in insertion order (only used with max_retained_tx)
+    overdrafts: HashMap<u16, f64>,       // k: client id, v: overdraft limit
+    closed: HashSet<AccountKey>,         // closed accounts (removed from inner)
+    charged_back: HashSet<u16>,          // clients with a chargeback (see freeze_on_chargeback)
+    earlier_file_tx: HashSet<TxKey>,     // tx of previous input files (see Accounts::start_file)
+    // k: tx id of a Fee / Close (or only seen in dispute rows), v: client id & kind
+    non_monetary_tx: HashMap<TxKey, (u16, TransactionKind)>,
+    metadata: HashMap<u16, Vec<(String, String)>>, // k: client id, v: extras of its first tx
+    finalized: HashMap<TxKey, TransactionKind>,    // v: Resolve or Chargeback (only if idempotent)
+    orphan_disputes: usize,                        // see Accounts::orphan_disputes
+    open_disputes: HashMap<u16, u32>, // k: client id, v: number of its tx under dispute
+    options: AccountsOptions,
+}
 
-    #[test]
-    fn accounts_output_ok() -> Result<(), Box<dyn Error>> {
-        let mut accounts = Accounts::new();
+impl Default for Accounts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-        accounts.add_client(1);
-        accounts.add_client(2);
+impl Accounts {
+    /// Accounts with default options (see [Accounts::builder] to configure it)
+    pub fn new() -> Self {
+        Self::builder().build()
+    }
 
-        let mut output: Vec<u8> = Vec::new();
-        accounts.output_as_csv(Some(&mut output))?;
+    /// A builder to configure Accounts options
+    pub fn builder() -> AccountsBuilder {
+        AccountsBuilder::default()
+    }
 
-        let output_str = std::str::from_utf8(&output).unwrap();
-        // println!("output: {:?}", output_str);
+    /// Same as [Accounts::new] but pre-allocate memory for `clients` accounts & `txs` transactions
+    pub fn with_capacity(clients: usize, txs: usize) -> Self {
+        Self::builder().capacity(clients, txs).build()
+    }
 
-        assert!(
-            output_str == "client,available,held,total,locked\n1,0.0,0.0,0.0,false\n2,0.0,0.0,0.0,false\n" ||
-            output_str == "client,available,held,total,locked\n2,0.0,0.0,0.0,false\n1,0.0,0.0,0.0,false\n"
-        );
+    /// Same as [Accounts::new] but keep track of every applied transaction per client
+    /// (see [Accounts::history]), at the cost of extra memory
+    pub fn with_history() -> Self {
+        Self::builder().history(true).build()
+    }
 
-        // let mut stdout = std::io::stdout();
-        // accounts.output_as_csv(Some(&mut stdout));
+    /// Same as [Accounts::new] but keep at most `max_retained_tx` transactions in memory
+    /// (see [AccountsBuilder::max_retained_tx])
+    pub fn with_max_retained_tx(max_retained_tx: usize) -> Self {
+        Self::builder()
+            .max_retained_tx(Some(max_retained_tx))
+            .build()
+    }
 
+    /// Start processing a new input file: with [TxIdScope::File], tx ids used so far
+    /// can then be reused (see [AccountsBuilder::tx_id_scope])
+    pub fn start_file(&mut self) {
+        if self.options.tx_id_scope == TxIdScope::File {
+            self.earlier_file_tx = self.tx.keys().copied().collect();
+        }
+    }
+
+    /// Allow a client to withdraw until its available amount reaches `-limit`
+    /// (default limit is 0.0: no overdraft, a negative limit is considered as 0.0)
+    pub fn set_overdraft(&mut self, client_id: u16, limit: f64) {
+        self.overdrafts.insert(client_id, limit.max(0.0));
+    }
+
+    /// Set number of decimal places of amounts in csv output (0 to [MAX_AMOUNT_SCALE])
+    ///
+    /// Only formatting changes: amounts are still stored at full precision
+    pub fn set_scale(&mut self, scale: usize) -> Result<(), String> {
+        if scale > MAX_AMOUNT_SCALE {
+            return Err(format!(
+                "Invalid scale: {} (max: {})",
+                scale, MAX_AMOUNT_SCALE
+            ));
+        }
+        self.options.scale = Some(scale);
         Ok(())
     }
 
-    #[test]
-    fn accounts_valid_deposit() -> Result<(), Box<dyn Error>> {
-        let mut accounts = Accounts::new();
+    /// Check accounting identity (available + held == total) for all accounts
+    ///
+    /// Note: in debug builds, this is checked for the account affected by every transaction
+    /// handled by [Accounts::handle_transaction]
+    pub fn validate(&self) -> Result<(), String> {
+        self.inner
+            .iter()
+            .try_for_each(|((client, currency), account)| {
+                account.validate().map_err(|e| {
+                    format!(
+                        "Account (client id: {}, currency: {:?}): {}",
+                        client, currency, e
+                    )
+                })
+            })
+    }
 
-        let client_id = 1;
-        let deposit_amount = 25.11;
-        let transaction =
-            Transaction::new(TransactionKind::Deposit, client_id, 1, Some(deposit_amount));
+    /// Repair accounts breaking the accounting identity (see [Accounts::validate]),
+    /// e.g. after restoring an externally produced snapshot: total is recomputed
+    /// from available + held. Every correction is logged (warn).
+    ///
+    /// Return client ids of repaired accounts (sorted)
+    pub fn reconcile(&mut self) -> Vec<u16> {
+        let mut repaired: Vec<u16> = self
+            .inner
+            .iter_mut()
+            .filter(|(_, account)| account.validate().is_err())
+            .map(|((client, currency), account)| {
+                let total = account.available + account.held;
+                warn!(
+                    "Repairing account (client id: {}, currency: {:?}): total {} -> {}",
+                    client, currency, account.total, total
+                );
+                account.total = total;
+                *client
+            })
+            .collect();
+        repaired.sort();
+        repaired.dedup();
+        repaired
+    }
 
-        accounts.handle_transaction(transaction)?;
+    /// Tx ids of retained transactions (deposits & withdrawals, not disputes...), in no order
+    ///
+    /// Note: evicted transactions are not included (see [AccountsBuilder::max_retained_tx])
+    /// and, with [AccountsBuilder::per_client_tx], a tx id can be listed once per client
+    pub fn transaction_ids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.tx.values().map(|t| t.tx)
+    }
 
-        let account: &Account = accounts
-            .get_client_account(client_id)
-            .ok_or("Cannot client client account")?;
+    /// Credit interest (`rate` * available, e.g. 0.01 for 1%) to every non locked account
+    /// with positive available funds, in 1 pass
+    ///
+    /// Interest is rounded to the output scale with the configured rounding
+    /// (see [Accounts::set_scale] & [AccountsBuilder::rounding])
+    pub fn accrue_interest(&mut self, rate: f64) -> Result<(), String> {
+        if !rate.is_finite() || rate < 0.0 {
+            return Err(format!("Invalid interest rate: {}", rate));
+        }
 
-        assert_eq!(account.available, deposit_amount);
-        assert_eq!(account.total, deposit_amount);
-        assert_eq!(account.held, 0.0);
+        let scale = self.options.scale.unwrap_or(AMOUNT_DECIMALS);
+        let rounding = self.options.rounding;
+        for account in self
+            .inner
+            .values_mut()
+            .filter(|a| !a.locked && a.available > 0.0)
+        {
+            let interest = rounding.round(account.available * rate, scale);
+            account.available += interest;
+            account.total += interest;
+        }
         Ok(())
     }
 
-    #[test]
-    fn accounts_invalid_deposit() -> Result<(), Box<dyn Error>> {
-        // Testing deposit < 0, == NAN, == f64::MAX + 9999
-        // Check for distinct error on each cases
+    /// Ordered list of transactions applied for a client
+    /// (None if history is not enabled or if the client is unknown)
+    pub fn history(&self, client_id: u16) -> Option<&[Transaction]> {
+        self.history
+            .as_ref()
+            .and_then(|h| h.get(&client_id))
+            .map(|v| v.as_slice())
+    }
 
-        let mut accounts = Accounts::new();
+    #[doc(hidden)]
+    fn add_account(&mut self, key: AccountKey) {
+        self.inner.entry(key).or_insert_with(Account::new);
+    }
 
-        let client_id = 1;
+    #[doc(hidden)]
+    #[cfg(test)]
+    fn get_client_account(&self, client_id: u16) -> Option<&Account> {
+        self.inner.get(&(client_id, None))
+    }
 
-        let deposit_amount0 = -42.42;
-        let transaction0 = Transaction::new(
-            TransactionKind::Deposit,
-            client_id,
-            1,
-            Some(deposit_amount0),
-        );
+    #[doc(hidden)]
+    fn try_get_account(&mut self, key: AccountKey) -> Result<&mut Account, TransactionError> {
+        self.inner
+            .get_mut(&key)
+            .ok_or(TransactionError::UnknownClient(key.0))
+    }
 
-        let deposit_amount0_1 = f64::NAN;
-        let transaction0_1 = Transaction::new(
-            TransactionKind::Deposit,
-            client_id,
-            2,
-            Some(deposit_amount0_1),
-        );
+    /// Account of a transaction: a Dispute (or Resolve, Chargeback, Reversal) applies
+    /// to the account (currency) of the transaction it references, which must belong
+    /// to the same client
+    #[doc(hidden)]
+    fn account_key(&self, transaction: &Transaction) -> Result<AccountKey, TransactionError> {
+        let referenced = match transaction.kind {
+            TransactionKind::Dispute
+            | TransactionKind::Resolve
+            | TransactionKind::Chargeback
+            | TransactionKind::Reversal => self.get_transaction(transaction.client, transaction.tx),
+            _ => None,
+        };
 
-        let deposit_amount1 = f64::MAX;
-        let transaction1 = Transaction::new(
-            TransactionKind::Deposit,
-            client_id,
-            3,
-            Some(deposit_amount1),
-        );
+        match referenced {
+            Some(t) if t.client != transaction.client => {
+                Err(TransactionError::ClientMismatch(transaction.tx))
+            }
+            Some(t) if transaction.currency.is_some() && transaction.currency != t.currency => {
+                Err(TransactionError::CurrencyMismatch(transaction.tx))
+            }
+            Some(t) => Ok((transaction.client, t.currency)),
+            None => Ok((transaction.client, transaction.currency)),
+        }
+    }
+
+    #[doc(hidden)]
+    fn tx_key(&self, client_id: u16, tx: u32) -> TxKey {
+        if self.options.per_client_tx {
+            (client_id, tx)
+        } else {
+            (0, tx)
+        }
+    }
+
+    /// Is a tx id already used by a Deposit or Withdrawal? (see [AccountsBuilder::tx_id_scope])
+    #[doc(hidden)]
+    fn is_tx_id_used(&self, client_id: u16, tx: u32) -> bool {
+        let key = self.tx_key(client_id, tx);
+        match self.tx.get(&key) {
+            Some(t) => !self.earlier_file_tx.contains(&key) || t.under_dispute,
+            None => false,
+        }
+    }
+
+    #[doc(hidden)]
+    fn get_transaction(&self, client_id: u16, tx: u32) -> Option<&Transaction> {
+        self.tx.get(&self.tx_key(client_id, tx))
+    }
+
+    #[doc(hidden)]
+    fn get_transaction_mut(&mut self, client_id: u16, tx: u32) -> Option<&mut Transaction> {
+        let key = self.tx_key(client_id, tx);
+        self.tx.get_mut(&key)
+    }
+
+    #[doc(hidden)]
+    fn retain_transaction(&mut self, transaction: Transaction) {
+        let tx = self.tx_key(transaction.client, transaction.tx);
+        self.tx.insert(tx, transaction);
+        let replaced = self.earlier_file_tx.remove(&tx);
+        self.non_monetary_tx.remove(&tx);
+
+        if let Some(max_retained_tx) = self.options.max_retained_tx {
+            // A replaced tx of a previous file (see TxIdScope::File) is now the newest one
+            if replaced {
+                self.tx_order.retain(|t| *t != tx);
+            }
+            self.tx_order.push_back(tx);
+
+            while self.tx.len() > max_retained_tx {
+                // Oldest tx that can be evicted (a disputed tx is kept until resolved / charged back)
+                let evictable = self
+                    .tx_order
+                    .iter()
+                    .position(|t| !matches!(self.tx.get(t), Some(t) if t.under_dispute));
+
+                match evictable.and_then(|i| self.tx_order.remove(i)) {
+                    Some(evicted_tx) => {
+                        self.tx.remove(&evicted_tx);
+                    }
+                    None => break,
+                }
+            }
+        }
+    }
+
+    /// Are tx ids only unique per client? (see [AccountsBuilder::per_client_tx])
+    #[cfg(feature = "parallel")]
+    pub(crate) fn per_client_tx(&self) -> bool {
+        self.options.per_client_tx
+    }
+
+    /// All kept tx ids, with their client id
+    #[cfg(feature = "parallel")]
+    pub(crate) fn tx_ids_by_client(&self) -> Vec<(u16, u32)> {
+        self.tx.values().map(|t| (t.client, t.tx)).collect()
+    }
+
+    /// Move all data of clients matching `belongs_to` into a new Accounts (with the same options)
+    #[cfg(feature = "parallel")]
+    pub(crate) fn split_off_clients<F>(&mut self, belongs_to: F) -> Accounts
+    where
+        F: Fn(u16) -> bool,
+    {
+        let (inner, kept): (HashMap<_, _>, HashMap<_, _>) =
+            self.inner.drain().partition(|((c, _), _)| belongs_to(*c));
+        self.inner = kept;
+        let (tx, kept): (HashMap<_, _>, HashMap<_, _>) =
+            self.tx.drain().partition(|(_, t)| belongs_to(t.client));
+        self.tx = kept;
+        let (tx_order, kept): (VecDeque<_>, VecDeque<_>) =
+            self.tx_order.drain(..).partition(|t| tx.contains_key(t));
+        self.tx_order = kept;
+        let (earlier_file_tx, kept): (HashSet<_>, HashSet<_>) = self
+            .earlier_file_tx
+            .drain()
+            .partition(|t| tx.contains_key(t));
+        self.earlier_file_tx = kept;
+        let (non_monetary_tx, kept): (HashMap<_, _>, HashMap<_, _>) = self
+            .non_monetary_tx
+            .drain()
+            .partition(|(_, (c, _))| belongs_to(*c));
+        self.non_monetary_tx = kept;
+        let (metadata, kept): (HashMap<_, _>, HashMap<_, _>) =
+            self.metadata.drain().partition(|(c, _)| belongs_to(*c));
+        self.metadata = kept;
+        let (finalized, kept): (HashMap<_, _>, HashMap<_, _>) = self
+            .finalized
+            .drain()
+            .partition(|(t, _)| tx.contains_key(t));
+        self.finalized = kept;
+        let (overdrafts, kept): (HashMap<_, _>, HashMap<_, _>) =
+            self.overdrafts.drain().partition(|(c, _)| belongs_to(*c));
+        self.overdrafts = kept;
+        let (closed, kept): (HashSet<_>, HashSet<_>) =
+            self.closed.drain().partition(|(c, _)| belongs_to(*c));
+        self.closed = kept;
+        let (charged_back, kept): (HashSet<_>, HashSet<_>) =
+            self.charged_back.drain().partition(|c| belongs_to(*c));
+        self.charged_back = kept;
+        let (open_disputes, kept): (HashMap<_, _>, HashMap<_, _>) = self
+            .open_disputes
+            .drain()
+            .partition(|(c, _)| belongs_to(*c));
+        self.open_disputes = kept;
+        let history = self.history.as_mut().map(|history| {
+            let (moved, kept): (HashMap<_, _>, HashMap<_, _>) =
+                history.drain().partition(|(c, _)| belongs_to(*c));
+            *history = kept;
+            moved
+        });
+
+        Accounts {
+            inner,
+            tx,
+            history,
+            tx_order,
+            overdrafts,
+            closed,
+            charged_back,
+            earlier_file_tx,
+            non_monetary_tx,
+            metadata,
+            finalized,
+            orphan_disputes: 0,
+            open_disputes,
+            options: self.options.clone(),
+        }
+    }
+
+    /// Move all data of `other` into self (clients of `other` must not be known by self)
+    #[cfg(feature = "parallel")]
+    pub(crate) fn absorb(&mut self, other: Accounts) {
+        self.inner.extend(other.inner);
+        self.tx.extend(other.tx);
+        self.tx_order.extend(other.tx_order);
+        self.overdrafts.extend(other.overdrafts);
+        self.closed.extend(other.closed);
+        self.charged_back.extend(other.charged_back);
+        self.earlier_file_tx.extend(other.earlier_file_tx);
+        self.non_monetary_tx.extend(other.non_monetary_tx);
+        for (client_id, extras) in other.metadata {
+            self.metadata.entry(client_id).or_insert(extras);
+        }
+        self.finalized.extend(other.finalized);
+        self.orphan_disputes += other.orphan_disputes;
+        self.open_disputes.extend(other.open_disputes);
+        if let (Some(history), Some(other_history)) = (self.history.as_mut(), other.history) {
+            history.extend(other_history);
+        }
+    }
+
+    /// Merge `other` (e.g. the result of another shard of transactions) into self:
+    /// balances are summed per account, locked flags are OR'ed & transactions are united
+    ///
+    /// A tx kept by both with a different content is rejected with
+    /// [TransactionError::InvalidTransaction] (self is then left untouched), an identical one
+    /// is kept once. Overdraft limits of self take precedence.
+    pub fn merge(&mut self, other: Accounts) -> Result<(), TransactionError> {
+        if let Some((_, t)) = other.tx.iter().find(|(key, t)| {
+            self.tx
+                .get(key)
+                .is_some_and(|self_t| !self_t.is_replay_of(t))
+        }) {
+            return Err(TransactionError::InvalidTransaction(t.tx));
+        }
+
+        for (key, account) in other.inner {
+            let merged = self.inner.entry(key).or_insert_with(Account::new);
+            merged.available += account.available;
+            merged.held += account.held;
+            merged.total += account.total;
+            merged.locked |= account.locked;
+        }
+        for key in other.tx_order {
+            if !self.tx.contains_key(&key) {
+                self.tx_order.push_back(key);
+            }
+        }
+        for (key, transaction) in other.tx {
+            self.tx.entry(key).or_insert(transaction);
+        }
+        for (client_id, limit) in other.overdrafts {
+            self.overdrafts.entry(client_id).or_insert(limit);
+        }
+        self.closed.extend(other.closed);
+        self.charged_back.extend(other.charged_back);
+        self.earlier_file_tx.extend(other.earlier_file_tx);
+        self.non_monetary_tx.extend(other.non_monetary_tx);
+        for (client_id, extras) in other.metadata {
+            self.metadata.entry(client_id).or_insert(extras);
+        }
+        self.finalized.extend(other.finalized);
+        self.orphan_disputes += other.orphan_disputes;
+        self.recount_open_disputes();
+        if let (Some(history), Some(other_history)) = (self.history.as_mut(), other.history) {
+            for (client_id, transactions) in other_history {
+                history.entry(client_id).or_default().extend(transactions);
+            }
+        }
+        Ok(())
+    }
+
+    /// Generate csv for all accounts (header: client, currency, available, held, total, locked, disputed_count)
+    pub fn output_as_csv<W>(&self, into: &mut W) -> Result<(), csv::Error>
+    where
+        W: Write,
+    {
+        self.write_csv(into, |_| true)
+    }
+
+    /// Same as [Accounts::output_as_csv] with a writer trait object (e.g. when embedding,
+    /// to avoid 1 instantiation per writer type)
+    pub fn output_as_csv_dyn(&self, mut into: &mut dyn Write) -> Result<(), csv::Error> {
+        self.write_csv(&mut into, |_| true)
+    }
+
+    /// Same as [Accounts::output_as_csv] but only for locked accounts
+    pub fn output_locked_as_csv<W>(&self, into: &mut W) -> Result<(), csv::Error>
+    where
+        W: Write,
+    {
+        self.write_csv(into, |a| a.locked)
+    }
+
+    /// Capture current state (see [AccountsSnapshot])
+    pub fn snapshot(&self) -> AccountsSnapshot {
+        let mut accounts: Vec<(AccountKey, Account)> =
+            self.inner.iter().map(|(k, a)| (*k, *a)).collect();
+        accounts.sort_by_key(|(k, _)| *k);
+
+        let mut transactions: Vec<TransactionState> = self
+            .tx
+            .values()
+            .map(|t| TransactionState {
+                transaction: t.clone(),
+                under_dispute: t.under_dispute,
+                disputed_amount: t.disputed_amount,
+                reversed: t.reversed,
+            })
+            .collect();
+        transactions.sort_by_key(|t| (t.transaction.tx, t.transaction.client));
+
+        let mut overdrafts: Vec<(u16, f64)> =
+            self.overdrafts.iter().map(|(c, l)| (*c, *l)).collect();
+        overdrafts.sort_by_key(|(c, _)| *c);
+
+        let mut closed: Vec<AccountKey> = self.closed.iter().copied().collect();
+        closed.sort();
+
+        let mut finalized: Vec<(TxKey, TransactionKind)> =
+            self.finalized.iter().map(|(k, kind)| (*k, *kind)).collect();
+        finalized.sort_by_key(|(k, _)| *k);
+
+        AccountsSnapshot {
+            accounts,
+            transactions,
+            tx_order: self.tx_order.iter().copied().collect(),
+            overdrafts,
+            closed,
+            finalized,
+        }
+    }
+
+    /// Replace current state with a snapshot (options, e.g. strict or max_retained_tx, are kept)
+    pub fn restore(&mut self, snapshot: AccountsSnapshot) {
+        self.inner = snapshot.accounts.into_iter().collect();
+        self.tx = snapshot
+            .transactions
+            .into_iter()
+            .map(|t| {
+                let mut transaction = t.transaction;
+                transaction.under_dispute = t.under_dispute;
+                transaction.disputed_amount = t.disputed_amount;
+                transaction.reversed = t.reversed;
+                (self.tx_key(transaction.client, transaction.tx), transaction)
+            })
+            .collect();
+        self.tx_order = snapshot.tx_order.into_iter().collect();
+        self.overdrafts = snapshot.overdrafts.into_iter().collect();
+        self.closed = snapshot.closed.into_iter().collect();
+        self.earlier_file_tx.clear();
+        self.non_monetary_tx.clear();
+        self.metadata.clear();
+        self.finalized = snapshot.finalized.into_iter().collect();
+        // Only a chargeback locks an account
+        self.charged_back = self
+            .inner
+            .iter()
+            .filter(|(_, a)| a.locked)
+            .map(|((c, _), _)| *c)
+            .collect();
+        self.recount_open_disputes();
+    }
+
+    /// Rebuild open disputes counters from retained tx (e.g. after a restore)
+    #[doc(hidden)]
+    fn recount_open_disputes(&mut self) {
+        self.open_disputes.clear();
+        for t in self.tx.values().filter(|t| t.under_dispute) {
+            *self.open_disputes.entry(t.client).or_default() += 1;
+        }
+    }
+
+    /// A dispute of a client tx has been resolved or charged back
+    #[doc(hidden)]
+    fn close_dispute(&mut self, client_id: u16) {
+        if let Some(count) = self.open_disputes.get_mut(&client_id) {
+            *count -= 1;
+            if *count == 0 {
+                self.open_disputes.remove(&client_id);
+            }
+        }
+    }
+
+    /// Summary of all accounts, sorted by client id then currency (see [AccountReport])
+    pub fn report(&self) -> Vec<AccountReport> {
+        let tx_counts = self.count_tx_by_account();
+
+        let mut report: Vec<AccountReport> = self
+            .inner
+            .iter()
+            .map(|(key, a)| {
+                let (tx_count, disputed_count) = tx_counts.get(key).copied().unwrap_or((0, 0));
+                AccountReport {
+                    client: key.0,
+                    currency: key.1,
+                    available: a.available,
+                    held: a.held,
+                    total: a.total,
+                    locked: a.locked,
+                    tx_count,
+                    disputed_count,
+                }
+            })
+            .collect();
+        report.sort_by_key(|r| (r.client, r.currency));
+        report
+    }
+
+    /// Sums of available, held & total over all accounts (all currencies mixed), with the
+    /// number of clients & locked accounts
+    ///
+    /// Amounts are summed in account order (client id then currency) so that the f64 result
+    /// does not depend on the (random) HashMap order
+    pub fn aggregate(&self) -> Aggregate {
+        let mut accounts: Vec<(&AccountKey, &Account)> = self.inner.iter().collect();
+        accounts.sort_by_key(|(key, _)| **key);
+
+        let mut aggregate =
+            accounts
+                .iter()
+                .fold(Aggregate::default(), |mut aggregate, (_, account)| {
+                    aggregate.available += account.available;
+                    aggregate.held += account.held;
+                    aggregate.total += account.total;
+                    if account.locked {
+                        aggregate.locked_count += 1;
+                    }
+                    aggregate
+                });
+
+        let mut clients: Vec<u16> = accounts.iter().map(|((client, _), _)| *client).collect();
+        clients.dedup();
+        aggregate.clients = clients.len();
+        aggregate.orphan_disputes = self.orphan_disputes;
+        aggregate
+    }
+
+    /// Number of Dispute, Resolve & Chargeback transactions received for a client without
+    /// account (e.g. a dispute row preceding its deposit): such data is suspicious
+    /// (each one is logged as a warning)
+    pub fn orphan_disputes(&self) -> usize {
+        self.orphan_disputes
+    }
+
+    /// Line ending of csv output (see [AccountsBuilder::crlf])
+    pub fn line_ending(&self) -> &'static str {
+        if self.options.crlf {
+            "\r\n"
+        } else {
+            "\n"
+        }
+    }
+
+    /// Count transactions & transactions currently under dispute (in 1 pass over all tx)
+    fn count_tx_by_account(&self) -> HashMap<AccountKey, (usize, usize)> {
+        let mut counts: HashMap<AccountKey, (usize, usize)> = HashMap::new();
+        for t in self.tx.values() {
+            let count = counts.entry((t.client, t.currency)).or_default();
+            count.0 += 1;
+            if t.under_dispute {
+                count.1 += 1;
+            }
+        }
+        counts
+    }
+
+    #[doc(hidden)]
+    fn write_csv<W, F>(&self, into: &mut W, filter: F) -> Result<(), csv::Error>
+    where
+        W: Write,
+        F: Fn(&Account) -> bool,
+    {
+        #[derive(Debug, Serialize)]
+        struct AccountLine {
+            client: u16,
+            currency: Option<Currency>,
+            available: String,
+            held: String,
+            total: String,
+            locked: bool,
+            disputed_count: usize,
+        }
+
+        impl AccountLine {
+            fn from_account(
+                key: AccountKey,
+                account: &Account,
+                disputed_count: usize,
+                scale: usize,
+                rounding: RoundingMode,
+                minor_units: bool,
+            ) -> Self {
+                // Create a AccountLine from a client id and its account (amounts formatted with scale)
+                let format_amount = |amount: f64| {
+                    let amount = format!("{:.*}", scale, rounding.round(amount, scale));
+                    if minor_units {
+                        to_minor_units(&amount)
+                    } else {
+                        amount
+                    }
+                };
+                Self {
+                    client: key.0,
+                    currency: key.1,
+                    available: format_amount(account.available),
+                    held: format_amount(account.held),
+                    total: format_amount(account.total),
+                    locked: account.locked,
+                    disputed_count,
+                }
+            }
+        }
+
+        let scale = self.options.scale.unwrap_or(AMOUNT_DECIMALS);
+
+        let tx_counts = self.count_tx_by_account();
+
+        let suppress_empty = self.options.suppress_empty;
+        let accounts: Vec<(&AccountKey, &Account)> = self
+            .inner
+            .iter()
+            .filter(|(_, a)| filter(a) && !(suppress_empty && a.is_empty()))
+            .collect();
+
+        // Write header ourselves so it is written even if there is no account line
+        let mut wtr = csv::WriterBuilder::new()
+            .has_headers(false)
+            .quote_style(self.options.quote_style.to_csv())
+            .terminator(if self.options.crlf {
+                csv::Terminator::CRLF
+            } else {
+                csv::Terminator::Any(b'\n')
+            })
+            .from_writer(into);
+        let metadata_columns = &self.options.metadata_columns;
+        wtr.write_record(
+            ACCOUNT_CSV_FIELDS
+                .iter()
+                .copied()
+                .chain(metadata_columns.iter().map(String::as_str)),
+        )?;
+
+        for (i, (key, a)) in accounts.into_iter().enumerate() {
+            let disputed_count = tx_counts.get(key).map_or(0, |(_, d)| *d);
+            let line = AccountLine::from_account(
+                *key,
+                a,
+                disputed_count,
+                scale,
+                self.options.rounding,
+                self.options.minor_units,
+            );
+            if metadata_columns.is_empty() {
+                wtr.serialize(line)?;
+            } else {
+                let extras = self.metadata.get(&key.0);
+                let values: Vec<&str> = metadata_columns
+                    .iter()
+                    .map(|column| {
+                        extras
+                            .and_then(|e| e.iter().find(|(c, _)| c == column))
+                            .map_or("", |(_, v)| v.as_str())
+                    })
+                    .collect();
+                wtr.serialize((line, values))?;
+            }
+
+            // Flush periodically (slow or pipe writers)
+            if (i + 1) % OUTPUT_FLUSH_LINES == 0 {
+                wtr.flush()?;
+            }
+        }
+
+        wtr.flush()?;
+        Ok(())
+    }
+
+    /// Handle a transaction, returning a [TransactionError] if it fails
+    ///
+    /// Note: a Deposit or a Withdrawal identical to an already applied one is a replay
+    /// (e.g. when processing overlapping files) and is silently skipped
+    /// (see [AccountsBuilder::idempotent] for a Resolve or Chargeback)
+    pub fn handle_transaction(&mut self, transaction: Transaction) -> Result<(), TransactionError> {
+        self.handle_transaction_with_outcome(transaction)
+            .map(|_| ())
+    }
+
+    /// Handle transactions in order (e.g. built with [Transaction::new], no csv needed),
+    /// stopping at the first [TransactionError]
+    pub fn apply_all<I>(&mut self, transactions: I) -> Result<(), TransactionError>
+    where
+        I: IntoIterator<Item = Transaction>,
+    {
+        transactions
+            .into_iter()
+            .try_for_each(|t| self.handle_transaction(t))
+    }
+
+    /// Same as [Accounts::handle_transaction] but detail what changed (see [TransactionOutcome])
+    pub fn handle_transaction_with_outcome(
+        &mut self,
+        transaction: Transaction,
+    ) -> Result<TransactionOutcome, TransactionError> {
+        let client_id = transaction.client;
+        let kind = transaction.kind;
+        let tx = transaction.tx;
+        let key = self.account_key(&transaction)?;
+        let before = self.inner.get(&key).copied().unwrap_or_else(Account::new);
+
+        // Informational row: no account is created or modified & its tx id is not used
+        if kind == TransactionKind::Note {
+            debug!(
+                "Ignoring note (client id: {}, tx: {})",
+                client_id, transaction.tx
+            );
+            return Ok(TransactionOutcome::new(key, kind, &before, &before, false));
+        }
+
+        if self.is_replay(&transaction) {
+            debug!("Skipping replayed tx: {}", transaction.tx);
+            return Ok(TransactionOutcome::new(key, kind, &before, &before, true));
+        }
+
+        let record = self.history.as_ref().map(|_| transaction.clone());
+
+        if !self.options.metadata_columns.is_empty() && !transaction.extras.is_empty() {
+            self.metadata
+                .entry(client_id)
+                .or_insert_with(|| transaction.extras.clone());
+        }
+
+        self.apply_transaction(transaction, key)?;
+
+        if self.options.idempotent
+            && matches!(kind, TransactionKind::Resolve | TransactionKind::Chargeback)
+        {
+            let tx_key = self.tx_key(client_id, tx);
+            self.finalized.insert(tx_key, kind);
+        }
+
+        let after = self.inner.get(&key).copied().unwrap_or(before);
+        debug_assert!(
+            after.validate().is_ok(),
+            "Inconsistent account (client id: {}): {:?}",
+            client_id,
+            after
+        );
+        let outcome = TransactionOutcome::new(key, kind, &before, &after, false);
+        debug!(
+            "Applied {:?} (client id: {}, currency: {:?}): available {:+}, held {:+}, total {:+}",
+            kind,
+            client_id,
+            key.1,
+            outcome.available_delta,
+            outcome.held_delta,
+            outcome.total_delta,
+        );
+
+        if let (Some(history), Some(record)) = (self.history.as_mut(), record) {
+            history.entry(client_id).or_default().push(record);
+        }
+
+        Ok(outcome)
+    }
+
+    #[doc(hidden)]
+    fn is_replay(&self, transaction: &Transaction) -> bool {
+        match transaction.kind {
+            TransactionKind::Deposit | TransactionKind::Withdrawal => {
+                self.is_tx_id_used(transaction.client, transaction.tx)
+                    && self
+                        .get_transaction(transaction.client, transaction.tx)
+                        .is_some_and(|t| t.is_replay_of(transaction))
+            }
+            // Same final state, and no new dispute since
+            TransactionKind::Resolve | TransactionKind::Chargeback if self.options.idempotent => {
+                let tx_key = self.tx_key(transaction.client, transaction.tx);
+                self.finalized.get(&tx_key) == Some(&transaction.kind)
+                    && self
+                        .get_transaction(transaction.client, transaction.tx)
+                        .is_some_and(|t| !t.under_dispute)
+            }
+            _ => false,
+        }
+    }
+
+    /// Only a Deposit can be disputed (unless [AccountsBuilder::dispute_withdrawals])
+    #[doc(hidden)]
+    fn check_disputable(&self, matching_transaction: &Transaction) -> Result<(), TransactionError> {
+        match matching_transaction.kind {
+            TransactionKind::Deposit => Ok(()),
+            TransactionKind::Withdrawal if self.options.dispute_withdrawals => Ok(()),
+            _ => Err(TransactionError::NonDisputableKind(matching_transaction.tx)),
+        }
+    }
+
+    /// A Deposit or Withdrawal amount must be at least [AccountsBuilder::min_amount]
+    #[doc(hidden)]
+    fn check_min_amount(&self, amount: f64) -> Result<(), TransactionError> {
+        match self.options.min_amount {
+            Some(min_amount) if amount < min_amount => Err(TransactionError::BelowMinimum(amount)),
+            _ => Ok(()),
+        }
+    }
+
+    #[doc(hidden)]
+    fn apply_transaction(
+        &mut self,
+        transaction: Transaction,
+        key: AccountKey,
+    ) -> Result<(), TransactionError> {
+        if self.options.freeze_on_chargeback && self.charged_back.contains(&transaction.client) {
+            return Err(TransactionError::AccountLocked(transaction.client));
+        }
+        if self.closed.contains(&key) {
+            return Err(TransactionError::AccountClosed(transaction.client));
+        }
+        // A Close never creates an account (it would block its client id for good)
+        if (self.options.require_known_client || transaction.kind == TransactionKind::Close)
+            && transaction.kind != TransactionKind::Deposit
+            && !self.inner.contains_key(&key)
+        {
+            return Err(TransactionError::UnknownClient(transaction.client));
+        }
+        if matches!(
+            transaction.kind,
+            TransactionKind::Dispute | TransactionKind::Resolve | TransactionKind::Chargeback
+        ) && !self.inner.contains_key(&key)
+        {
+            warn!(
+                "{:?} of tx {} for a client without account (client id: {})",
+                transaction.kind, transaction.tx, transaction.client
+            );
+            self.orphan_disputes += 1;
+        }
+        self.add_account(key);
+
+        // Only Dispute, Resolve, Chargeback, Reversal & Close can come without amount
+        if transaction.amount.is_none()
+            && matches!(
+                transaction.kind,
+                TransactionKind::Deposit | TransactionKind::Withdrawal | TransactionKind::Fee
+            )
+        {
+            return Err(TransactionError::MissingAmount(transaction.tx));
+        }
+
+        let amount = get_amount(&transaction)?;
+
+        // A locked (frozen) account is left untouched: a Dispute, Resolve, Chargeback (unless
+        // chargeback_on_locked), Reversal or Close is rejected too (Deposit, Withdrawal & Fee
+        // check it in their own arm)
+        let frozen = match transaction.kind {
+            TransactionKind::Dispute
+            | TransactionKind::Resolve
+            | TransactionKind::Reversal
+            | TransactionKind::Close => true,
+            TransactionKind::Chargeback => !self.options.chargeback_on_locked,
+            _ => false,
+        };
+        if frozen && self.inner.get(&key).is_some_and(|a| a.locked) {
+            return Err(TransactionError::AccountLocked(transaction.client));
+        }
+
+        let (kind, client_id, tx) = (transaction.kind, transaction.client, transaction.tx);
+        let result = match kind {
+            TransactionKind::Deposit => self.apply_deposit(transaction, key, amount),
+            TransactionKind::Withdrawal => self.apply_withdrawal(transaction, key, amount),
+            TransactionKind::Fee => self.apply_fee(transaction, key, amount),
+            TransactionKind::Reversal => self.apply_reversal(transaction, key),
+            TransactionKind::Dispute => self.apply_dispute(transaction, key, amount),
+            TransactionKind::Resolve => self.apply_resolve(transaction, key),
+            TransactionKind::Chargeback => self.apply_chargeback(transaction, key),
+            TransactionKind::Close => self.apply_close(transaction, key),
+            // Ignored (see handle_transaction_with_outcome)
+            TransactionKind::Note => Ok(()),
+        };
+
+        // Record the kind of tx ids not kept in tx (a Fee, a Close or an unknown tx id only
+        // referenced so far): a dispute / resolve / chargeback of such a tx id is not unknown.
+        // Not with max_retained_tx: an unknown tx id could be an evicted one (and such tx ids
+        // would not be bounded)
+        if self.options.max_retained_tx.is_none() {
+            let tx_key = self.tx_key(client_id, tx);
+            match (kind, &result) {
+                (
+                    TransactionKind::Dispute
+                    | TransactionKind::Resolve
+                    | TransactionKind::Chargeback,
+                    Err(TransactionError::UnknownTransaction(_)),
+                ) => {
+                    if self.non_monetary_tx.contains_key(&tx_key) {
+                        return Err(TransactionError::NonDisputableKind(tx));
+                    }
+                    self.non_monetary_tx.insert(tx_key, (client_id, kind));
+                }
+                (TransactionKind::Fee | TransactionKind::Close, Ok(())) => {
+                    self.non_monetary_tx
+                        .entry(tx_key)
+                        .or_insert((client_id, kind));
+                }
+                _ => {}
+            }
+        }
+        result
+    }
+
+    /// Credit the account (tx id must be unused)
+    #[doc(hidden)]
+    fn apply_deposit(
+        &mut self,
+        transaction: Transaction,
+        key: AccountKey,
+        amount: f64,
+    ) -> Result<(), TransactionError> {
+        if self.is_tx_id_used(transaction.client, transaction.tx) {
+            return Err(TransactionError::InvalidTransaction(transaction.tx));
+        }
+        self.check_min_amount(amount)?;
+
+        if self
+            .options
+            .max_transaction_amount
+            .is_some_and(|max| amount > max)
+        {
+            return Err(TransactionError::AmountExceedsLimit(transaction.tx));
+        }
+        let max_account_total = self.options.max_account_total;
+
+        let account = self.try_get_account(key)?;
+
+        if account.locked {
+            return Err(TransactionError::AccountLocked(transaction.client));
+        }
+
+        if max_account_total.is_some_and(|max| account.total + amount > max) {
+            return Err(TransactionError::AmountExceedsLimit(transaction.tx));
+        }
+
+        let account_avail = account.available;
+        let account_total = account.total;
+
+        account.available += amount;
+        account.total += amount;
+
+        if ((account.available == account_avail) || (account.total == account_total))
+            && amount != 0.0
+        {
+            return Err(TransactionError::AccountAmountTooLarge);
+        }
+
+        // keep track of our transaction
+        self.retain_transaction(transaction);
+
+        Ok(())
+    }
+
+    /// Debit the account, up to its available funds (plus its overdraft limit)
+    #[doc(hidden)]
+    fn apply_withdrawal(
+        &mut self,
+        transaction: Transaction,
+        key: AccountKey,
+        amount: f64,
+    ) -> Result<(), TransactionError> {
+        if self.is_tx_id_used(transaction.client, transaction.tx) {
+            return Err(TransactionError::InvalidTransaction(transaction.tx));
+        }
+        self.check_min_amount(amount)?;
+
+        let overdraft = self
+            .overdrafts
+            .get(&transaction.client)
+            .copied()
+            .unwrap_or(0.0);
+        let account = self.try_get_account(key)?;
+
+        if account.locked {
+            return Err(TransactionError::AccountLocked(transaction.client));
+        }
+
+        if amount > account.available + overdraft {
+            return Err(TransactionError::InvalidAmount(amount));
+        }
+        account.available -= amount;
+        account.total -= amount;
+
+        // keep track of our transaction
+        self.retain_transaction(transaction);
+
+        Ok(())
+    }
+
+    /// Debit the account, up to its available funds (a fee is not retained)
+    #[doc(hidden)]
+    fn apply_fee(
+        &mut self,
+        transaction: Transaction,
+        key: AccountKey,
+        amount: f64,
+    ) -> Result<(), TransactionError> {
+        let account = self.try_get_account(key)?;
+
+        if account.locked {
+            return Err(TransactionError::AccountLocked(transaction.client));
+        }
+
+        if amount > account.available {
+            return Err(TransactionError::InvalidAmount(amount));
+        }
+        account.available -= amount;
+        account.total -= amount;
+
+        // Note: a fee is not kept in tx (it cannot be disputed)
+
+        Ok(())
+    }
+
+    /// Cancel a (non disputed) deposit, debiting its amount
+    #[doc(hidden)]
+    fn apply_reversal(
+        &mut self,
+        transaction: Transaction,
+        key: AccountKey,
+    ) -> Result<(), TransactionError> {
+        let matching_transaction = self
+            .get_transaction(transaction.client, transaction.tx)
+            .ok_or(TransactionError::UnknownTransaction(transaction.tx))?;
+
+        if matching_transaction.reversed {
+            return Err(TransactionError::TxReversed(transaction.tx));
+        }
+        // Only a deposit (not currently disputed) can be reversed
+        if matching_transaction.kind != TransactionKind::Deposit
+            || matching_transaction.under_dispute
+        {
+            return Err(TransactionError::InvalidTransaction(transaction.tx));
+        }
+
+        let amount_of_matching_tr = get_amount(matching_transaction)?;
+
+        let account = self.try_get_account(key)?;
+
+        if amount_of_matching_tr > account.available {
+            return Err(TransactionError::InsufficientFunds(transaction.tx));
+        }
+        account.available -= amount_of_matching_tr;
+        account.total -= amount_of_matching_tr;
+
+        let matching_transaction = self
+            .get_transaction_mut(transaction.client, transaction.tx)
+            .ok_or(TransactionError::UnknownTransaction(transaction.tx))?;
+
+        matching_transaction.reversed = true;
+
+        Ok(())
+    }
+
+    /// Hold the amount (or a fraction of it) of the referenced transaction
+    #[doc(hidden)]
+    fn apply_dispute(
+        &mut self,
+        transaction: Transaction,
+        key: AccountKey,
+        amount: f64,
+    ) -> Result<(), TransactionError> {
+        let matching_transaction = self
+            .get_transaction(transaction.client, transaction.tx)
+            .ok_or(TransactionError::UnknownTransaction(transaction.tx))?;
+        self.check_disputable(matching_transaction)?;
+
+        if matching_transaction.reversed {
+            return Err(TransactionError::TxReversed(transaction.tx));
+        }
+
+        if matching_transaction.under_dispute {
+            return Err(TransactionError::TxAlreadyDisputed(transaction.tx));
+        }
+
+        if let (Some(ts), Some(matching_ts)) =
+            (transaction.timestamp, matching_transaction.timestamp)
+        {
+            if ts < matching_ts {
+                return Err(TransactionError::OutOfOrder(transaction.tx));
+            }
+        }
+
+        if self.options.strict && matching_transaction.kind == TransactionKind::Withdrawal {
+            return Err(TransactionError::CannotDisputeWithdrawal(transaction.tx));
+        }
+
+        let amount_of_matching_tr = get_amount(matching_transaction)?;
+
+        // Partial dispute: only a fraction of the transaction amount is held
+        let disputed_amount = match transaction.amount {
+            Some(_) if amount > amount_of_matching_tr => {
+                return Err(TransactionError::InvalidAmount(amount));
+            }
+            Some(_) => amount,
+            None => amount_of_matching_tr,
+        };
+
+        // Note: the referenced transaction belongs to this client (see account_key)
+        let open_disputes = self
+            .open_disputes
+            .get(&transaction.client)
+            .copied()
+            .unwrap_or(0);
+        if self
+            .options
+            .max_open_disputes
+            .is_some_and(|max| open_disputes >= max)
+        {
+            return Err(TransactionError::TooManyDisputes(transaction.client));
+        }
+
+        let account = self.try_get_account(key)?;
+
+        account.available -= disputed_amount;
+        account.held += disputed_amount;
+        *self.open_disputes.entry(transaction.client).or_default() += 1;
+
+        // XXX: not a fan of this... :-/
+        let matching_transaction = self
+            .get_transaction_mut(transaction.client, transaction.tx)
+            .ok_or(TransactionError::UnknownTransaction(transaction.tx))?;
+
+        matching_transaction.under_dispute = true;
+        matching_transaction.disputed_amount = Some(disputed_amount);
+
+        Ok(())
+    }
+
+    /// Release the funds held by the Dispute of the referenced transaction
+    #[doc(hidden)]
+    fn apply_resolve(
+        &mut self,
+        transaction: Transaction,
+        key: AccountKey,
+    ) -> Result<(), TransactionError> {
+        let matching_transaction = self
+            .get_transaction(transaction.client, transaction.tx)
+            .ok_or(TransactionError::UnknownTransaction(transaction.tx))?;
+        self.check_disputable(matching_transaction)?;
+
+        if !matching_transaction.under_dispute {
+            return Err(TransactionError::TxNonDisputed(transaction.tx));
+        }
+
+        let amount_of_matching_tr = get_disputed_amount(matching_transaction)?;
+        check_referenced_amount(&transaction, amount_of_matching_tr)?;
+
+        let account = self.try_get_account(key)?;
+
+        account.held -= amount_of_matching_tr;
+        account.available += amount_of_matching_tr;
+
+        let matching_transaction = self
+            .get_transaction_mut(transaction.client, transaction.tx)
+            .ok_or(TransactionError::UnknownTransaction(transaction.tx))?;
+
+        matching_transaction.under_dispute = false;
+        matching_transaction.disputed_amount = None;
+        let owner = matching_transaction.client;
+        self.close_dispute(owner);
+
+        Ok(())
+    }
+
+    /// Withdraw the funds held by the Dispute of the referenced transaction & lock the account
+    #[doc(hidden)]
+    fn apply_chargeback(
+        &mut self,
+        transaction: Transaction,
+        key: AccountKey,
+    ) -> Result<(), TransactionError> {
+        let matching_transaction = self
+            .get_transaction(transaction.client, transaction.tx)
+            .ok_or(TransactionError::UnknownTransaction(transaction.tx))?;
+        self.check_disputable(matching_transaction)?;
+
+        if !matching_transaction.under_dispute {
+            // No implicit dispute on a locked account (see chargeback_on_locked)
+            if self.inner.get(&key).is_some_and(|a| a.locked) {
+                return Err(TransactionError::AccountLocked(transaction.client));
+            }
+            if self.options.auto_dispute_on_chargeback {
+                return self.apply_dispute_then_chargeback(transaction, key);
+            }
+            return Err(TransactionError::TxNonDisputed(transaction.tx));
+        }
+
+        let amount_of_matching_tr = get_disputed_amount(matching_transaction)?;
+        check_referenced_amount(&transaction, amount_of_matching_tr)?;
+
+        let account = self.try_get_account(key)?;
+
+        if account.total - amount_of_matching_tr < 0.0 {
+            warn!(
+                "Rejecting chargeback of tx {}: total would be negative (client id: {})",
+                transaction.tx, transaction.client
+            );
+            return Err(TransactionError::NegativeTotal(transaction.tx));
+        }
+
+        account.held -= amount_of_matching_tr;
+        account.total -= amount_of_matching_tr;
+        account.locked = true;
+        self.charged_back.insert(transaction.client);
+
+        let matching_transaction = self
+            .get_transaction_mut(transaction.client, transaction.tx)
+            .ok_or(TransactionError::UnknownTransaction(transaction.tx))?;
+
+        matching_transaction.under_dispute = false;
+        matching_transaction.disputed_amount = None;
+        let owner = matching_transaction.client;
+        self.close_dispute(owner);
+
+        Ok(())
+    }
+
+    /// Dispute (full amount) then charge back a non disputed transaction
+    /// (see [AccountsBuilder::auto_dispute_on_chargeback]), rolled back if any step fails
+    #[doc(hidden)]
+    fn apply_dispute_then_chargeback(
+        &mut self,
+        transaction: Transaction,
+        key: AccountKey,
+    ) -> Result<(), TransactionError> {
+        debug!(
+            "Implicit dispute of tx {} before its chargeback (client id: {})",
+            transaction.tx, transaction.client
+        );
+        let account_before = *self.try_get_account(key)?;
+
+        let mut dispute = transaction.clone();
+        dispute.kind = TransactionKind::Dispute;
+        dispute.amount = None;
+        self.apply_dispute(dispute, key, 0.0)?;
+
+        let tx = transaction.tx;
+        let client_id = transaction.client;
+        let res = self.apply_chargeback(transaction, key);
+        if res.is_err() {
+            *self.try_get_account(key)? = account_before;
+            if let Some(matching_transaction) = self.get_transaction_mut(client_id, tx) {
+                matching_transaction.under_dispute = false;
+                matching_transaction.disputed_amount = None;
+                let owner = matching_transaction.client;
+                self.close_dispute(owner);
+            }
+        }
+        res
+    }
+
+    /// Close an empty account: it is removed & any later transaction on it is rejected
+    #[doc(hidden)]
+    fn apply_close(
+        &mut self,
+        transaction: Transaction,
+        key: AccountKey,
+    ) -> Result<(), TransactionError> {
+        let account = self.try_get_account(key)?;
+
+        if account.available != 0.0 || account.held != 0.0 {
+            return Err(TransactionError::AccountNotEmpty(transaction.client));
+        }
+
+        // Note: its retained tx are kept (a tx id cannot be reused)
+        self.inner.remove(&key);
+        self.closed.insert(key);
+
+        Ok(())
+    }
+}
+
+/// A Resolve or Chargeback should carry no amount: if present, it must match the disputed one
+fn check_referenced_amount(
+    transaction: &Transaction,
+    disputed_amount: f64,
+) -> Result<(), TransactionError> {
+    match transaction.amount {
+        Some(amount) if amount != disputed_amount => {
+            Err(TransactionError::InvalidTransaction(transaction.tx))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Get amount held by the Dispute of a [Transaction] (full amount unless a partial Dispute)
+fn get_disputed_amount(transaction: &Transaction) -> Result<f64, TransactionError> {
+    match transaction.disputed_amount {
+        Some(disputed_amount) => Ok(disputed_amount),
+        None => get_amount(transaction),
+    }
+}
+
+/// Get amount of money for a given [Transaction], returning 0.0 on None
+///
+/// Amount must be strictly positive: a zero amount (or -0.0) is rejected with
+/// [TransactionError::ZeroAmount], a negative one (or NaN) with [TransactionError::InvalidAmount]
+/// (for deposits, withdrawals & fees alike)
+fn get_amount(transaction: &Transaction) -> Result<f64, TransactionError> {
+    match transaction.amount {
+        // -0.0 matches too (-0.0 == 0.0)
+        Some(0.0) => Err(TransactionError::ZeroAmount(transaction.tx)),
+        Some(a) if a > 0.0 => Ok(a),
+        Some(a) => Err(TransactionError::InvalidAmount(a)),
+        None => Ok(0.0),
+    }
+}
+
+/// Check of a csv state file: an account (client id & currency) must appear at most once
+/// (otherwise an [io::ErrorKind::InvalidData] error is returned, before any account is restored)
+fn check_unique_accounts<I>(keys: I) -> Result<(), csv::Error>
+where
+    I: IntoIterator<Item = AccountKey>,
+{
+    let mut seen: HashSet<AccountKey> = HashSet::new();
+    for key in keys {
+        if !seen.insert(key) {
+            return Err(csv::Error::from(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "state: duplicate account (client id: {}, currency: {:?})",
+                    key.0, key.1
+                ),
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Turn a formatted amount into an integer of minor units by dropping its decimal point
+/// (e.g. "25.1100" -> "251100", "-0.5000" -> "-5000", "-0.0000" -> "0")
+fn to_minor_units(amount: &str) -> String {
+    let (sign, digits) = match amount.strip_prefix('-') {
+        Some(digits) => ("-", digits),
+        None => ("", amount),
+    };
+    let digits: String = digits.chars().filter(|c| *c != '.').collect();
+    match digits.trim_start_matches('0') {
+        "" => "0".to_string(),
+        digits => format!("{}{}", sign, digits),
+    }
+}
+
+/// Serialize an amount with [AMOUNT_DECIMALS] decimal places (e.g. 100.0 -> "100.0000")
+pub(crate) fn serialize_amount<S>(amount: &f64, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(&format!("{:.*}", AMOUNT_DECIMALS, amount))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csv_reader::CsvReader;
+    use crate::strategies;
+    use proptest::prelude::*;
+    use std::error::Error;
+    use std::path::PathBuf;
+
+    #[test]
+    fn accounts_output_ok() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::new();
+
+        accounts.add_account((1, None));
+        accounts.add_account((2, None));
+
+        let mut output: Vec<u8> = Vec::new();
+        accounts.output_as_csv(&mut output)?;
+
+        let output_str = std::str::from_utf8(&output).unwrap();
+        // println!("output: {:?}", output_str);
+
+        assert!(
+            output_str
+                == format!(
+                    "{}\n1,,0.0000,0.0000,0.0000,false,0\n2,,0.0000,0.0000,0.0000,false,0\n",
+                    ACCOUNT_CSV_HEADER
+                )
+                || output_str
+                    == format!(
+                        "{}\n2,,0.0000,0.0000,0.0000,false,0\n1,,0.0000,0.0000,0.0000,false,0\n",
+                        ACCOUNT_CSV_HEADER
+                    )
+        );
+        assert_eq!(ACCOUNT_CSV_HEADER, ACCOUNT_CSV_FIELDS.join(","));
+
+        // let mut stdout = std::io::stdout();
+        // accounts.output_as_csv(&mut stdout);
+
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_valid_deposit() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::new();
+
+        let client_id = 1;
+        let deposit_amount = 25.11;
+        let transaction =
+            Transaction::new(TransactionKind::Deposit, client_id, 1, Some(deposit_amount));
+
+        accounts.handle_transaction(transaction)?;
+
+        let account: &Account = accounts
+            .get_client_account(client_id)
+            .ok_or("Cannot client client account")?;
+
+        assert_eq!(account.available, deposit_amount);
+        assert_eq!(account.total, deposit_amount);
+        assert_eq!(account.held, 0.0);
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_invalid_deposit() -> Result<(), Box<dyn Error>> {
+        // Testing deposit < 0, == NAN, == f64::MAX + 9999
+        // Check for distinct error on each cases
+
+        let mut accounts = Accounts::new();
+
+        let client_id = 1;
+
+        let deposit_amount0 = -42.42;
+        let transaction0 = Transaction::new(
+            TransactionKind::Deposit,
+            client_id,
+            1,
+            Some(deposit_amount0),
+        );
+
+        let deposit_amount0_1 = f64::NAN;
+        let transaction0_1 = Transaction::new(
+            TransactionKind::Deposit,
+            client_id,
+            2,
+            Some(deposit_amount0_1),
+        );
+
+        let deposit_amount1 = f64::MAX;
+        let transaction1 = Transaction::new(
+            TransactionKind::Deposit,
+            client_id,
+            3,
+            Some(deposit_amount1),
+        );
 
         let deposit_amount2 = 9999.0;
         let transaction2 = Transaction::new(
             TransactionKind::Deposit,
             client_id,
-            4,
-            Some(deposit_amount2),
-        );
+            4,
+            Some(deposit_amount2),
+        );
+
+        match accounts.handle_transaction(transaction0) {
+            Err(TransactionError::InvalidAmount(a)) => {
+                assert_eq!(a, deposit_amount0);
+            }
+            _ => {
+                panic!("Not an error?");
+            }
+        }
+
+        match accounts.handle_transaction(transaction0_1) {
+            Err(TransactionError::InvalidAmount(a)) => {
+                assert!(a.is_nan());
+            }
+            _ => {
+                panic!("Not an error?");
+            }
+        }
+
+        let account: &Account = accounts
+            .get_client_account(client_id)
+            .ok_or("Cannot client client account")?;
+
+        assert_eq!(account.available, 0.0);
+        assert_eq!(account.total, 0.0);
+        assert_eq!(account.held, 0.0);
+
+        accounts.handle_transaction(transaction1)?;
+
+        match accounts.handle_transaction(transaction2) {
+            Err(TransactionError::AccountAmountTooLarge) => {}
+            _ => {
+                panic!("Not an error?");
+            }
+        }
+
+        let account: &Account = accounts
+            .get_client_account(client_id)
+            .ok_or("Cannot client client account")?;
+
+        println!("account: {:?}", account);
+
+        assert_eq!(account.available, deposit_amount1);
+        assert_eq!(account.total, deposit_amount1);
+        assert_eq!(account.held, 0.0);
+        assert!(account.available.is_normal());
+        assert!(account.total.is_normal());
+
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_valid_withdrawal() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::new();
+
+        let client_id = 1;
+        let deposit_amount = 25.11;
+        let transaction =
+            Transaction::new(TransactionKind::Deposit, client_id, 1, Some(deposit_amount));
+
+        let withdraw_amount = 25.0;
+        let transaction1 = Transaction::new(
+            TransactionKind::Withdrawal,
+            client_id,
+            2,
+            Some(withdraw_amount),
+        );
+
+        accounts.handle_transaction(transaction)?;
+        accounts.handle_transaction(transaction1)?;
+
+        let account: &Account = accounts
+            .get_client_account(client_id)
+            .ok_or("Cannot client client account")?;
+
+        let left_amount = deposit_amount - withdraw_amount;
+
+        assert_eq!(account.available, left_amount);
+        assert_eq!(account.total, left_amount);
+        assert_eq!(account.held, 0.0);
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_withdraw_too_much() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::new();
+
+        let client_id = 1;
+        let deposit_amount = 25.11;
+        let transaction =
+            Transaction::new(TransactionKind::Deposit, client_id, 1, Some(deposit_amount));
+
+        let withdraw_amount = 42.0;
+        let transaction1 = Transaction::new(
+            TransactionKind::Withdrawal,
+            client_id,
+            2,
+            Some(withdraw_amount),
+        );
+
+        accounts.handle_transaction(transaction)?;
+        match accounts.handle_transaction(transaction1) {
+            Err(TransactionError::InvalidAmount(a)) => {
+                assert_eq!(a, withdraw_amount);
+            }
+            _ => {
+                panic!("No error??");
+            }
+        };
+
+        let account: &Account = accounts
+            .get_client_account(client_id)
+            .ok_or("Cannot client client account")?;
+
+        assert_eq!(account.available, deposit_amount);
+        assert_eq!(account.total, deposit_amount);
+        assert_eq!(account.held, 0.0);
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_dispute_then_resolve() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::new();
+
+        let client_id = 1;
+        let deposit_amount = 25.11;
+        let transaction1 =
+            Transaction::new(TransactionKind::Deposit, client_id, 1, Some(deposit_amount));
+
+        let transaction2 = Transaction::new(TransactionKind::Dispute, client_id, 1, None);
+
+        accounts.handle_transaction(transaction1)?;
+        accounts.handle_transaction(transaction2)?;
+
+        let account: &Account = accounts
+            .get_client_account(client_id)
+            .ok_or("Cannot client client account")?;
+
+        assert_eq!(account.available, 0.0);
+        assert_eq!(account.total, deposit_amount);
+        assert_eq!(account.held, deposit_amount);
+
+        // Now Resolve
+
+        let transaction3 = Transaction::new(TransactionKind::Resolve, client_id, 1, None);
+
+        accounts.handle_transaction(transaction3)?;
+
+        let account: &Account = accounts
+            .get_client_account(client_id)
+            .ok_or("Cannot client client account")?;
+
+        assert_eq!(account.available, deposit_amount);
+        assert_eq!(account.total, deposit_amount);
+        assert_eq!(account.held, 0.0);
+
+        Ok(())
+    }
+
+    #[test]
+    #[allow(clippy::bool_assert_comparison)]
+    fn accounts_dispute_then_chargebacks() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::new();
+
+        let client_id = 1;
+        let deposit_amount = 25.11;
+        let transaction1 =
+            Transaction::new(TransactionKind::Deposit, client_id, 1, Some(deposit_amount));
+
+        let transaction2 = Transaction::new(TransactionKind::Dispute, client_id, 1, None);
+
+        accounts.handle_transaction(transaction1)?;
+        accounts.handle_transaction(transaction2)?;
+
+        let account: &Account = accounts
+            .get_client_account(client_id)
+            .ok_or("Cannot client client account")?;
+
+        assert_eq!(account.available, 0.0);
+        assert_eq!(account.total, deposit_amount);
+        assert_eq!(account.held, deposit_amount);
+
+        // Now Chargeback
+
+        let transaction3 = Transaction::new(TransactionKind::Chargeback, client_id, 1, None);
+
+        accounts.handle_transaction(transaction3)?;
+
+        let account: &Account = accounts
+            .get_client_account(client_id)
+            .ok_or("Cannot client client account")?;
+
+        assert_eq!(account.available, 0.0);
+        assert_eq!(account.total, 0.0);
+        assert_eq!(account.held, 0.0);
+        assert_eq!(account.locked, true);
+
+        // Try another Deposit (should be rejected as account is locked)
+
+        let transaction3 =
+            Transaction::new(TransactionKind::Deposit, client_id, 2, Some(deposit_amount));
+
+        match accounts.handle_transaction(transaction3) {
+            Err(TransactionError::AccountLocked(client_id_)) => {
+                assert_eq!(client_id_, client_id);
+            }
+            _ => {
+                panic!("No error??");
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_resolve_non_disputed() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::new();
+
+        let client_id = 1;
+        let deposit_amount = 25.11;
+        let tx = 1;
+        let transaction1 = Transaction::new(
+            TransactionKind::Deposit,
+            client_id,
+            tx,
+            Some(deposit_amount),
+        );
+
+        accounts.handle_transaction(transaction1)?;
+
+        let account: &Account = accounts
+            .get_client_account(client_id)
+            .ok_or("Cannot client client account")?;
+
+        assert_eq!(account.available, deposit_amount);
+        assert_eq!(account.total, deposit_amount);
+        assert_eq!(account.held, 0.0);
+
+        // Now Resolve
+
+        let transaction3 = Transaction::new(TransactionKind::Resolve, client_id, tx, None);
+
+        match accounts.handle_transaction(transaction3) {
+            Err(TransactionError::TxNonDisputed(tx_)) => {
+                assert_eq!(tx, tx_);
+            }
+            _ => {
+                panic!("No error??")
+            }
+        };
+
+        let account: &Account = accounts
+            .get_client_account(client_id)
+            .ok_or("Cannot client client account")?;
+
+        assert_eq!(account.available, deposit_amount);
+        assert_eq!(account.total, deposit_amount);
+        assert_eq!(account.held, 0.0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_non_unique_tx() {
+        let mut accounts = Accounts::new();
+
+        let client_id = 1;
+        let tx = 1;
+        let deposit_amount = 25.11;
+        let withdraw_amount = 5.99;
+        let transaction1 = Transaction::new(
+            TransactionKind::Deposit,
+            client_id,
+            tx,
+            Some(deposit_amount),
+        );
+        let transaction2 = Transaction::new(
+            TransactionKind::Withdrawal,
+            client_id,
+            tx,
+            Some(withdraw_amount),
+        );
+
+        accounts.handle_transaction(transaction1).unwrap();
+
+        match accounts.handle_transaction(transaction2) {
+            Err(TransactionError::InvalidTransaction(tx_)) => {
+                assert_eq!(tx_, tx)
+            }
+            _ => {
+                panic!("No error??")
+            }
+        };
+    }
+
+    #[test]
+    fn accounts_history_deposit_dispute_resolve() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::with_history();
+
+        let client_id = 1;
+        let tx = 1;
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Deposit,
+            client_id,
+            tx,
+            Some(25.11),
+        ))?;
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Dispute,
+            client_id,
+            tx,
+            None,
+        ))?;
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Resolve,
+            client_id,
+            tx,
+            None,
+        ))?;
+        // Rejected transactions are not recorded
+        assert!(accounts
+            .handle_transaction(Transaction::new(
+                TransactionKind::Dispute,
+                client_id,
+                42,
+                None
+            ))
+            .is_err());
+
+        let history = accounts
+            .history(client_id)
+            .ok_or("Cannot get client history")?;
+        let kinds: Vec<TransactionKind> = history.iter().map(|t| t.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                TransactionKind::Deposit,
+                TransactionKind::Dispute,
+                TransactionKind::Resolve
+            ]
+        );
+        assert!(history.iter().all(|t| t.client == client_id && t.tx == tx));
+        assert!(accounts.history(2).is_none());
+
+        // History is opt-in
+        let mut accounts = Accounts::new();
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Deposit,
+            client_id,
+            tx,
+            Some(25.11),
+        ))?;
+        assert!(accounts.history(client_id).is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_output_disputed_count() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::new();
+
+        let client_id = 1;
+        let tx = 1;
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Deposit,
+            client_id,
+            tx,
+            Some(2.0),
+        ))?;
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Deposit,
+            client_id,
+            tx + 1,
+            Some(3.0),
+        ))?;
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Dispute,
+            client_id,
+            tx,
+            None,
+        ))?;
+
+        let mut output: Vec<u8> = Vec::new();
+        accounts.output_as_csv(&mut output)?;
+        assert_eq!(
+            std::str::from_utf8(&output)?,
+            "client,currency,available,held,total,locked,disputed_count\n1,,3.0000,2.0000,5.0000,false,1\n"
+        );
+
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Resolve,
+            client_id,
+            tx,
+            None,
+        ))?;
+
+        let mut output: Vec<u8> = Vec::new();
+        accounts.output_as_csv(&mut output)?;
+        assert_eq!(
+            std::str::from_utf8(&output)?,
+            "client,currency,available,held,total,locked,disputed_count\n1,,5.0000,0.0000,5.0000,false,0\n"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_max_retained_tx_eviction() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::with_max_retained_tx(2);
+
+        let client_id = 1;
+        for tx in 1..=3 {
+            accounts.handle_transaction(Transaction::new(
+                TransactionKind::Deposit,
+                client_id,
+                tx,
+                Some(1.0),
+            ))?;
+        }
+        assert_eq!(accounts.tx.len(), 2);
+
+        // tx 1 has been evicted
+        match accounts.handle_transaction(Transaction::new(
+            TransactionKind::Dispute,
+            client_id,
+            1,
+            None,
+        )) {
+            Err(TransactionError::UnknownTransaction(tx)) => {
+                assert_eq!(tx, 1);
+            }
+            _ => {
+                panic!("No error??");
+            }
+        }
+
+        // A disputed tx is never evicted
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Dispute,
+            client_id,
+            2,
+            None,
+        ))?;
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Deposit,
+            client_id,
+            4,
+            Some(1.0),
+        ))?;
+        assert_eq!(accounts.tx.len(), 2);
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Resolve,
+            client_id,
+            2,
+            None,
+        ))?;
+
+        let account: &Account = accounts
+            .get_client_account(client_id)
+            .ok_or("Cannot client client account")?;
+
+        assert_eq!(account.available, 4.0);
+        assert_eq!(account.total, 4.0);
+        assert_eq!(account.held, 0.0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_with_capacity_same_as_new() -> Result<(), Box<dyn Error>> {
+        for sample in ["resources/sample_1.csv", "resources/sample_2.csv"] {
+            let mut outputs = vec![];
+            for mut accounts in [Accounts::new(), Accounts::with_capacity(16, 1024)] {
+                // Keep track of rejected transactions too (e.g. withdrawal in sample_2.csv)
+                let mut lines: Vec<String> = vec![];
+                for transaction in CsvReader::new(PathBuf::from(sample))? {
+                    if let Err(e) = accounts.handle_transaction(transaction?) {
+                        lines.push(e.to_string());
+                    }
+                }
+
+                let mut output: Vec<u8> = Vec::new();
+                accounts.output_as_csv(&mut output)?;
+                let mut account_lines: Vec<String> = std::str::from_utf8(&output)?
+                    .lines()
+                    .map(String::from)
+                    .collect();
+                account_lines.sort();
+                lines.extend(account_lines);
+                outputs.push(lines);
+            }
+            assert_eq!(outputs[0], outputs[1]);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_valid_fee() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::new();
+
+        let client_id = 1;
+        let deposit_amount = 25.11;
+        let fee_amount = 0.11;
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Deposit,
+            client_id,
+            1,
+            Some(deposit_amount),
+        ))?;
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Fee,
+            client_id,
+            2,
+            Some(fee_amount),
+        ))?;
+
+        let account: &Account = accounts
+            .get_client_account(client_id)
+            .ok_or("Cannot client client account")?;
+
+        assert_eq!(account.available, deposit_amount - fee_amount);
+        assert_eq!(account.total, deposit_amount - fee_amount);
+        assert_eq!(account.held, 0.0);
+
+        // A fee cannot be disputed
+        match accounts.handle_transaction(Transaction::new(
+            TransactionKind::Dispute,
+            client_id,
+            2,
+            None,
+        )) {
+            Err(TransactionError::NonDisputableKind(tx)) => {
+                assert_eq!(tx, 2);
+            }
+            _ => {
+                panic!("No error??");
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_fee_too_much() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::new();
+
+        let client_id = 1;
+        let deposit_amount = 1.0;
+        let fee_amount = 1.5;
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Deposit,
+            client_id,
+            1,
+            Some(deposit_amount),
+        ))?;
+
+        match accounts.handle_transaction(Transaction::new(
+            TransactionKind::Fee,
+            client_id,
+            2,
+            Some(fee_amount),
+        )) {
+            Err(TransactionError::InvalidAmount(a)) => {
+                assert_eq!(a, fee_amount);
+            }
+            _ => {
+                panic!("No error??");
+            }
+        }
+
+        let account: &Account = accounts
+            .get_client_account(client_id)
+            .ok_or("Cannot client client account")?;
+
+        assert_eq!(account.available, deposit_amount);
+        assert_eq!(account.total, deposit_amount);
+        assert_eq!(account.held, 0.0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_identical_replay() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::new();
+
+        let client_id = 1;
+        let deposit_amount = 25.11;
+        let transaction =
+            Transaction::new(TransactionKind::Deposit, client_id, 1, Some(deposit_amount));
+
+        accounts.handle_transaction(transaction.clone())?;
+        accounts.handle_transaction(transaction)?;
+
+        let account: &Account = accounts
+            .get_client_account(client_id)
+            .ok_or("Cannot client client account")?;
+
+        assert_eq!(account.available, deposit_amount);
+        assert_eq!(account.total, deposit_amount);
+        assert_eq!(account.held, 0.0);
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_conflicting_replay() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::new();
+
+        let client_id = 1;
+        let tx = 1;
+        let deposit_amount = 25.11;
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Deposit,
+            client_id,
+            tx,
+            Some(deposit_amount),
+        ))?;
+
+        match accounts.handle_transaction(Transaction::new(
+            TransactionKind::Deposit,
+            client_id,
+            tx,
+            Some(deposit_amount + 1.0),
+        )) {
+            Err(TransactionError::InvalidTransaction(tx_)) => {
+                assert_eq!(tx_, tx)
+            }
+            _ => {
+                panic!("No error??")
+            }
+        };
+
+        let account: &Account = accounts
+            .get_client_account(client_id)
+            .ok_or("Cannot client client account")?;
+
+        assert_eq!(account.available, deposit_amount);
+        assert_eq!(account.total, deposit_amount);
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_deposit_missing_amount() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::new();
+
+        let client_id = 1;
+        let tx = 1;
+        // e.g. "deposit,1,1," (csv row with an empty amount field)
+        let transaction = Transaction::new(TransactionKind::Deposit, client_id, tx, None);
+
+        match accounts.handle_transaction(transaction) {
+            Err(TransactionError::MissingAmount(tx_)) => {
+                assert_eq!(tx_, tx);
+            }
+            _ => {
+                panic!("No error??");
+            }
+        }
+
+        // tx has not been kept
+        assert!(accounts.get_transaction(client_id, tx).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_output_locked() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::new();
+
+        // No locked account: header only
+        let mut output: Vec<u8> = Vec::new();
+        accounts.output_locked_as_csv(&mut output)?;
+        assert_eq!(
+            std::str::from_utf8(&output)?,
+            "client,currency,available,held,total,locked,disputed_count\n"
+        );
+
+        for (client_id, tx) in [(1, 1), (2, 2)] {
+            accounts.handle_transaction(Transaction::new(
+                TransactionKind::Deposit,
+                client_id,
+                tx,
+                Some(2.0),
+            ))?;
+        }
+        accounts.handle_transaction(Transaction::new(TransactionKind::Dispute, 2, 2, None))?;
+        accounts.handle_transaction(Transaction::new(TransactionKind::Chargeback, 2, 2, None))?;
+
+        let mut output: Vec<u8> = Vec::new();
+        accounts.output_locked_as_csv(&mut output)?;
+        assert_eq!(
+            std::str::from_utf8(&output)?,
+            "client,currency,available,held,total,locked,disputed_count\n2,,0.0000,0.0000,0.0000,true,0\n"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_strict_dispute_withdrawal() -> Result<(), Box<dyn Error>> {
+        let client_id = 1;
+        let deposit_amount = 25.11;
+        let withdraw_amount = 5.0;
+        let transactions = [
+            Transaction::new(TransactionKind::Deposit, client_id, 1, Some(deposit_amount)),
+            Transaction::new(
+                TransactionKind::Withdrawal,
+                client_id,
+                2,
+                Some(withdraw_amount),
+            ),
+        ];
+
+        let mut accounts = Accounts::builder()
+            .strict(true)
+            .dispute_withdrawals(true)
+            .build();
+        for transaction in transactions.iter().cloned() {
+            accounts.handle_transaction(transaction)?;
+        }
+
+        match accounts.handle_transaction(Transaction::new(
+            TransactionKind::Dispute,
+            client_id,
+            2,
+            None,
+        )) {
+            Err(TransactionError::CannotDisputeWithdrawal(tx)) => {
+                assert_eq!(tx, 2);
+            }
+            _ => {
+                panic!("No error??");
+            }
+        }
+
+        let account: &Account = accounts
+            .get_client_account(client_id)
+            .ok_or("Cannot client client account")?;
+        assert_eq!(account.available, deposit_amount - withdraw_amount);
+        assert_eq!(account.held, 0.0);
+
+        // Deposits can still be disputed
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Dispute,
+            client_id,
+            1,
+            None,
+        ))?;
+
+        // Non strict mode: accepted
+        let mut accounts = Accounts::builder().dispute_withdrawals(true).build();
+        for transaction in transactions {
+            accounts.handle_transaction(transaction)?;
+        }
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Dispute,
+            client_id,
+            2,
+            None,
+        ))?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_dispute_only_deposits() -> Result<(), Box<dyn Error>> {
+        let client_id = 1;
+        let transactions = [
+            Transaction::new(TransactionKind::Deposit, client_id, 1, Some(10.0)),
+            Transaction::new(TransactionKind::Withdrawal, client_id, 2, Some(4.0)),
+        ];
+
+        // Default policy: only a deposit can be disputed
+        let mut accounts = Accounts::new();
+        for transaction in transactions.iter().cloned() {
+            accounts.handle_transaction(transaction)?;
+        }
+
+        for kind in [
+            TransactionKind::Dispute,
+            TransactionKind::Resolve,
+            TransactionKind::Chargeback,
+        ] {
+            match accounts.handle_transaction(Transaction::new(kind, client_id, 2, None)) {
+                Err(TransactionError::NonDisputableKind(tx)) => {
+                    assert_eq!(tx, 2);
+                }
+                _ => {
+                    panic!("No error??");
+                }
+            }
+        }
+
+        let account: &Account = accounts
+            .get_client_account(client_id)
+            .ok_or("Cannot client client account")?;
+        assert_eq!(account.available, 6.0);
+        assert_eq!(account.held, 0.0);
+        assert!(!account.locked);
+
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Dispute,
+            client_id,
+            1,
+            None,
+        ))?;
+
+        // Relaxed policy: a withdrawal can be disputed then charged back
+        let mut accounts = Accounts::builder().dispute_withdrawals(true).build();
+        for transaction in transactions {
+            accounts.handle_transaction(transaction)?;
+        }
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Dispute,
+            client_id,
+            2,
+            None,
+        ))?;
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Chargeback,
+            client_id,
+            2,
+            None,
+        ))?;
+
+        let account: &Account = accounts
+            .get_client_account(client_id)
+            .ok_or("Cannot client client account")?;
+        assert!(account.locked);
+
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_builder() -> Result<(), Box<dyn Error>> {
+        let client_id = 1;
+        let transactions = [
+            Transaction::new(TransactionKind::Deposit, client_id, 1, Some(10.0)),
+            Transaction::new(TransactionKind::Deposit, client_id, 2, Some(10.0)),
+            Transaction::new(TransactionKind::Withdrawal, client_id, 3, Some(5.0)),
+        ];
+        let dispute = Transaction::new(TransactionKind::Dispute, client_id, 3, None);
+
+        let mut accounts1 = Accounts::builder()
+            .capacity(1, 3)
+            .history(true)
+            .strict(true)
+            .dispute_withdrawals(true)
+            .max_retained_tx(Some(1))
+            .build();
+        let mut accounts2 = Accounts::builder().dispute_withdrawals(true).build();
+
+        for transaction in transactions {
+            accounts1.handle_transaction(transaction.clone())?;
+            accounts2.handle_transaction(transaction)?;
+        }
+
+        assert_eq!(accounts1.tx.len(), 1);
+        assert_eq!(accounts2.tx.len(), 3);
+        assert_eq!(accounts1.history(client_id).map(|h| h.len()), Some(3));
+        assert!(accounts2.history(client_id).is_none());
+
+        assert!(matches!(
+            accounts1.handle_transaction(dispute.clone()),
+            Err(TransactionError::CannotDisputeWithdrawal(3))
+        ));
+        assert!(accounts2.handle_transaction(dispute).is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_zero_amount() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::new();
+
+        let client_id = 1;
+        for (tx, kind, amount) in [
+            (1, TransactionKind::Deposit, 0.0),
+            (2, TransactionKind::Deposit, -0.0),
+            (3, TransactionKind::Withdrawal, 0.0),
+            (4, TransactionKind::Withdrawal, -0.0),
+        ] {
+            match accounts.handle_transaction(Transaction::new(kind, client_id, tx, Some(amount))) {
+                Err(TransactionError::ZeroAmount(t)) => {
+                    assert_eq!(t, tx);
+                }
+                _ => {
+                    panic!("No error??");
+                }
+            }
+        }
+
+        // A `deposit,1,1,0` csv row
+        let csv = b"type,client,tx,amount\ndeposit,1,5,0\n";
+        for transaction in CsvReader::from_reader(csv.as_slice())? {
+            match accounts.handle_transaction(transaction?) {
+                Err(e @ TransactionError::ZeroAmount(5)) => {
+                    assert_eq!(e.to_string(), "Amount is zero (tx: 5)");
+                }
+                _ => {
+                    panic!("No error??");
+                }
+            }
+        }
+
+        // Still an invalid amount if negative
+        match accounts.handle_transaction(Transaction::new(
+            TransactionKind::Deposit,
+            client_id,
+            6,
+            Some(-1.0),
+        )) {
+            Err(TransactionError::InvalidAmount(a)) => {
+                assert_eq!(a, -1.0);
+            }
+            _ => {
+                panic!("No error??");
+            }
+        }
+
+        assert!(accounts.tx.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_reversal() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::new();
+
+        let client_id = 1;
+        let tx = 1;
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Deposit,
+            client_id,
+            tx,
+            Some(25.11),
+        ))?;
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Reversal,
+            client_id,
+            tx,
+            None,
+        ))?;
+
+        let account: &Account = accounts
+            .get_client_account(client_id)
+            .ok_or("Cannot client client account")?;
+
+        assert_eq!(account.available, 0.0);
+        assert_eq!(account.total, 0.0);
+        assert_eq!(account.held, 0.0);
+
+        // A reversed deposit cannot be disputed
+        match accounts.handle_transaction(Transaction::new(
+            TransactionKind::Dispute,
+            client_id,
+            tx,
+            None,
+        )) {
+            Err(TransactionError::TxReversed(tx_)) => {
+                assert_eq!(tx_, tx);
+            }
+            _ => {
+                panic!("No error??");
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_reversal_insufficient_funds() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::new();
+
+        let client_id = 1;
+        let tx = 1;
+        let deposit_amount = 25.11;
+        let withdraw_amount = 20.0;
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Deposit,
+            client_id,
+            tx,
+            Some(deposit_amount),
+        ))?;
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Withdrawal,
+            client_id,
+            2,
+            Some(withdraw_amount),
+        ))?;
+
+        match accounts.handle_transaction(Transaction::new(
+            TransactionKind::Reversal,
+            client_id,
+            tx,
+            None,
+        )) {
+            Err(TransactionError::InsufficientFunds(tx_)) => {
+                assert_eq!(tx_, tx);
+            }
+            _ => {
+                panic!("No error??");
+            }
+        }
+
+        let account: &Account = accounts
+            .get_client_account(client_id)
+            .ok_or("Cannot client client account")?;
+
+        assert_eq!(account.available, deposit_amount - withdraw_amount);
+        assert_eq!(account.total, deposit_amount - withdraw_amount);
+
+        // Deposit has not been reversed, it can still be disputed
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Dispute,
+            client_id,
+            tx,
+            None,
+        ))?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_output_integer_amounts() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::new();
+
+        for transaction in CsvReader::new(PathBuf::from("resources/sample_3_integer_amounts.csv"))?
+        {
+            accounts.handle_transaction(transaction?)?;
+        }
+
+        let mut output: Vec<u8> = Vec::new();
+        accounts.output_as_csv(&mut output)?;
+        let mut lines: Vec<&str> = std::str::from_utf8(&output)?.lines().collect();
+        lines.sort();
+
+        assert_eq!(
+            lines,
+            vec![
+                "1,,100.5000,0.0000,100.5000,false,0",
+                "2,,50.0000,0.0000,50.0000,false,0",
+                "client,currency,available,held,total,locked,disputed_count",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_output_write_error() {
+        /// A writer failing once `limit` bytes have been written
+        struct FailingWriter {
+            written: usize,
+            limit: usize,
+        }
+
+        impl Write for FailingWriter {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                if self.written + buf.len() > self.limit {
+                    return Err(std::io::Error::other("writer is full"));
+                }
+                self.written += buf.len();
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut accounts = Accounts::new();
+        accounts.add_account((1, None));
+        accounts.add_account((2, None));
+
+        // Room for header + 1st account line only
+        let header_and_line_len = "client,currency,available,held,total,locked,disputed_count\n"
+            .len()
+            + "1,,0.0000,0.0000,0.0000,false,0\n".len();
+        let mut writer = FailingWriter {
+            written: 0,
+            limit: header_and_line_len,
+        };
+
+        match accounts.output_as_csv(&mut writer) {
+            Err(e) => {
+                assert!(matches!(e.kind(), csv::ErrorKind::Io(_)));
+            }
+            _ => {
+                panic!("No error??");
+            }
+        }
+    }
+
+    #[test]
+    fn accounts_validate() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::new();
+
+        let client_id = 1;
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Deposit,
+            client_id,
+            1,
+            Some(0.1),
+        ))?;
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Deposit,
+            client_id,
+            2,
+            Some(0.2),
+        ))?;
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Dispute,
+            client_id,
+            1,
+            None,
+        ))?;
+        accounts.validate()?;
+
+        // Deliberately break the accounting identity
+        accounts.try_get_account((client_id, None))?.held += 1.0;
+        let e = accounts.validate().unwrap_err();
+        assert!(e.contains("client id: 1"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_output_empty() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::new();
+        for transaction in CsvReader::new(PathBuf::from("resources/empty.csv"))? {
+            accounts.handle_transaction(transaction?)?;
+        }
+
+        let mut output: Vec<u8> = Vec::new();
+        accounts.output_as_csv(&mut output)?;
+        assert_eq!(
+            std::str::from_utf8(&output)?,
+            "client,currency,available,held,total,locked,disputed_count\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_overdraft() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::new();
+
+        let client_id = 1;
+        let deposit_amount = 10.0;
+        accounts.set_overdraft(client_id, 5.0);
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Deposit,
+            client_id,
+            1,
+            Some(deposit_amount),
+        ))?;
+
+        // Permitted overdraft
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Withdrawal,
+            client_id,
+            2,
+            Some(14.0),
+        ))?;
+
+        let account: &Account = accounts
+            .get_client_account(client_id)
+            .ok_or("Cannot client client account")?;
+        assert_eq!(account.available, -4.0);
+        assert_eq!(account.total, -4.0);
+
+        // Exceeding the limit
+        match accounts.handle_transaction(Transaction::new(
+            TransactionKind::Withdrawal,
+            client_id,
+            3,
+            Some(1.5),
+        )) {
+            Err(TransactionError::InvalidAmount(a)) => {
+                assert_eq!(a, 1.5);
+            }
+            _ => {
+                panic!("No error??");
+            }
+        }
+
+        // No overdraft for other clients
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Deposit,
+            2,
+            4,
+            Some(deposit_amount),
+        ))?;
+        assert!(accounts
+            .handle_transaction(Transaction::new(
+                TransactionKind::Withdrawal,
+                2,
+                5,
+                Some(14.0),
+            ))
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_dispute_timestamps() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::new();
+
+        let client_id = 1;
+        for tx in [1, 2] {
+            accounts.handle_transaction(
+                Transaction::new(TransactionKind::Deposit, client_id, tx, Some(1.0))
+                    .with_timestamp(100),
+            )?;
+        }
+
+        // In order
+        accounts.handle_transaction(
+            Transaction::new(TransactionKind::Dispute, client_id, 1, None).with_timestamp(100),
+        )?;
+        // Out of order
+        match accounts.handle_transaction(
+            Transaction::new(TransactionKind::Dispute, client_id, 2, None).with_timestamp(99),
+        ) {
+            Err(TransactionError::OutOfOrder(tx)) => {
+                assert_eq!(tx, 2);
+            }
+            _ => {
+                panic!("No error??");
+            }
+        }
+        // No timestamp: no check
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Dispute,
+            client_id,
+            2,
+            None,
+        ))?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_report() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::new();
+
+        for tx in [1, 2] {
+            accounts.handle_transaction(Transaction::new(
+                TransactionKind::Deposit,
+                1,
+                tx,
+                Some(2.0),
+            ))?;
+        }
+        accounts.handle_transaction(Transaction::new(TransactionKind::Dispute, 1, 1, None))?;
+        accounts.handle_transaction(Transaction::new(TransactionKind::Deposit, 2, 3, Some(1.5)))?;
+
+        let report = accounts.report();
+        assert_eq!(report.len(), 2);
+
+        let disputed = &report[0];
+        assert_eq!(disputed.client, 1);
+        assert_eq!(disputed.available, 2.0);
+        assert_eq!(disputed.held, 2.0);
+        assert_eq!(disputed.total, 4.0);
+        assert!(!disputed.locked);
+        assert_eq!(disputed.tx_count, 2);
+        assert_eq!(disputed.disputed_count, 1);
+        assert!(disputed.has_open_dispute());
+
+        assert_eq!(report[1].client, 2);
+        assert_eq!(report[1].tx_count, 1);
+        assert!(!report[1].has_open_dispute());
+
+        // Columns are aligned with the header
+        assert_eq!(disputed.to_string().len(), AccountReport::header().len());
+        assert_eq!(
+            disputed.to_string(),
+            "     1                    2.0000           2.0000           4.0000  false        2        1"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_snapshot_restore() -> Result<(), Box<dyn Error>> {
+        let mut transactions: Vec<Transaction> =
+            CsvReader::new(PathBuf::from("resources/sample_1.csv"))?.collect::<Result<_, _>>()?;
+        // Disputes open before the snapshot, closed after it
+        transactions.extend([
+            Transaction::new(TransactionKind::Dispute, 1, 1, None),
+            Transaction::new(TransactionKind::Dispute, 2, 2, None),
+            Transaction::new(TransactionKind::Withdrawal, 2, 6, Some(1.0)), // rejected
+            Transaction::new(TransactionKind::Resolve, 1, 1, None),
+            Transaction::new(TransactionKind::Chargeback, 2, 2, None),
+            Transaction::new(TransactionKind::Deposit, 2, 7, Some(1.0)), // rejected (locked)
+            Transaction::new(TransactionKind::Deposit, 3, 8, Some(1.0)),
+        ]);
+        let (first_half, second_half) = transactions.split_at(7);
+
+        let run = |accounts: &mut Accounts, transactions: &[Transaction]| -> Vec<String> {
+            transactions
+                .iter()
+                .filter_map(|t| accounts.handle_transaction(t.clone()).err())
+                .map(|e| e.to_string())
+                .collect()
+        };
+
+        // Single run baseline
+        let mut baseline = Accounts::new();
+        let mut baseline_errors = run(&mut baseline, first_half);
+        let snapshot = baseline.snapshot();
+        baseline_errors.extend(run(&mut baseline, second_half));
+
+        let mut accounts = Accounts::new();
+        let mut errors = run(&mut accounts, first_half);
+        assert_eq!(accounts.snapshot(), snapshot);
+
+        // Resume in a fresh instance
+        let mut resumed = Accounts::new();
+        resumed.restore(accounts.snapshot());
+        assert_eq!(resumed.snapshot(), snapshot);
+        errors.extend(run(&mut resumed, second_half));
+
+        assert_eq!(baseline_errors.len(), 2);
+        assert_eq!(errors, baseline_errors);
+        assert_eq!(resumed.snapshot(), baseline.snapshot());
+        assert_eq!(resumed.report(), baseline.report());
+
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_resolve_mismatched_amount() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::new();
+
+        let client_id = 1;
+        let tx = 1;
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Deposit,
+            client_id,
+            tx,
+            Some(2.0),
+        ))?;
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Dispute,
+            client_id,
+            tx,
+            None,
+        ))?;
+
+        for kind in [TransactionKind::Resolve, TransactionKind::Chargeback] {
+            match accounts.handle_transaction(Transaction::new(kind, client_id, tx, Some(3.0))) {
+                Err(TransactionError::InvalidTransaction(tx_)) => {
+                    assert_eq!(tx_, tx);
+                }
+                _ => {
+                    panic!("No error??");
+                }
+            }
+        }
+
+        // Still under dispute, a matching amount is accepted
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Resolve,
+            client_id,
+            tx,
+            Some(2.0),
+        ))?;
+
+        let account = accounts
+            .get_client_account(client_id)
+            .ok_or("Cannot client client account")?;
+        assert_eq!(account.available, 2.0);
+        assert_eq!(account.held, 0.0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_output_scale() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::new();
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Deposit,
+            1,
+            1,
+            Some(1.23456),
+        ))?;
+
+        let mut outputs = vec![];
+        for scale in [2, 4] {
+            accounts.set_scale(scale)?;
+            let mut output: Vec<u8> = Vec::new();
+            accounts.output_as_csv(&mut output)?;
+            outputs.push(String::from_utf8(output)?);
+        }
+        assert_eq!(
+            outputs,
+            vec![
+                "client,currency,available,held,total,locked,disputed_count\n1,,1.23,0.00,1.23,false,0\n",
+                "client,currency,available,held,total,locked,disputed_count\n1,,1.2346,0.0000,1.2346,false,0\n",
+            ]
+        );
+
+        assert!(accounts.set_scale(MAX_AMOUNT_SCALE).is_ok());
+        assert!(accounts.set_scale(MAX_AMOUNT_SCALE + 1).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_per_client_tx() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::builder().per_client_tx(true).build();
+
+        // 2 clients reusing tx 1
+        let tx = 1;
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Deposit,
+            1,
+            tx,
+            Some(1.0),
+        ))?;
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Deposit,
+            2,
+            tx,
+            Some(2.0),
+        ))?;
+        assert_eq!(accounts.tx.len(), 2);
+
+        // Dispute references the transaction of its own client
+        accounts.handle_transaction(Transaction::new(TransactionKind::Dispute, 2, tx, None))?;
+        accounts.handle_transaction(Transaction::new(TransactionKind::Chargeback, 2, tx, None))?;
+
+        let account = accounts
+            .get_client_account(1)
+            .ok_or("Cannot client client account")?;
+        assert_eq!(account.available, 1.0);
+        assert!(!account.locked);
+        let account = accounts
+            .get_client_account(2)
+            .ok_or("Cannot client client account")?;
+        assert_eq!(account.total, 0.0);
+        assert!(account.locked);
+
+        // Still unique for a given client
+        match accounts.handle_transaction(Transaction::new(
+            TransactionKind::Deposit,
+            1,
+            tx,
+            Some(3.0),
+        )) {
+            Err(TransactionError::InvalidTransaction(tx_)) => {
+                assert_eq!(tx_, tx);
+            }
+            _ => {
+                panic!("No error??");
+            }
+        }
+
+        // Default: globally unique
+        let mut accounts = Accounts::new();
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Deposit,
+            1,
+            tx,
+            Some(1.0),
+        ))?;
+        assert!(accounts
+            .handle_transaction(Transaction::new(TransactionKind::Deposit, 2, tx, Some(2.0)))
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_reconcile() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::new();
+        accounts.handle_transaction(Transaction::new(TransactionKind::Deposit, 1, 1, Some(2.0)))?;
+        accounts.handle_transaction(Transaction::new(TransactionKind::Deposit, 2, 2, Some(1.0)))?;
+
+        // Corrupted snapshot: total of client 2 is off
+        let mut snapshot = accounts.snapshot();
+        snapshot.accounts[1].1.total = 5.0;
+
+        let mut restored = Accounts::new();
+        restored.restore(snapshot);
+        assert!(restored.validate().is_err());
+
+        assert_eq!(restored.reconcile(), vec![2]);
+        restored.validate()?;
+        let account = restored
+            .get_client_account(2)
+            .ok_or("Cannot client client account")?;
+        assert_eq!(account.total, 1.0);
+
+        // Nothing left to repair
+        assert!(restored.reconcile().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_transaction_outcome() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::new();
+
+        let client_id = 1;
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Deposit,
+            client_id,
+            1,
+            Some(1.0),
+        ))?;
+
+        let deposit = Transaction::new(TransactionKind::Deposit, client_id, 2, Some(2.5));
+        let outcome = accounts.handle_transaction_with_outcome(deposit.clone())?;
+        assert_eq!(
+            outcome,
+            TransactionOutcome {
+                client: client_id,
+                currency: None,
+                kind: TransactionKind::Deposit,
+                available_delta: 2.5,
+                held_delta: 0.0,
+                total_delta: 2.5,
+                available: 3.5,
+                held: 0.0,
+                total: 3.5,
+                locked: false,
+                replay: false,
+            }
+        );
+
+        let outcome = accounts.handle_transaction_with_outcome(Transaction::new(
+            TransactionKind::Dispute,
+            client_id,
+            2,
+            None,
+        ))?;
+        assert_eq!(outcome.kind, TransactionKind::Dispute);
+        assert_eq!(outcome.available_delta, -2.5);
+        assert_eq!(outcome.held_delta, 2.5);
+        assert_eq!(outcome.total_delta, 0.0);
+        assert_eq!(outcome.available, 1.0);
+        assert_eq!(outcome.held, 2.5);
+        assert_eq!(outcome.total, 3.5);
+        assert!(!outcome.locked);
+
+        // Replay: nothing changed
+        let outcome = accounts.handle_transaction_with_outcome(deposit)?;
+        assert!(outcome.replay);
+        assert_eq!(outcome.total_delta, 0.0);
+        assert_eq!(outcome.total, 3.5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_output_rounding() -> Result<(), Box<dyn Error>> {
+        let mut outputs = vec![];
+        for rounding in [RoundingMode::HalfUp, RoundingMode::HalfEven] {
+            let mut accounts = Accounts::builder().rounding(rounding).build();
+            accounts.handle_transaction(Transaction::new(
+                TransactionKind::Deposit,
+                1,
+                1,
+                Some(0.00005),
+            ))?;
+            accounts.handle_transaction(Transaction::new(
+                TransactionKind::Deposit,
+                2,
+                2,
+                Some(0.00015),
+            ))?;
+
+            let mut output: Vec<u8> = Vec::new();
+            accounts.output_as_csv(&mut output)?;
+            let mut lines: Vec<String> = std::str::from_utf8(&output)?
+                .lines()
+                .skip(1)
+                .map(String::from)
+                .collect();
+            lines.sort();
+            outputs.push(lines);
+        }
+
+        assert_eq!(
+            outputs[0],
+            vec![
+                "1,,0.0001,0.0000,0.0001,false,0",
+                "2,,0.0002,0.0000,0.0002,false,0"
+            ]
+        );
+        assert_eq!(
+            outputs[1],
+            vec![
+                "1,,0.0000,0.0000,0.0000,false,0",
+                "2,,0.0002,0.0000,0.0002,false,0"
+            ]
+        );
+
+        // Not a tie, negative amounts
+        assert_eq!(RoundingMode::HalfEven.round(0.00006, 4), 0.0001);
+        assert_eq!(RoundingMode::HalfEven.round(-0.00005, 4), -0.0);
+        assert_eq!(RoundingMode::HalfUp.round(-0.00005, 4), -0.0001);
+
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_locked_dispute() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::new();
+
+        let client_id = 1;
+        for tx in [1, 2] {
+            accounts.handle_transaction(Transaction::new(
+                TransactionKind::Deposit,
+                client_id,
+                tx,
+                Some(2.0),
+            ))?;
+        }
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Dispute,
+            client_id,
+            1,
+            None,
+        ))?;
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Chargeback,
+            client_id,
+            1,
+            None,
+        ))?;
+
+        // Dispute on another transaction of the locked account
+        match accounts.handle_transaction(Transaction::new(
+            TransactionKind::Dispute,
+            client_id,
+            2,
+            None,
+        )) {
+            Err(TransactionError::AccountLocked(client_id_)) => {
+                assert_eq!(client_id_, client_id);
+            }
+            _ => {
+                panic!("No error??");
+            }
+        }
+
+        let account = accounts
+            .get_client_account(client_id)
+            .ok_or("Cannot client client account")?;
+        assert_eq!(account.available, 2.0);
+        assert_eq!(account.held, 0.0);
+        assert!(account.locked);
+
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_chargeback_then_dispute() -> Result<(), Box<dyn Error>> {
+        // tx 3 is disputed before the chargeback of tx 1, tx 2 is disputed after it
+        let mut accounts = Accounts::new();
+        let mut errors = vec![];
+        for transaction in CsvReader::new(PathBuf::from(
+            "resources/sample_5_chargeback_then_dispute.csv",
+        ))? {
+            let transaction = transaction?;
+            let tx = transaction.tx;
+            if let Err(e) = accounts.handle_transaction(transaction) {
+                errors.push((tx, e));
+            }
+        }
+
+        // Every dispute family transaction after the lock is rejected: held funds (of the
+        // dispute opened before the lock) stay held
+        let txs: Vec<u32> = errors.iter().map(|(tx, _)| *tx).collect();
+        assert_eq!(txs, vec![2, 3, 3]);
+        assert!(errors
+            .iter()
+            .all(|(_, e)| matches!(e, TransactionError::AccountLocked(1))));
+
+        let account = accounts
+            .get_client_account(1)
+            .ok_or("Cannot client client account")?;
+        assert_eq!(account.available, 3.0);
+        assert_eq!(account.held, 4.0);
+        assert_eq!(account.total, 7.0);
+        assert!(account.locked);
+
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_apply_all() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::new();
+        accounts.apply_all(vec![
+            Transaction::new(TransactionKind::Deposit, 1, 1, Some(3.0)),
+            Transaction::new(TransactionKind::Deposit, 2, 2, Some(2.0)),
+            Transaction::new(TransactionKind::Withdrawal, 1, 3, Some(1.0)),
+            Transaction::new(TransactionKind::Dispute, 2, 2, None),
+        ])?;
+
+        let account = accounts
+            .get_client_account(1)
+            .ok_or("Cannot client client account")?;
+        assert_eq!(account.available, 2.0);
+        assert_eq!(account.total, 2.0);
+        let account = accounts
+            .get_client_account(2)
+            .ok_or("Cannot client client account")?;
+        assert_eq!(account.available, 0.0);
+        assert_eq!(account.held, 2.0);
+
+        // Stop at first error
+        match accounts.apply_all(vec![
+            Transaction::new(TransactionKind::Withdrawal, 1, 4, Some(5.0)),
+            Transaction::new(TransactionKind::Deposit, 1, 5, Some(1.0)),
+        ]) {
+            Err(TransactionError::InvalidAmount(amount)) => {
+                assert_eq!(amount, 5.0);
+            }
+            _ => {
+                panic!("No error??");
+            }
+        }
+        let account = accounts
+            .get_client_account(1)
+            .ok_or("Cannot client client account")?;
+        assert_eq!(account.total, 2.0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_partial_dispute() -> Result<(), Box<dyn Error>> {
+        for kind in [TransactionKind::Resolve, TransactionKind::Chargeback] {
+            let mut accounts = Accounts::new();
+
+            let client_id = 1;
+            let tx = 1;
+            accounts.handle_transaction(Transaction::new(
+                TransactionKind::Deposit,
+                client_id,
+                tx,
+                Some(4.0),
+            ))?;
+
+            // More than the original amount
+            match accounts.handle_transaction(Transaction::new(
+                TransactionKind::Dispute,
+                client_id,
+                tx,
+                Some(5.0),
+            )) {
+                Err(TransactionError::InvalidAmount(amount)) => {
+                    assert_eq!(amount, 5.0);
+                }
+                _ => {
+                    panic!("No error??");
+                }
+            }
+
+            // Half dispute
+            accounts.handle_transaction(Transaction::new(
+                TransactionKind::Dispute,
+                client_id,
+                tx,
+                Some(2.0),
+            ))?;
+            let account = accounts
+                .get_client_account(client_id)
+                .ok_or("Cannot client client account")?;
+            assert_eq!(account.available, 2.0);
+            assert_eq!(account.held, 2.0);
+
+            accounts.handle_transaction(Transaction::new(kind, client_id, tx, None))?;
+            let account = accounts
+                .get_client_account(client_id)
+                .ok_or("Cannot client client account")?;
+            assert_eq!(account.held, 0.0);
+            if kind == TransactionKind::Resolve {
+                assert_eq!(account.available, 4.0);
+                assert_eq!(account.total, 4.0);
+                assert!(!account.locked);
+            } else {
+                assert_eq!(account.available, 2.0);
+                assert_eq!(account.total, 2.0);
+                assert!(account.locked);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_currencies() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::new();
+        let transactions: Vec<Transaction> =
+            CsvReader::new(PathBuf::from("resources/sample_7_currencies.csv"))?
+                .collect::<Result<_, _>>()?;
+        // Dispute (without currency) applies to the EUR account
+        accounts.apply_all(transactions)?;
+
+        let mut output: Vec<u8> = Vec::new();
+        accounts.output_as_csv(&mut output)?;
+        let mut lines: Vec<&str> = std::str::from_utf8(&output)?.lines().collect();
+        lines.sort();
+        assert_eq!(
+            lines,
+            vec![
+                "1,EUR,0.0000,5.0000,5.0000,false,1",
+                "1,USD,7.5000,0.0000,7.5000,false,0",
+                "client,currency,available,held,total,locked,disputed_count",
+            ]
+        );
+
+        // Cross currency
+        let usd: Currency = "USD".parse()?;
+        match accounts.handle_transaction(
+            Transaction::new(TransactionKind::Resolve, 1, 2, None).with_currency(usd),
+        ) {
+            Err(TransactionError::CurrencyMismatch(tx)) => {
+                assert_eq!(tx, 2);
+            }
+            _ => {
+                panic!("No error??");
+            }
+        }
+
+        // Not enough USD (even if enough in total)
+        assert!(accounts
+            .handle_transaction(
+                Transaction::new(TransactionKind::Withdrawal, 1, 4, Some(10.0)).with_currency(usd)
+            )
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_amount_limits() -> Result<(), Box<dyn Error>> {
+        let client_id = 1;
+        let mut accounts = Accounts::builder()
+            .max_transaction_amount(Some(100.0))
+            .max_account_total(Some(150.0))
+            .build();
+
+        // At the limits: accepted
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Deposit,
+            client_id,
+            1,
+            Some(100.0),
+        ))?;
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Deposit,
+            client_id,
+            2,
+            Some(50.0),
+        ))?;
+
+        // Just over the account limit
+        match accounts.handle_transaction(Transaction::new(
+            TransactionKind::Deposit,
+            client_id,
+            3,
+            Some(0.0001),
+        )) {
+            Err(TransactionError::AmountExceedsLimit(tx)) => {
+                assert_eq!(tx, 3);
+            }
+            _ => {
+                panic!("No error??");
+            }
+        }
+
+        // Just over the transaction limit (account total would be within its limit)
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Withdrawal,
+            client_id,
+            4,
+            Some(150.0),
+        ))?;
+        match accounts.handle_transaction(Transaction::new(
+            TransactionKind::Deposit,
+            client_id,
+            5,
+            Some(100.0001),
+        )) {
+            Err(TransactionError::AmountExceedsLimit(tx)) => {
+                assert_eq!(tx, 5);
+            }
+            _ => {
+                panic!("No error??");
+            }
+        }
+
+        let account: &Account = accounts
+            .get_client_account(client_id)
+            .ok_or("Cannot client client account")?;
+        assert_eq!(account.available, 0.0);
+        assert_eq!(account.total, 0.0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_merge() -> Result<(), Box<dyn Error>> {
+        let mut accounts1 = Accounts::new();
+        let mut accounts2 = Accounts::new();
+
+        // Client 1 only in accounts1, client 2 only in accounts2, client 3 in both
+        for transaction in [
+            Transaction::new(TransactionKind::Deposit, 1, 1, Some(10.0)),
+            Transaction::new(TransactionKind::Deposit, 3, 2, Some(5.0)),
+            Transaction::new(TransactionKind::Dispute, 3, 2, None),
+        ] {
+            accounts1.handle_transaction(transaction)?;
+        }
+        for transaction in [
+            Transaction::new(TransactionKind::Deposit, 2, 3, Some(20.0)),
+            Transaction::new(TransactionKind::Deposit, 3, 4, Some(7.0)),
+            Transaction::new(TransactionKind::Dispute, 3, 4, None),
+            Transaction::new(TransactionKind::Chargeback, 3, 4, None),
+        ] {
+            accounts2.handle_transaction(transaction)?;
+        }
+
+        // A tx id collision with a different content is rejected
+        let mut accounts3 = Accounts::new();
+        accounts3.handle_transaction(Transaction::new(
+            TransactionKind::Deposit,
+            3,
+            2,
+            Some(1.0),
+        ))?;
+        match accounts1.merge(accounts3) {
+            Err(TransactionError::InvalidTransaction(tx)) => {
+                assert_eq!(tx, 2);
+            }
+            _ => {
+                panic!("No error??");
+            }
+        }
+        assert_eq!(accounts1.tx.len(), 2);
+
+        accounts1.merge(accounts2)?;
+
+        assert_eq!(accounts1.tx.len(), 4);
+        let account: &Account = accounts1
+            .get_client_account(1)
+            .ok_or("Cannot client client account")?;
+        assert_eq!(account.total, 10.0);
+        let account: &Account = accounts1
+            .get_client_account(2)
+            .ok_or("Cannot client client account")?;
+        assert_eq!(account.total, 20.0);
+        let account: &Account = accounts1
+            .get_client_account(3)
+            .ok_or("Cannot client client account")?;
+        assert_eq!(account.available, 0.0);
+        assert_eq!(account.held, 5.0);
+        assert_eq!(account.total, 5.0);
+        assert!(account.locked);
+
+        // Disputed tx of accounts1 is still disputed
+        assert!(accounts1.tx.get(&(0, 2)).is_some_and(|t| t.under_dispute));
+
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_chargeback_negative_total() -> Result<(), Box<dyn Error>> {
+        let client_id = 1;
+        let mut accounts = Accounts::new();
+        for transaction in [
+            Transaction::new(TransactionKind::Deposit, client_id, 1, Some(10.0)),
+            Transaction::new(TransactionKind::Withdrawal, client_id, 2, Some(8.0)),
+            Transaction::new(TransactionKind::Dispute, client_id, 1, None),
+        ] {
+            accounts.handle_transaction(transaction)?;
+        }
+
+        match accounts.handle_transaction(Transaction::new(
+            TransactionKind::Chargeback,
+            client_id,
+            1,
+            None,
+        )) {
+            Err(TransactionError::NegativeTotal(tx)) => {
+                assert_eq!(tx, 1);
+            }
+            _ => {
+                panic!("No error??");
+            }
+        }
+
+        // Account is left untouched (still disputed)
+        let account: &Account = accounts
+            .get_client_account(client_id)
+            .ok_or("Cannot client client account")?;
+        assert_eq!(account.available, -8.0);
+        assert_eq!(account.held, 10.0);
+        assert_eq!(account.total, 2.0);
+        assert!(!account.locked);
+
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Resolve,
+            client_id,
+            1,
+            None,
+        ))?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_close() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::new();
+        for transaction in [
+            Transaction::new(TransactionKind::Deposit, 1, 1, Some(10.0)),
+            Transaction::new(TransactionKind::Withdrawal, 1, 2, Some(10.0)),
+            Transaction::new(TransactionKind::Deposit, 2, 3, Some(5.0)),
+        ] {
+            accounts.handle_transaction(transaction)?;
+        }
+
+        // Clean close
+        accounts.handle_transaction(Transaction::new(TransactionKind::Close, 1, 4, None))?;
+        assert!(accounts.get_client_account(1).is_none());
+
+        let mut output = Vec::new();
+        accounts.output_as_csv(&mut output)?;
+        assert_eq!(
+            std::str::from_utf8(&output)?,
+            "client,currency,available,held,total,locked,disputed_count\n2,,5.0000,0.0000,5.0000,false,0\n"
+        );
+
+        for transaction in [
+            Transaction::new(TransactionKind::Deposit, 1, 5, Some(1.0)),
+            Transaction::new(TransactionKind::Dispute, 1, 1, None),
+            Transaction::new(TransactionKind::Close, 1, 6, None),
+        ] {
+            match accounts.handle_transaction(transaction) {
+                Err(TransactionError::AccountClosed(client_id)) => {
+                    assert_eq!(client_id, 1);
+                }
+                _ => {
+                    panic!("No error??");
+                }
+            }
+        }
+
+        // Rejected close: remaining funds (available, then held)
+        match accounts.handle_transaction(Transaction::new(TransactionKind::Close, 2, 7, None)) {
+            Err(TransactionError::AccountNotEmpty(client_id)) => {
+                assert_eq!(client_id, 2);
+            }
+            _ => {
+                panic!("No error??");
+            }
+        }
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Withdrawal,
+            2,
+            8,
+            Some(5.0),
+        ))?;
+        accounts.handle_transaction(Transaction::new(TransactionKind::Dispute, 2, 3, None))?;
+        assert!(matches!(
+            accounts.handle_transaction(Transaction::new(TransactionKind::Close, 2, 9, None)),
+            Err(TransactionError::AccountNotEmpty(2))
+        ));
+        assert!(accounts.get_client_account(2).is_some());
+
+        // Closed accounts are part of a snapshot
+        let mut restored = Accounts::new();
+        restored.restore(accounts.snapshot());
+        assert!(matches!(
+            restored.handle_transaction(Transaction::new(
+                TransactionKind::Deposit,
+                1,
+                10,
+                Some(1.0)
+            )),
+            Err(TransactionError::AccountClosed(1))
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_accrue_interest() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::new();
+        for transaction in [
+            Transaction::new(TransactionKind::Deposit, 1, 1, Some(100.0)),
+            Transaction::new(TransactionKind::Deposit, 2, 2, Some(10.005)),
+            Transaction::new(TransactionKind::Deposit, 3, 3, Some(50.0)),
+            Transaction::new(TransactionKind::Dispute, 3, 3, None),
+            Transaction::new(TransactionKind::Chargeback, 3, 3, None),
+        ] {
+            accounts.handle_transaction(transaction)?;
+        }
+
+        assert!(accounts.accrue_interest(-0.01).is_err());
+        assert!(accounts.accrue_interest(f64::NAN).is_err());
+
+        // 1% (rounded half to even with 4 decimal places): 100.0 -> 1.0, 10.005 -> 0.1000 (0.10005)
+        accounts.accrue_interest(0.01)?;
+
+        let account: &Account = accounts
+            .get_client_account(1)
+            .ok_or("Cannot client client account")?;
+        assert_eq!(account.available, 101.0);
+        assert_eq!(account.total, 101.0);
+        let account: &Account = accounts
+            .get_client_account(2)
+            .ok_or("Cannot client client account")?;
+        assert_eq!(account.available, 10.005 + 0.1);
+        assert_eq!(account.total, 10.005 + 0.1);
+        // Locked: skipped
+        let account: &Account = accounts
+            .get_client_account(3)
+            .ok_or("Cannot client client account")?;
+        assert_eq!(account.total, 0.0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_transaction_ids() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::new();
+        assert_eq!(accounts.transaction_ids().count(), 0);
+
+        for transaction in [
+            Transaction::new(TransactionKind::Deposit, 1, 1, Some(10.0)),
+            Transaction::new(TransactionKind::Deposit, 2, 5, Some(10.0)),
+            Transaction::new(TransactionKind::Withdrawal, 1, 3, Some(1.0)),
+            Transaction::new(TransactionKind::Dispute, 2, 5, None),
+        ] {
+            accounts.handle_transaction(transaction)?;
+        }
+        // Rejected: not seen
+        assert!(accounts
+            .handle_transaction(Transaction::new(
+                TransactionKind::Withdrawal,
+                1,
+                4,
+                Some(99.0)
+            ))
+            .is_err());
+
+        let mut txs: Vec<u32> = accounts.transaction_ids().collect();
+        txs.sort();
+        assert_eq!(txs, vec![1, 3, 5]);
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_aggregate() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::new();
+        assert_eq!(accounts.aggregate(), Aggregate::default());
+
+        let usd: Currency = "USD".parse()?;
+        for transaction in [
+            Transaction::new(TransactionKind::Deposit, 1, 1, Some(10.0)),
+            Transaction::new(TransactionKind::Withdrawal, 1, 2, Some(2.5)),
+            Transaction::new(TransactionKind::Deposit, 2, 3, Some(20.0)),
+            Transaction::new(TransactionKind::Deposit, 2, 4, Some(5.0)),
+            Transaction::new(TransactionKind::Dispute, 2, 3, None),
+            Transaction::new(TransactionKind::Deposit, 3, 5, Some(1.0)),
+            Transaction::new(TransactionKind::Dispute, 3, 5, None),
+            Transaction::new(TransactionKind::Chargeback, 3, 5, None),
+            Transaction::new(TransactionKind::Deposit, 1, 6, Some(4.0)).with_currency(usd),
+        ] {
+            accounts.handle_transaction(transaction)?;
+        }
+
+        let aggregate = accounts.aggregate();
+        assert_eq!(
+            aggregate,
+            Aggregate {
+                available: 7.5 + 5.0 + 4.0,
+                held: 20.0,
+                total: 7.5 + 25.0 + 4.0,
+                clients: 3,
+                locked_count: 1,
+                orphan_disputes: 0,
+            }
+        );
+        assert_eq!(
+            aggregate.to_string(),
+            "clients: 3, locked: 1, available: 16.5000, held: 20.0000, total: 36.5000, orphan disputes: 0"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_note() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::new();
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Deposit,
+            1,
+            1,
+            Some(10.0),
+        ))?;
+
+        // Even with an (invalid) amount, on a known or an unknown client
+        let outcome = accounts.handle_transaction_with_outcome(Transaction::new(
+            TransactionKind::Note,
+            1,
+            2,
+            Some(-5.0),
+        ))?;
+        assert_eq!(outcome.total_delta, 0.0);
+        assert_eq!(outcome.total, 10.0);
+        accounts.handle_transaction(Transaction::new(TransactionKind::Note, 2, 3, None))?;
+
+        let mut output = Vec::new();
+        accounts.output_as_csv(&mut output)?;
+        assert_eq!(
+            std::str::from_utf8(&output)?,
+            "client,currency,available,held,total,locked,disputed_count\n1,,10.0000,0.0000,10.0000,false,0\n"
+        );
+
+        // Tx id 2 is not used
+        assert_eq!(accounts.transaction_ids().count(), 1);
+        accounts.handle_transaction(Transaction::new(TransactionKind::Deposit, 1, 2, Some(1.0)))?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_require_known_client() -> Result<(), Box<dyn Error>> {
+        let withdrawal = Transaction::new(TransactionKind::Withdrawal, 2, 2, Some(1.0));
+        let dispute = Transaction::new(TransactionKind::Dispute, 2, 9, None);
+
+        // Default: the account is created, then the withdrawal is rejected
+        let mut accounts = Accounts::new();
+        accounts.handle_transaction(Transaction::new(TransactionKind::Deposit, 1, 1, Some(5.0)))?;
+        assert!(matches!(
+            accounts.handle_transaction(withdrawal.clone()),
+            Err(TransactionError::InvalidAmount(_))
+        ));
+        assert!(accounts.get_client_account(2).is_some());
+
+        let mut accounts = Accounts::builder().require_known_client(true).build();
+        accounts.handle_transaction(Transaction::new(TransactionKind::Deposit, 1, 1, Some(5.0)))?;
+        for transaction in [withdrawal, dispute] {
+            match accounts.handle_transaction(transaction) {
+                Err(TransactionError::UnknownClient(client_id)) => {
+                    assert_eq!(client_id, 2);
+                }
+                _ => {
+                    panic!("No error??");
+                }
+            }
+        }
+        assert!(accounts.get_client_account(2).is_none());
+
+        // A deposit creates the account
+        accounts.handle_transaction(Transaction::new(TransactionKind::Deposit, 2, 3, Some(2.0)))?;
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Withdrawal,
+            2,
+            4,
+            Some(1.0),
+        ))?;
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_output_dyn() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::new();
+        accounts.handle_transaction(Transaction::new(TransactionKind::Deposit, 1, 1, Some(1.5)))?;
+
+        let mut expected: Vec<u8> = Vec::new();
+        accounts.output_as_csv(&mut expected)?;
+
+        let mut buffer: Vec<u8> = Vec::new();
+        {
+            let mut output: Box<dyn Write + '_> = Box::new(&mut buffer);
+            accounts.output_as_csv_dyn(&mut output)?;
+        }
+        assert_eq!(buffer, expected);
+
+        Ok(())
+    }
+
+    /// Accounts with client 1 account & a retained deposit (tx 1) of 10.0
+    fn accounts_with_deposit() -> Result<Accounts, Box<dyn Error>> {
+        let mut accounts = Accounts::builder().dispute_withdrawals(true).build();
+        accounts.add_account((1, None));
+        accounts.apply_deposit(
+            Transaction::new(TransactionKind::Deposit, 1, 1, Some(10.0)),
+            (1, None),
+            10.0,
+        )?;
+        Ok(accounts)
+    }
+
+    #[test]
+    fn accounts_apply_deposit() -> Result<(), Box<dyn Error>> {
+        let mut accounts = accounts_with_deposit()?;
+        let account = accounts
+            .get_client_account(1)
+            .ok_or("Cannot client client account")?;
+        assert_eq!((account.available, account.total), (10.0, 10.0));
+        assert!(accounts.get_transaction(1, 1).is_some());
+
+        // tx id already used
+        let deposit = Transaction::new(TransactionKind::Deposit, 1, 1, Some(1.0));
+        match accounts.apply_deposit(deposit, (1, None), 1.0) {
+            Err(TransactionError::InvalidTransaction(tx)) => {
+                assert_eq!(tx, 1);
+            }
+            _ => {
+                panic!("No error??");
+            }
+        }
+
+        // Account must exist (see apply_transaction)
+        let deposit = Transaction::new(TransactionKind::Deposit, 2, 2, Some(1.0));
+        match accounts.apply_deposit(deposit, (2, None), 1.0) {
+            Err(TransactionError::UnknownClient(client_id)) => {
+                assert_eq!(client_id, 2);
+            }
+            _ => {
+                panic!("No error??");
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_apply_withdrawal() -> Result<(), Box<dyn Error>> {
+        let mut accounts = accounts_with_deposit()?;
+
+        let withdrawal = Transaction::new(TransactionKind::Withdrawal, 1, 2, Some(11.0));
+        match accounts.apply_withdrawal(withdrawal, (1, None), 11.0) {
+            Err(TransactionError::InvalidAmount(amount)) => {
+                assert_eq!(amount, 11.0);
+            }
+            _ => {
+                panic!("No error??");
+            }
+        }
+
+        let withdrawal = Transaction::new(TransactionKind::Withdrawal, 1, 2, Some(4.0));
+        accounts.apply_withdrawal(withdrawal, (1, None), 4.0)?;
+        let account = accounts
+            .get_client_account(1)
+            .ok_or("Cannot client client account")?;
+        assert_eq!((account.available, account.total), (6.0, 6.0));
+        assert!(accounts.get_transaction(1, 2).is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_apply_fee() -> Result<(), Box<dyn Error>> {
+        let mut accounts = accounts_with_deposit()?;
+
+        let fee = Transaction::new(TransactionKind::Fee, 1, 2, Some(0.5));
+        accounts.apply_fee(fee, (1, None), 0.5)?;
+        let account = accounts
+            .get_client_account(1)
+            .ok_or("Cannot client client account")?;
+        assert_eq!((account.available, account.total), (9.5, 9.5));
+        // Not retained
+        assert!(accounts.get_transaction(1, 2).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_apply_reversal() -> Result<(), Box<dyn Error>> {
+        let mut accounts = accounts_with_deposit()?;
+
+        let reversal = Transaction::new(TransactionKind::Reversal, 1, 1, None);
+        accounts.apply_reversal(reversal.clone(), (1, None))?;
+        let account = accounts
+            .get_client_account(1)
+            .ok_or("Cannot client client account")?;
+        assert_eq!((account.available, account.total), (0.0, 0.0));
+
+        match accounts.apply_reversal(reversal, (1, None)) {
+            Err(TransactionError::TxReversed(tx)) => {
+                assert_eq!(tx, 1);
+            }
+            _ => {
+                panic!("No error??");
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_apply_dispute_resolve() -> Result<(), Box<dyn Error>> {
+        let mut accounts = accounts_with_deposit()?;
+
+        let resolve = Transaction::new(TransactionKind::Resolve, 1, 1, None);
+        match accounts.apply_resolve(resolve.clone(), (1, None)) {
+            Err(TransactionError::TxNonDisputed(tx)) => {
+                assert_eq!(tx, 1);
+            }
+            _ => {
+                panic!("No error??");
+            }
+        }
+
+        // Partial dispute
+        let dispute = Transaction::new(TransactionKind::Dispute, 1, 1, Some(4.0));
+        accounts.apply_dispute(dispute, (1, None), 4.0)?;
+        let account = accounts
+            .get_client_account(1)
+            .ok_or("Cannot client client account")?;
+        assert_eq!((account.available, account.held), (6.0, 4.0));
+
+        accounts.apply_resolve(resolve, (1, None))?;
+        let account = accounts
+            .get_client_account(1)
+            .ok_or("Cannot client client account")?;
+        assert_eq!((account.available, account.held), (10.0, 0.0));
+        assert!(!accounts.get_transaction(1, 1).ok_or("No tx")?.under_dispute);
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_apply_chargeback() -> Result<(), Box<dyn Error>> {
+        let mut accounts = accounts_with_deposit()?;
+
+        let dispute = Transaction::new(TransactionKind::Dispute, 1, 1, None);
+        accounts.apply_dispute(dispute, (1, None), 0.0)?;
+        let chargeback = Transaction::new(TransactionKind::Chargeback, 1, 1, None);
+        accounts.apply_chargeback(chargeback, (1, None))?;
+
+        let account = accounts
+            .get_client_account(1)
+            .ok_or("Cannot client client account")?;
+        assert_eq!(
+            (account.available, account.held, account.total),
+            (0.0, 0.0, 0.0)
+        );
+        assert!(account.locked);
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_apply_close() -> Result<(), Box<dyn Error>> {
+        let mut accounts = accounts_with_deposit()?;
+
+        let close = Transaction::new(TransactionKind::Close, 1, 2, None);
+        match accounts.apply_close(close.clone(), (1, None)) {
+            Err(TransactionError::AccountNotEmpty(client_id)) => {
+                assert_eq!(client_id, 1);
+            }
+            _ => {
+                panic!("No error??");
+            }
+        }
+
+        let withdrawal = Transaction::new(TransactionKind::Withdrawal, 1, 3, Some(10.0));
+        accounts.apply_withdrawal(withdrawal, (1, None), 10.0)?;
+        accounts.apply_close(close, (1, None))?;
+        assert!(accounts.get_client_account(1).is_none());
+        assert!(accounts.closed.contains(&(1, None)));
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_min_amount() -> Result<(), Box<dyn Error>> {
+        let client_id = 1;
+        let mut accounts = Accounts::builder().min_amount(Some(0.01)).build();
+
+        // At the minimum: accepted
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Deposit,
+            client_id,
+            1,
+            Some(0.01),
+        ))?;
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Deposit,
+            client_id,
+            2,
+            Some(1.0),
+        ))?;
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Withdrawal,
+            client_id,
+            3,
+            Some(0.01),
+        ))?;
+
+        // Below the minimum (a valid precision though)
+        for (kind, tx) in [
+            (TransactionKind::Deposit, 4),
+            (TransactionKind::Withdrawal, 5),
+        ] {
+            match accounts.handle_transaction(Transaction::new(kind, client_id, tx, Some(0.001))) {
+                Err(TransactionError::BelowMinimum(amount)) => {
+                    assert_eq!(amount, 0.001);
+                }
+                _ => {
+                    panic!("No error??");
+                }
+            }
+        }
 
-        match accounts.handle_transaction(transaction0) {
-            Err(TransactionError::InvalidAmount(a)) => {
-                assert_eq!(a, deposit_amount0);
+        // Zero amount: still rejected as such
+        match accounts.handle_transaction(Transaction::new(
+            TransactionKind::Deposit,
+            client_id,
+            6,
+            Some(0.0),
+        )) {
+            Err(TransactionError::ZeroAmount(tx)) => {
+                assert_eq!(tx, 6);
             }
             _ => {
-                panic!("Not an error?");
+                panic!("No error??");
             }
         }
 
-        match accounts.handle_transaction(transaction0_1) {
-            Err(TransactionError::InvalidAmount(a)) => {
-                assert!(a.is_nan());
+        let account: &Account = accounts
+            .get_client_account(client_id)
+            .ok_or("Cannot client client account")?;
+        assert_eq!(account.available, 1.0);
+        assert_eq!(account.total, 1.0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_auto_dispute_on_chargeback() -> Result<(), Box<dyn Error>> {
+        let client_id = 1;
+        let deposit = Transaction::new(TransactionKind::Deposit, client_id, 1, Some(10.0));
+        let chargeback = Transaction::new(TransactionKind::Chargeback, client_id, 1, None);
+
+        // Default: a dispute is required
+        let mut accounts = Accounts::new();
+        accounts.handle_transaction(deposit.clone())?;
+        match accounts.handle_transaction(chargeback.clone()) {
+            Err(TransactionError::TxNonDisputed(tx)) => {
+                assert_eq!(tx, 1);
             }
             _ => {
-                panic!("Not an error?");
+                panic!("No error??");
             }
         }
+        let account: &Account = accounts
+            .get_client_account(client_id)
+            .ok_or("Cannot client client account")?;
+        assert_eq!((account.available, account.total), (10.0, 10.0));
+        assert!(!account.locked);
 
+        // Implicit dispute
+        let mut accounts = Accounts::builder().auto_dispute_on_chargeback(true).build();
+        accounts.handle_transaction(deposit.clone())?;
+        accounts.handle_transaction(chargeback.clone())?;
         let account: &Account = accounts
             .get_client_account(client_id)
             .ok_or("Cannot client client account")?;
+        assert_eq!(
+            (account.available, account.held, account.total),
+            (0.0, 0.0, 0.0)
+        );
+        assert!(account.locked);
 
-        assert_eq!(account.available, 0.0);
-        assert_eq!(account.total, 0.0);
-        assert_eq!(account.held, 0.0);
+        // Failed chargeback (total would be negative): the implicit dispute is rolled back
+        let mut accounts = Accounts::builder().auto_dispute_on_chargeback(true).build();
+        accounts.handle_transaction(deposit)?;
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Deposit,
+            client_id,
+            2,
+            Some(5.0),
+        ))?;
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Withdrawal,
+            client_id,
+            3,
+            Some(14.0),
+        ))?;
+        match accounts.handle_transaction(chargeback) {
+            Err(TransactionError::NegativeTotal(tx)) => {
+                assert_eq!(tx, 1);
+            }
+            _ => {
+                panic!("No error??");
+            }
+        }
+        let account: &Account = accounts
+            .get_client_account(client_id)
+            .ok_or("Cannot client client account")?;
+        assert_eq!(
+            (account.available, account.held, account.total),
+            (1.0, 0.0, 1.0)
+        );
+        assert!(!account.locked);
+        assert!(
+            !accounts
+                .get_transaction(client_id, 1)
+                .ok_or("No tx")?
+                .under_dispute
+        );
 
-        accounts.handle_transaction(transaction1)?;
+        Ok(())
+    }
 
-        match accounts.handle_transaction(transaction2) {
-            Err(TransactionError::AccountAmountTooLarge) => {}
+    /// Logger capturing warnings of the current thread (see [captured_warnings])
+    struct CaptureLogger;
+
+    thread_local! {
+        static WARNINGS: std::cell::RefCell<Vec<String>> = const { std::cell::RefCell::new(Vec::new()) };
+    }
+
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, metadata: &log::Metadata) -> bool {
+            metadata.level() <= log::Level::Warn
+        }
+
+        fn log(&self, record: &log::Record) {
+            if self.enabled(record.metadata()) {
+                WARNINGS.with(|w| w.borrow_mut().push(record.args().to_string()));
+            }
+        }
+
+        fn flush(&self) {}
+    }
+
+    /// Install [CaptureLogger] (once) & take warnings logged so far by the current thread
+    fn captured_warnings() -> Vec<String> {
+        static INIT: std::sync::Once = std::sync::Once::new();
+        INIT.call_once(|| {
+            log::set_logger(&CaptureLogger).expect("Cannot set logger");
+            log::set_max_level(log::LevelFilter::Warn);
+        });
+        WARNINGS.with(|w| w.take())
+    }
+
+    #[test]
+    fn accounts_orphan_disputes() -> Result<(), Box<dyn Error>> {
+        captured_warnings();
+        let mut accounts = Accounts::new();
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Deposit,
+            1,
+            1,
+            Some(10.0),
+        ))?;
+        accounts.handle_transaction(Transaction::new(TransactionKind::Dispute, 1, 1, None))?;
+        assert_eq!(accounts.orphan_disputes(), 0);
+        assert!(captured_warnings().is_empty());
+
+        // Client 2 never deposited: its dispute (of an unknown tx) is rejected but reported
+        assert!(accounts
+            .handle_transaction(Transaction::new(TransactionKind::Dispute, 2, 2, None))
+            .is_err());
+        assert_eq!(accounts.orphan_disputes(), 1);
+        let warnings = captured_warnings();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("client without account (client id: 2)"));
+
+        // Its (auto created) account is known now
+        assert!(accounts
+            .handle_transaction(Transaction::new(TransactionKind::Resolve, 2, 2, None))
+            .is_err());
+        assert_eq!(accounts.orphan_disputes(), 1);
+        assert_eq!(accounts.aggregate().orphan_disputes, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_output_quote_style() -> Result<(), Box<dyn Error>> {
+        let output_with = |quote_style: QuoteStyle| -> Result<String, Box<dyn Error>> {
+            let mut accounts = Accounts::builder().quote_style(quote_style).build();
+            accounts.handle_transaction(Transaction::new(
+                TransactionKind::Deposit,
+                1,
+                1,
+                Some(1.5),
+            ))?;
+            let mut output: Vec<u8> = Vec::new();
+            accounts.output_as_csv(&mut output)?;
+            Ok(String::from_utf8(output)?)
+        };
+
+        let necessary = output_with(QuoteStyle::default())?;
+        assert_eq!(
+            necessary,
+            "client,currency,available,held,total,locked,disputed_count\n\
+             1,,1.5000,0.0000,1.5000,false,0\n"
+        );
+        let always = output_with(QuoteStyle::Always)?;
+        assert_eq!(
+            always,
+            "\"client\",\"currency\",\"available\",\"held\",\"total\",\"locked\",\"disputed_count\"\n\
+             \"1\",\"\",\"1.5000\",\"0.0000\",\"1.5000\",\"false\",\"0\"\n"
+        );
+        // Same fields once unquoted
+        assert_eq!(always.replace('"', ""), necessary);
+        assert_eq!(output_with(QuoteStyle::Never)?, necessary);
+
+        assert_eq!("always".parse::<QuoteStyle>(), Ok(QuoteStyle::Always));
+        assert!("minimal".parse::<QuoteStyle>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_output_minor_units() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::builder().minor_units(true).build();
+        accounts.apply_all([
+            Transaction::new(TransactionKind::Deposit, 1, 1, Some(25.11)),
+            Transaction::new(TransactionKind::Deposit, 1, 2, Some(0.5)),
+            Transaction::new(TransactionKind::Dispute, 1, 2, None),
+        ])?;
+
+        let mut output: Vec<u8> = Vec::new();
+        accounts.output_as_csv(&mut output)?;
+        assert_eq!(
+            std::str::from_utf8(&output)?,
+            format!("{}\n1,,251100,5000,256100,false,1\n", ACCOUNT_CSV_HEADER)
+        );
+
+        accounts.set_scale(2)?;
+        let mut output: Vec<u8> = Vec::new();
+        accounts.output_as_csv(&mut output)?;
+        assert!(std::str::from_utf8(&output)?.ends_with("\n1,,2511,50,2561,false,1\n"));
+
+        assert_eq!(to_minor_units("0.0000"), "0");
+        assert_eq!(to_minor_units("-0.0000"), "0");
+        assert_eq!(to_minor_units("-1.2300"), "-12300");
+        assert_eq!(to_minor_units("100"), "100");
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_snapshot_unique_accounts() -> Result<(), Box<dyn Error>> {
+        let header = "record,client,currency,tx,type,amount,timestamp,available,held,total,locked,under_dispute,disputed_amount,reversed,limit\n";
+
+        // Same client, distinct currencies
+        let state = format!(
+            "{}account,1,,,,,,1.0,0.0,1.0,false,,,,\naccount,1,USD,,,,,2.0,0.0,2.0,false,,,,\n",
+            header
+        );
+        assert_eq!(
+            AccountsSnapshot::read_csv(state.as_bytes())?.accounts.len(),
+            2
+        );
+
+        let state = format!(
+            "{}account,1,,,,,,1.0,0.0,1.0,false,,,,\naccount,2,,,,,,1.0,0.0,1.0,false,,,,\naccount,1,,,,,,2.0,0.0,2.0,false,,,,\n",
+            header
+        );
+        match AccountsSnapshot::read_csv(state.as_bytes()) {
+            Err(e) => {
+                assert!(
+                    matches!(e.kind(), csv::ErrorKind::Io(e) if e.kind() == io::ErrorKind::InvalidData)
+                );
+                assert!(e
+                    .to_string()
+                    .contains("state: duplicate account (client id: 1"));
+            }
             _ => {
-                panic!("Not an error?");
+                panic!("No error??");
             }
         }
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_max_open_disputes() -> Result<(), Box<dyn Error>> {
+        let client_id = 1;
+        let mut accounts = Accounts::builder().max_open_disputes(Some(2)).build();
+        for tx in 1..=4 {
+            accounts.handle_transaction(Transaction::new(
+                TransactionKind::Deposit,
+                client_id,
+                tx,
+                Some(1.0),
+            ))?;
+        }
+        // Another client has its own count
+        accounts.handle_transaction(Transaction::new(TransactionKind::Deposit, 2, 5, Some(1.0)))?;
+
+        // Under the cap
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Dispute,
+            client_id,
+            1,
+            None,
+        ))?;
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Dispute,
+            client_id,
+            2,
+            None,
+        ))?;
+        accounts.handle_transaction(Transaction::new(TransactionKind::Dispute, 2, 5, None))?;
 
+        // Over the cap
+        match accounts.handle_transaction(Transaction::new(
+            TransactionKind::Dispute,
+            client_id,
+            3,
+            None,
+        )) {
+            Err(TransactionError::TooManyDisputes(c)) => {
+                assert_eq!(c, client_id);
+            }
+            _ => {
+                panic!("No error??");
+            }
+        }
         let account: &Account = accounts
             .get_client_account(client_id)
             .ok_or("Cannot client client account")?;
+        assert_eq!((account.available, account.held), (2.0, 2.0));
 
-        println!("account: {:?}", account);
+        // A resolved dispute is no longer open
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Resolve,
+            client_id,
+            1,
+            None,
+        ))?;
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Dispute,
+            client_id,
+            3,
+            None,
+        ))?;
+        assert_eq!(accounts.open_disputes.get(&client_id), Some(&2));
 
-        assert_eq!(account.available, deposit_amount1);
-        assert_eq!(account.total, deposit_amount1);
+        // Counters are rebuilt on restore
+        let snapshot = accounts.snapshot();
+        let mut restored = Accounts::builder().max_open_disputes(Some(2)).build();
+        restored.restore(snapshot);
+        assert_eq!(restored.open_disputes, accounts.open_disputes);
+
+        // Without cap
+        let mut accounts = Accounts::new();
+        for tx in 1..=3 {
+            accounts.handle_transaction(Transaction::new(
+                TransactionKind::Deposit,
+                client_id,
+                tx,
+                Some(1.0),
+            ))?;
+            accounts.handle_transaction(Transaction::new(
+                TransactionKind::Dispute,
+                client_id,
+                tx,
+                None,
+            ))?;
+        }
+        assert_eq!(accounts.open_disputes.get(&client_id), Some(&3));
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_snapshot_csv() -> Result<(), Box<dyn Error>> {
+        let usd: Currency = "USD".parse()?;
+        let mut accounts = Accounts::builder().max_retained_tx(Some(10)).build();
+        accounts.set_overdraft(3, 5.0);
+        accounts.apply_all([
+            Transaction::new(TransactionKind::Deposit, 1, 1, Some(1.0 / 3.0)).with_timestamp(7),
+            Transaction::new(TransactionKind::Deposit, 2, 2, Some(2.5)).with_currency(usd),
+            Transaction::new(TransactionKind::Dispute, 2, 2, Some(0.5)),
+            Transaction::new(TransactionKind::Deposit, 3, 3, Some(1.0)),
+            Transaction::new(TransactionKind::Withdrawal, 3, 4, Some(1.0)),
+            Transaction::new(TransactionKind::Close, 3, 5, None),
+        ])?;
+        let snapshot = accounts.snapshot();
+
+        let mut state: Vec<u8> = Vec::new();
+        snapshot.write_csv(&mut state)?;
+        assert_eq!(AccountsSnapshot::read_csv(state.as_slice())?, snapshot);
+
+        // Empty state
+        let empty = Accounts::new().snapshot();
+        let mut state: Vec<u8> = Vec::new();
+        empty.write_csv(&mut state)?;
+        assert!(state.is_empty());
+        assert_eq!(AccountsSnapshot::read_csv(state.as_slice())?, empty);
+
+        // Invalid state
+        let state = "record,client,currency,tx,type,amount,timestamp,available,held,total,locked,under_dispute,disputed_amount,reversed,limit\n\
+                     account,1,,,,,,1.0,,1.0,false,,,,\n";
+        match AccountsSnapshot::read_csv(state.as_bytes()) {
+            Err(e) => {
+                assert!(e.to_string().contains("missing held in Account row"));
+            }
+            _ => {
+                panic!("No error??");
+            }
+        }
+        Ok(())
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn accounts_random_invariants(transactions in strategies::transactions(1_000, 8)) {
+            let mut accounts = Accounts::new();
+            for transaction in transactions {
+                let client = transaction.client;
+                let kind = transaction.kind;
+                let before = accounts.get_client_account(client).copied();
+                let result = accounts.handle_transaction(transaction);
+                let after = match accounts.get_client_account(client) {
+                    Some(after) => *after,
+                    // e.g. a dispute of another client's tx as first transaction of a client
+                    None => {
+                        prop_assert!(result.is_err());
+                        continue;
+                    }
+                };
+
+                // Balances are consistent
+                prop_assert_eq!(after.total, after.available + after.held, "{:?}", after);
+                prop_assert!(after.held >= 0.0, "{:?}", after);
+
+                match (result, before) {
+                    // A rejected transaction leaves the account unchanged
+                    (Err(_), Some(before)) => {
+                        prop_assert_eq!(after, before);
+                    }
+                    // Dispute / resolve only move funds between available & held
+                    (Ok(()), Some(before))
+                        if matches!(kind, TransactionKind::Dispute | TransactionKind::Resolve) =>
+                    {
+                        prop_assert_eq!(after.total, before.total, "{:?}", kind);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn accounts_chargeback_on_locked() -> Result<(), Box<dyn Error>> {
+        let transactions = [
+            Transaction::new(TransactionKind::Deposit, 1, 1, Some(10.0)),
+            Transaction::new(TransactionKind::Deposit, 1, 2, Some(5.0)),
+            Transaction::new(TransactionKind::Dispute, 1, 1, None),
+            Transaction::new(TransactionKind::Dispute, 1, 2, None),
+            Transaction::new(TransactionKind::Chargeback, 1, 1, None),
+        ];
+
+        // Default: the second chargeback is rejected, its funds stay held
+        let mut accounts = Accounts::new();
+        accounts.apply_all(transactions.clone())?;
+        match accounts.handle_transaction(Transaction::new(TransactionKind::Chargeback, 1, 2, None))
+        {
+            Err(TransactionError::AccountLocked(client_id)) => {
+                assert_eq!(client_id, 1);
+            }
+            _ => {
+                panic!("No error??");
+            }
+        }
+        let account = accounts
+            .get_client_account(1)
+            .ok_or("Cannot client client account")?;
+        assert_eq!(account.held, 5.0);
+        assert_eq!(account.total, 5.0);
+        assert!(account.locked);
+
+        // Allowed: both chargebacks apply
+        let mut accounts = Accounts::builder().chargeback_on_locked(true).build();
+        accounts.apply_all(transactions)?;
+        accounts.handle_transaction(Transaction::new(TransactionKind::Chargeback, 1, 2, None))?;
+        let account = accounts
+            .get_client_account(1)
+            .ok_or("Cannot client client account")?;
+        assert_eq!(account.available, 0.0);
         assert_eq!(account.held, 0.0);
-        assert!(account.available.is_normal());
-        assert!(account.total.is_normal());
+        assert_eq!(account.total, 0.0);
+        assert!(account.locked);
+
+        // ... but not of a non disputed tx, even with auto_dispute_on_chargeback
+        let mut accounts = Accounts::builder()
+            .chargeback_on_locked(true)
+            .auto_dispute_on_chargeback(true)
+            .build();
+        accounts.apply_all([
+            Transaction::new(TransactionKind::Deposit, 1, 1, Some(10.0)),
+            Transaction::new(TransactionKind::Deposit, 1, 2, Some(5.0)),
+            Transaction::new(TransactionKind::Chargeback, 1, 1, None),
+        ])?;
+        assert!(matches!(
+            accounts.handle_transaction(Transaction::new(TransactionKind::Chargeback, 1, 2, None)),
+            Err(TransactionError::AccountLocked(1))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_output_no_phantom_client() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::builder().require_known_client(true).build();
+        accounts.handle_transaction(Transaction::new(TransactionKind::Deposit, 1, 1, Some(2.0)))?;
+        assert!(accounts
+            .handle_transaction(Transaction::new(TransactionKind::Dispute, 7, 1, None))
+            .is_err());
+
+        let mut output: Vec<u8> = Vec::new();
+        accounts.output_as_csv(&mut output)?;
+        assert_eq!(
+            String::from_utf8(output)?,
+            format!("{}\n1,,2.0000,0.0000,2.0000,false,0\n", ACCOUNT_CSV_HEADER)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_freeze_on_chargeback() -> Result<(), Box<dyn Error>> {
+        let usd: Currency = "USD".parse()?;
+        let transactions = [
+            Transaction::new(TransactionKind::Deposit, 1, 1, Some(10.0)),
+            Transaction::new(TransactionKind::Deposit, 1, 2, Some(5.0)),
+            Transaction::new(TransactionKind::Deposit, 1, 3, Some(5.0)).with_currency(usd),
+            Transaction::new(TransactionKind::Dispute, 1, 1, None),
+            Transaction::new(TransactionKind::Chargeback, 1, 1, None),
+        ];
+
+        // Default: only the charged back account is locked
+        let mut accounts = Accounts::builder().chargeback_on_locked(true).build();
+        accounts.apply_all(transactions.clone())?;
+        accounts.handle_transaction(Transaction::new(TransactionKind::Dispute, 1, 3, None))?;
+
+        let mut accounts = Accounts::builder()
+            .chargeback_on_locked(true)
+            .freeze_on_chargeback(true)
+            .build();
+        accounts.apply_all(transactions)?;
+        for transaction in [
+            Transaction::new(TransactionKind::Dispute, 1, 2, None),
+            Transaction::new(TransactionKind::Dispute, 1, 3, None),
+            Transaction::new(TransactionKind::Deposit, 1, 4, Some(1.0)).with_currency(usd),
+        ] {
+            match accounts.handle_transaction(transaction) {
+                Err(TransactionError::AccountLocked(client_id)) => {
+                    assert_eq!(client_id, 1);
+                }
+                _ => {
+                    panic!("No error??");
+                }
+            }
+        }
+        // Other clients are not affected
+        accounts.handle_transaction(Transaction::new(TransactionKind::Deposit, 2, 5, Some(1.0)))?;
+
+        // Still frozen after a restore
+        let mut restored = Accounts::builder().freeze_on_chargeback(true).build();
+        restored.restore(accounts.snapshot());
+        assert!(matches!(
+            restored.handle_transaction(Transaction::new(TransactionKind::Dispute, 1, 3, None)),
+            Err(TransactionError::AccountLocked(1))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_tx_id_scope() -> Result<(), Box<dyn Error>> {
+        let file_1 = [
+            Transaction::new(TransactionKind::Deposit, 1, 1, Some(10.0)),
+            Transaction::new(TransactionKind::Deposit, 1, 2, Some(5.0)),
+            Transaction::new(TransactionKind::Dispute, 1, 2, None),
+        ];
+
+        // Global: a tx id of a previous file is rejected (or skipped if replayed)
+        let mut accounts = Accounts::new();
+        accounts.start_file();
+        accounts.apply_all(file_1.clone())?;
+        accounts.start_file();
+        assert!(matches!(
+            accounts.handle_transaction(Transaction::new(
+                TransactionKind::Deposit,
+                1,
+                1,
+                Some(3.0)
+            )),
+            Err(TransactionError::InvalidTransaction(1))
+        ));
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Deposit,
+            1,
+            1,
+            Some(10.0),
+        ))?;
+        let account = accounts
+            .get_client_account(1)
+            .ok_or("Cannot client client account")?;
+        assert_eq!(account.total, 15.0);
+
+        // File: a tx id of a previous file can be reused (once per file)
+        let mut accounts = Accounts::builder()
+            .tx_id_scope(TxIdScope::File)
+            .max_retained_tx(Some(10))
+            .build();
+        accounts.start_file();
+        accounts.apply_all(file_1)?;
+        accounts.start_file();
+        accounts.apply_all([
+            Transaction::new(TransactionKind::Deposit, 1, 1, Some(10.0)),
+            Transaction::new(TransactionKind::Dispute, 1, 1, None),
+        ])?;
+        let account = accounts
+            .get_client_account(1)
+            .ok_or("Cannot client client account")?;
+        assert_eq!(account.available, 10.0);
+        assert_eq!(account.held, 15.0);
+        assert_eq!(account.total, 25.0);
+        assert_eq!(accounts.tx_order.len(), 2);
+
+        for tx in [1, 2] {
+            // Used in this file / under dispute
+            match accounts.handle_transaction(Transaction::new(
+                TransactionKind::Deposit,
+                1,
+                tx,
+                Some(3.0),
+            )) {
+                Err(TransactionError::InvalidTransaction(tx_)) => {
+                    assert_eq!(tx_, tx);
+                }
+                _ => {
+                    panic!("No error??");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn accounts_dispute_non_monetary_tx() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::new();
+        accounts.handle_transaction(Transaction::new(TransactionKind::Deposit, 1, 1, Some(5.0)))?;
+
+        // First seen in a resolve: unknown
+        assert!(matches!(
+            accounts.handle_transaction(Transaction::new(TransactionKind::Resolve, 1, 7, None)),
+            Err(TransactionError::UnknownTransaction(7))
+        ));
+        // Then disputed: seen but not disputable
+        match accounts.handle_transaction(Transaction::new(TransactionKind::Dispute, 1, 7, None)) {
+            Err(TransactionError::NonDisputableKind(tx)) => {
+                assert_eq!(tx, 7);
+            }
+            _ => {
+                panic!("No error??");
+            }
+        }
+        // Never seen
+        assert!(matches!(
+            accounts.handle_transaction(Transaction::new(TransactionKind::Dispute, 1, 8, None)),
+            Err(TransactionError::UnknownTransaction(8))
+        ));
+
+        // Tx id of a fee: first reference is not disputable already
+        accounts.handle_transaction(Transaction::new(TransactionKind::Fee, 1, 9, Some(1.0)))?;
+        for kind in [
+            TransactionKind::Dispute,
+            TransactionKind::Resolve,
+            TransactionKind::Chargeback,
+        ] {
+            match accounts.handle_transaction(Transaction::new(kind, 1, 9, None)) {
+                Err(TransactionError::NonDisputableKind(tx)) => {
+                    assert_eq!(tx, 9);
+                }
+                _ => {
+                    panic!("No error??");
+                }
+            }
+        }
+        assert_eq!(
+            accounts.non_monetary_tx.get(&accounts.tx_key(1, 9)),
+            Some(&(1, TransactionKind::Fee))
+        );
+
+        // A deposit can still use this tx id, then be disputed
+        accounts.handle_transaction(Transaction::new(TransactionKind::Deposit, 1, 7, Some(2.0)))?;
+        accounts.handle_transaction(Transaction::new(TransactionKind::Dispute, 1, 7, None))?;
+        Ok(())
+    }
 
+    #[test]
+    fn accounts_output_crlf() -> Result<(), Box<dyn Error>> {
+        let transactions = [
+            Transaction::new(TransactionKind::Deposit, 1, 1, Some(1.5)),
+            Transaction::new(TransactionKind::Deposit, 2, 2, Some(2.0)),
+        ];
+
+        let mut accounts = Accounts::builder().crlf(true).build();
+        accounts.apply_all(transactions.clone())?;
+        assert_eq!(accounts.line_ending(), "\r\n");
+        let mut output: Vec<u8> = Vec::new();
+        accounts.output_as_csv(&mut output)?;
+        let output = String::from_utf8(output)?;
+        assert_eq!(output.matches("\r\n").count(), 3);
+        assert_eq!(output.matches('\n').count(), 3);
+        assert!(output.starts_with(&format!("{}\r\n", ACCOUNT_CSV_HEADER)));
+        assert!(output.ends_with(",false,0\r\n"));
+
+        // Default: LF
+        let mut accounts = Accounts::new();
+        accounts.apply_all(transactions)?;
+        assert_eq!(accounts.line_ending(), "\n");
+        let mut output: Vec<u8> = Vec::new();
+        accounts.output_as_csv(&mut output)?;
+        assert!(!String::from_utf8(output)?.contains('\r'));
         Ok(())
     }
 
     #[test]
-    fn accounts_valid_withdrawal() -> Result<(), Box<dyn Error>> {
-        let mut accounts = Accounts::new();
-
-        let client_id = 1;
-        let deposit_amount = 25.11;
-        let transaction =
-            Transaction::new(TransactionKind::Deposit, client_id, 1, Some(deposit_amount));
+    fn accounts_output_metadata_columns() -> Result<(), Box<dyn Error>> {
+        let csv = "type,client,tx,amount,region\n\
+                   deposit,1,1,1.0,EU\n\
+                   deposit,1,2,1.0,US\n\
+                   deposit,2,3,2.0,\n\
+                   deposit,3,4,3.0,\"Asia, Pacific\"\n";
+        let format = crate::csv_reader::CsvFormat {
+            extra_columns: vec!["region".to_string()],
+            ..crate::csv_reader::CsvFormat::default()
+        };
+        let mut accounts = Accounts::builder()
+            .metadata_columns(vec!["region".to_string(), "segment".to_string()])
+            .build();
+        for transaction in CsvReader::from_reader_with_format(csv.as_bytes(), format)? {
+            accounts.handle_transaction(transaction?)?;
+        }
 
-        let withdraw_amount = 25.0;
-        let transaction1 = Transaction::new(
-            TransactionKind::Withdrawal,
-            client_id,
-            2,
-            Some(withdraw_amount),
+        let mut output: Vec<u8> = Vec::new();
+        accounts.output_as_csv(&mut output)?;
+        let output = String::from_utf8(output)?;
+        let mut lines: Vec<&str> = output.lines().collect();
+        lines.sort();
+        assert_eq!(
+            lines,
+            vec![
+                "1,,2.0000,0.0000,2.0000,false,0,EU,",
+                "2,,2.0000,0.0000,2.0000,false,0,,",
+                "3,,3.0000,0.0000,3.0000,false,0,\"Asia, Pacific\",",
+                &format!("{},region,segment", ACCOUNT_CSV_HEADER),
+            ]
         );
+        Ok(())
+    }
 
-        accounts.handle_transaction(transaction)?;
-        accounts.handle_transaction(transaction1)?;
-
-        let account: &Account = accounts
-            .get_client_account(client_id)
+    #[test]
+    fn accounts_idempotent() -> Result<(), Box<dyn Error>> {
+        let transactions = [
+            Transaction::new(TransactionKind::Deposit, 1, 1, Some(10.0)),
+            Transaction::new(TransactionKind::Deposit, 1, 2, Some(5.0)),
+            Transaction::new(TransactionKind::Dispute, 1, 1, None),
+            Transaction::new(TransactionKind::Resolve, 1, 1, None),
+            Transaction::new(TransactionKind::Dispute, 1, 2, None),
+            Transaction::new(TransactionKind::Chargeback, 1, 2, None),
+        ];
+        let resolve = Transaction::new(TransactionKind::Resolve, 1, 1, None);
+        let chargeback = Transaction::new(TransactionKind::Chargeback, 1, 2, None);
+
+        // Default: duplicates are rejected
+        let mut accounts = Accounts::new();
+        accounts.apply_all(transactions.clone())?;
+        assert!(matches!(
+            accounts.handle_transaction(resolve.clone()),
+            Err(TransactionError::AccountLocked(1))
+        ));
+        assert!(matches!(
+            accounts.handle_transaction(chargeback.clone()),
+            Err(TransactionError::AccountLocked(1))
+        ));
+
+        // Idempotent: duplicates are skipped
+        let mut accounts = Accounts::builder().idempotent(true).build();
+        accounts.apply_all(transactions[..4].iter().cloned())?;
+        let outcome = accounts.handle_transaction_with_outcome(resolve.clone())?;
+        assert!(outcome.replay);
+        accounts.apply_all(transactions[4..].iter().cloned())?;
+        accounts.handle_transaction(resolve)?;
+        accounts.handle_transaction(chargeback.clone())?;
+        accounts.handle_transaction(chargeback)?;
+        let account = accounts
+            .get_client_account(1)
             .ok_or("Cannot client client account")?;
+        assert_eq!(account.available, 10.0);
+        assert_eq!(account.held, 0.0);
+        assert_eq!(account.total, 10.0);
+        assert!(account.locked);
+
+        // ... but not a different final state
+        match accounts.handle_transaction(Transaction::new(TransactionKind::Chargeback, 1, 1, None))
+        {
+            Err(TransactionError::AccountLocked(client_id)) => {
+                assert_eq!(client_id, 1);
+            }
+            _ => {
+                panic!("No error??");
+            }
+        }
 
-        let left_amount = deposit_amount - withdraw_amount;
+        let mut accounts = Accounts::builder().idempotent(true).build();
+        accounts.apply_all(transactions[..4].iter().cloned())?;
+        match accounts.handle_transaction(Transaction::new(TransactionKind::Chargeback, 1, 1, None))
+        {
+            Err(TransactionError::TxNonDisputed(tx)) => {
+                assert_eq!(tx, 1);
+            }
+            _ => {
+                panic!("No error??");
+            }
+        }
+        Ok(())
+    }
 
-        assert_eq!(account.available, left_amount);
-        assert_eq!(account.total, left_amount);
-        assert_eq!(account.held, 0.0);
+    #[test]
+    fn accounts_output_suppress_empty() -> Result<(), Box<dyn Error>> {
+        let transactions = [
+            Transaction::new(TransactionKind::Deposit, 1, 1, Some(2.0)),
+            // Phantom client 2 (rejected withdrawal)
+            Transaction::new(TransactionKind::Withdrawal, 2, 2, Some(1.0)),
+            // Client 3 back to zero
+            Transaction::new(TransactionKind::Deposit, 3, 3, Some(1.0)),
+            Transaction::new(TransactionKind::Withdrawal, 3, 4, Some(1.0)),
+        ];
+
+        let mut accounts = Accounts::builder().suppress_empty(true).build();
+        for transaction in transactions.clone() {
+            let _ = accounts.handle_transaction(transaction);
+        }
+        let mut output: Vec<u8> = Vec::new();
+        accounts.output_as_csv(&mut output)?;
+        assert_eq!(
+            String::from_utf8(output)?,
+            format!("{}\n1,,2.0000,0.0000,2.0000,false,0\n", ACCOUNT_CSV_HEADER)
+        );
+
+        // Default: written
+        let mut accounts = Accounts::new();
+        for transaction in transactions {
+            let _ = accounts.handle_transaction(transaction);
+        }
+        let mut output: Vec<u8> = Vec::new();
+        accounts.output_as_csv(&mut output)?;
+        assert_eq!(String::from_utf8(output)?.lines().count(), 4);
         Ok(())
     }
 
     #[test]
-    fn accounts_withdraw_too_much() -> Result<(), Box<dyn Error>> {
+    fn accounts_dispute_twice() -> Result<(), Box<dyn Error>> {
         let mut accounts = Accounts::new();
 
         let client_id = 1;
-        let deposit_amount = 25.11;
-        let transaction =
-            Transaction::new(TransactionKind::Deposit, client_id, 1, Some(deposit_amount));
-
-        let withdraw_amount = 42.0;
-        let transaction1 = Transaction::new(
-            TransactionKind::Withdrawal,
+        let tx = 1;
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Deposit,
             client_id,
-            2,
-            Some(withdraw_amount),
-        );
+            tx,
+            Some(10.0),
+        ))?;
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Dispute,
+            client_id,
+            tx,
+            None,
+        ))?;
 
-        accounts.handle_transaction(transaction)?;
-        match accounts.handle_transaction(transaction1) {
-            Err(TransactionError::InvalidAmount(a)) => {
-                assert_eq!(a, withdraw_amount);
+        // A second dispute of the same tx is rejected (nothing is held again)
+        match accounts.handle_transaction(Transaction::new(
+            TransactionKind::Dispute,
+            client_id,
+            tx,
+            None,
+        )) {
+            Err(TransactionError::TxAlreadyDisputed(t)) => {
+                assert_eq!(t, tx);
             }
             _ => {
                 panic!("No error??");
             }
-        };
+        }
 
-        let account: &Account = accounts
+        let account = accounts
             .get_client_account(client_id)
             .ok_or("Cannot client client account")?;
+        assert_eq!(account.available, 0.0);
+        assert_eq!(account.held, 10.0);
+
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Resolve,
+            client_id,
+            tx,
+            None,
+        ))?;
+
+        let mut output: Vec<u8> = Vec::new();
+        accounts.output_as_csv(&mut output)?;
+        assert_eq!(
+            std::str::from_utf8(&output)?,
+            "client,currency,available,held,total,locked,disputed_count\n1,,10.0000,0.0000,10.0000,false,0\n"
+        );
 
-        assert_eq!(account.available, deposit_amount);
-        assert_eq!(account.total, deposit_amount);
-        assert_eq!(account.held, 0.0);
         Ok(())
     }
 
     #[test]
-    fn accounts_dispute_then_resolve() -> Result<(), Box<dyn Error>> {
+    fn accounts_partial_dispute_twice() -> Result<(), Box<dyn Error>> {
         let mut accounts = Accounts::new();
 
         let client_id = 1;
-        let deposit_amount = 25.11;
-        let transaction1 =
-            Transaction::new(TransactionKind::Deposit, client_id, 1, Some(deposit_amount));
-
-        let transaction2 = Transaction::new(TransactionKind::Dispute, client_id, 1, None);
-
-        accounts.handle_transaction(transaction1)?;
-        accounts.handle_transaction(transaction2)?;
-
-        let account: &Account = accounts
-            .get_client_account(client_id)
-            .ok_or("Cannot client client account")?;
-
-        assert_eq!(account.available, 0.0);
-        assert_eq!(account.total, deposit_amount);
-        assert_eq!(account.held, deposit_amount);
-
-        // Now Resolve
+        let tx = 1;
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Deposit,
+            client_id,
+            tx,
+            Some(10.0),
+        ))?;
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Dispute,
+            client_id,
+            tx,
+            None,
+        ))?;
 
-        let transaction3 = Transaction::new(TransactionKind::Resolve, client_id, 1, None);
+        // A partial dispute of an already disputed tx does not overwrite the disputed amount
+        match accounts.handle_transaction(Transaction::new(
+            TransactionKind::Dispute,
+            client_id,
+            tx,
+            Some(4.0),
+        )) {
+            Err(TransactionError::TxAlreadyDisputed(t)) => {
+                assert_eq!(t, tx);
+            }
+            _ => {
+                panic!("No error??");
+            }
+        }
 
-        accounts.handle_transaction(transaction3)?;
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Resolve,
+            client_id,
+            tx,
+            None,
+        ))?;
 
-        let account: &Account = accounts
+        let account = accounts
             .get_client_account(client_id)
             .ok_or("Cannot client client account")?;
-
-        assert_eq!(account.available, deposit_amount);
-        assert_eq!(account.total, deposit_amount);
+        assert_eq!(account.available, 10.0);
         assert_eq!(account.held, 0.0);
+        assert_eq!(account.total, 10.0);
 
         Ok(())
     }
 
     #[test]
-    fn accounts_dispute_then_chargebacks() -> Result<(), Box<dyn Error>> {
-        let mut accounts = Accounts::new();
+    fn accounts_max_open_disputes_dispute_twice() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::builder().max_open_disputes(Some(1)).build();
 
         let client_id = 1;
-        let deposit_amount = 25.11;
-        let transaction1 =
-            Transaction::new(TransactionKind::Deposit, client_id, 1, Some(deposit_amount));
-
-        let transaction2 = Transaction::new(TransactionKind::Dispute, client_id, 1, None);
-
-        accounts.handle_transaction(transaction1)?;
-        accounts.handle_transaction(transaction2)?;
+        let tx = 1;
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Deposit,
+            client_id,
+            tx,
+            Some(10.0),
+        ))?;
+        accounts.handle_transaction(Transaction::new(
+            TransactionKind::Dispute,
+            client_id,
+            tx,
+            None,
+        ))?;
+
+        // Disputing the same tx again does not hold its funds twice
+        for _ in 0..2 {
+            match accounts.handle_transaction(Transaction::new(
+                TransactionKind::Dispute,
+                client_id,
+                tx,
+                None,
+            )) {
+                Err(TransactionError::TxAlreadyDisputed(t)) => {
+                    assert_eq!(t, tx);
+                }
+                _ => {
+                    panic!("No error??");
+                }
+            }
+        }
 
-        let account: &Account = accounts
+        let account = accounts
             .get_client_account(client_id)
             .ok_or("Cannot client client account")?;
-
         assert_eq!(account.available, 0.0);
-        assert_eq!(account.total, deposit_amount);
-        assert_eq!(account.held, deposit_amount);
-
-        // Now Chargeback
+        assert_eq!(account.held, 10.0);
+        assert_eq!(account.total, 10.0);
 
-        let transaction3 = Transaction::new(TransactionKind::Chargeback, client_id, 1, None);
-
-        accounts.handle_transaction(transaction3)?;
+        Ok(())
+    }
 
-        let account: &Account = accounts
-            .get_client_account(client_id)
+    #[test]
+    fn accounts_snapshot_finalized() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::builder().idempotent(true).build();
+        accounts.apply_all([
+            Transaction::new(TransactionKind::Deposit, 1, 1, Some(10.0)),
+            Transaction::new(TransactionKind::Dispute, 1, 1, None),
+            Transaction::new(TransactionKind::Resolve, 1, 1, None),
+        ])?;
+        let snapshot = accounts.snapshot();
+
+        let mut state: Vec<u8> = Vec::new();
+        snapshot.write_csv(&mut state)?;
+        assert!(std::str::from_utf8(&state)?.contains("\nfinalized,0,,1,resolve,"));
+        let restored_snapshot = AccountsSnapshot::read_csv(state.as_slice())?;
+        assert_eq!(restored_snapshot, snapshot);
+
+        // A duplicated resolve in a later run is still skipped
+        let mut accounts = Accounts::builder().idempotent(true).build();
+        accounts.restore(restored_snapshot);
+        let outcome = accounts.handle_transaction_with_outcome(Transaction::new(
+            TransactionKind::Resolve,
+            1,
+            1,
+            None,
+        ))?;
+        assert!(outcome.replay);
+        let account = accounts
+            .get_client_account(1)
             .ok_or("Cannot client client account")?;
-
-        assert_eq!(account.available, 0.0);
-        assert_eq!(account.total, 0.0);
+        assert_eq!(account.available, 10.0);
         assert_eq!(account.held, 0.0);
-        assert_eq!(account.locked, true);
 
-        // Try another Deposit (should be rejected as account is locked)
+        Ok(())
+    }
 
-        let transaction3 =
-            Transaction::new(TransactionKind::Deposit, client_id, 2, Some(deposit_amount));
+    #[test]
+    fn accounts_dispute_evicted_tx_twice() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::with_max_retained_tx(2);
+        for tx in 1..=3 {
+            accounts.handle_transaction(Transaction::new(
+                TransactionKind::Deposit,
+                1,
+                tx,
+                Some(1.0),
+            ))?;
+        }
 
-        match accounts.handle_transaction(transaction3) {
-            Err(TransactionError::AccountLocked(client_id_)) => {
-                assert_eq!(client_id_, client_id);
-            }
-            _ => {
-                panic!("No error??");
+        // tx 1 has been evicted: still unknown when referenced again, like a never seen tx id
+        for tx in [1, 1, 8, 8] {
+            match accounts.handle_transaction(Transaction::new(
+                TransactionKind::Dispute,
+                1,
+                tx,
+                None,
+            )) {
+                Err(TransactionError::UnknownTransaction(t)) => {
+                    assert_eq!(t, tx);
+                }
+                _ => {
+                    panic!("No error??");
+                }
             }
         }
-
+        assert!(accounts.non_monetary_tx.is_empty());
         Ok(())
     }
 
     #[test]
-    fn accounts_resolve_non_disputed() -> Result<(), Box<dyn Error>> {
+    fn accounts_close_unknown_client() -> Result<(), Box<dyn Error>> {
         let mut accounts = Accounts::new();
 
-        let client_id = 1;
-        let deposit_amount = 25.11;
-        let tx = 1;
-        let transaction1 = Transaction::new(
-            TransactionKind::Deposit,
-            client_id,
-            tx,
-            Some(deposit_amount),
-        );
-
-        accounts.handle_transaction(transaction1)?;
-
-        let account: &Account = accounts
-            .get_client_account(client_id)
-            .ok_or("Cannot client client account")?;
-
-        assert_eq!(account.available, deposit_amount);
-        assert_eq!(account.total, deposit_amount);
-        assert_eq!(account.held, 0.0);
-
-        // Now Resolve
-
-        let transaction3 = Transaction::new(TransactionKind::Resolve, client_id, tx, None);
-
-        match accounts.handle_transaction(transaction3) {
-            Err(TransactionError::TxNonDisputed(tx_)) => {
-                assert_eq!(tx, tx_);
+        match accounts.handle_transaction(Transaction::new(TransactionKind::Close, 1, 1, None)) {
+            Err(TransactionError::UnknownClient(client_id)) => {
+                assert_eq!(client_id, 1);
             }
             _ => {
-                panic!("No error??")
+                panic!("No error??");
             }
-        };
+        }
+        assert!(accounts.get_client_account(1).is_none());
 
-        let account: &Account = accounts
-            .get_client_account(client_id)
+        // Client id can still be used
+        accounts.handle_transaction(Transaction::new(TransactionKind::Deposit, 1, 2, Some(1.0)))?;
+        let account = accounts
+            .get_client_account(1)
             .ok_or("Cannot client client account")?;
-
-        assert_eq!(account.available, deposit_amount);
-        assert_eq!(account.total, deposit_amount);
-        assert_eq!(account.held, 0.0);
-
+        assert_eq!(account.available, 1.0);
         Ok(())
     }
 
     #[test]
-    fn accounts_non_unique_tx() {
-        let mut accounts = Accounts::new();
-
-        let client_id = 1;
-        let tx = 1;
-        let deposit_amount = 25.11;
-        let withdraw_amount = 5.99;
-        let transaction1 = Transaction::new(
-            TransactionKind::Deposit,
-            client_id,
-            tx,
-            Some(deposit_amount),
-        );
-        let transaction2 = Transaction::new(
-            TransactionKind::Withdrawal,
-            client_id,
-            tx,
-            Some(withdraw_amount),
-        );
-
-        accounts.handle_transaction(transaction1).unwrap();
-
-        match accounts.handle_transaction(transaction2) {
-            Err(TransactionError::InvalidTransaction(tx_)) => {
-                assert_eq!(tx_, tx)
-            }
-            _ => {
-                panic!("No error??")
+    fn accounts_dispute_other_client_tx() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::builder().max_open_disputes(Some(1)).build();
+        accounts.apply_all([
+            Transaction::new(TransactionKind::Deposit, 1, 1, Some(5.0)),
+            Transaction::new(TransactionKind::Deposit, 2, 2, Some(3.0)),
+        ])?;
+
+        for kind in [
+            TransactionKind::Dispute,
+            TransactionKind::Resolve,
+            TransactionKind::Chargeback,
+            TransactionKind::Reversal,
+        ] {
+            match accounts.handle_transaction(Transaction::new(kind, 2, 1, None)) {
+                Err(TransactionError::ClientMismatch(tx)) => {
+                    assert_eq!(tx, 1);
+                }
+                _ => {
+                    panic!("No error??");
+                }
             }
-        };
+        }
+        // Nothing held nor counted, on either client
+        assert!(accounts.open_disputes.is_empty());
+        for (client_id, total) in [(1, 5.0), (2, 3.0)] {
+            let account = accounts
+                .get_client_account(client_id)
+                .ok_or("Cannot get client account")?;
+            assert_eq!(account.available, total);
+            assert_eq!(account.held, 0.0);
+        }
+
+        // Each client can still dispute its own tx (up to the cap)
+        accounts.handle_transaction(Transaction::new(TransactionKind::Dispute, 1, 1, None))?;
+        accounts.handle_transaction(Transaction::new(TransactionKind::Dispute, 2, 2, None))?;
+        assert_eq!(accounts.open_disputes.get(&1), Some(&1));
+        assert_eq!(accounts.open_disputes.get(&2), Some(&1));
+        Ok(())
     }
 }
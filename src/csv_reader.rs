@@ -1,13 +1,43 @@
 // std
+use std::fmt;
 use std::fs::File;
-use std::path::PathBuf;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 // third party libs
-use csv::{Reader, Trim};
-use serde::Deserialize;
+use csv::{ByteRecord, Reader, Terminator, Trim};
+use flate2::read::MultiGzDecoder;
+use log::{debug, warn};
+use serde::{de, Deserialize, Deserializer, Serialize};
+
+/// Max length of the record snippet in a [RecordError]
+const RECORD_SNIPPET_LEN: usize = 64;
+
+/// Legacy csv column names (mapped to [Transaction] fields via serde aliases)
+const LEGACY_COLUMNS: [&str; 3] = ["client_id", "transaction_id", "amt"];
+
+/// Allowed csv columns: (column name, [Transaction] field)
+const CSV_COLUMNS: [(&str, &str); 9] = [
+    ("type", "type"),
+    ("client", "client"),
+    ("client_id", "client"),
+    ("tx", "tx"),
+    ("transaction_id", "tx"),
+    ("amount", "amount"),
+    ("amt", "amount"),
+    ("timestamp", "timestamp"),
+    ("currency", "currency"),
+];
+
+/// Alternative names of [TransactionKind] in the csv `type` column (see serde aliases)
+const KIND_ALIASES: [&str; 1] = ["info"];
+
+/// Mandatory [Transaction] fields in csv header
+const CSV_REQUIRED_FIELDS: [&str; 4] = ["type", "client", "tx", "amount"];
 
 /// Transaction type that we can handle
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub enum TransactionKind {
     /// A Deposit of money on an account
@@ -20,70 +50,742 @@ pub enum TransactionKind {
     Resolve,
     /// A Chargeback for an already disputed transaction
     Chargeback,
+    /// A Fee debited from an account (cannot be disputed)
+    Fee,
+    /// A Reversal of a deposit credited in error
+    Reversal,
+    /// A Close of an empty account (no later transaction is accepted on it)
+    Close,
+    /// An informational row (logged, then ignored)
+    #[serde(alias = "info")]
+    Note,
+}
+
+impl TransactionKind {
+    /// All transaction types
+    pub const ALL: [TransactionKind; 9] = [
+        TransactionKind::Deposit,
+        TransactionKind::Withdrawal,
+        TransactionKind::Dispute,
+        TransactionKind::Resolve,
+        TransactionKind::Chargeback,
+        TransactionKind::Fee,
+        TransactionKind::Reversal,
+        TransactionKind::Close,
+        TransactionKind::Note,
+    ];
+
+    /// Name as found in the csv `type` column
+    pub fn name(&self) -> &'static str {
+        match self {
+            TransactionKind::Deposit => "deposit",
+            TransactionKind::Withdrawal => "withdrawal",
+            TransactionKind::Dispute => "dispute",
+            TransactionKind::Resolve => "resolve",
+            TransactionKind::Chargeback => "chargeback",
+            TransactionKind::Fee => "fee",
+            TransactionKind::Reversal => "reversal",
+            TransactionKind::Close => "close",
+            TransactionKind::Note => "note",
+        }
+    }
+}
+
+/// A currency (ISO 4217 like code: 3 ascii letters, e.g. `USD`, `EUR`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Currency([u8; 3]);
+
+impl FromStr for Currency {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.as_bytes() {
+            [a, b, c] if s.bytes().all(|b| b.is_ascii_alphabetic()) => Ok(Currency([
+                a.to_ascii_uppercase(),
+                b.to_ascii_uppercase(),
+                c.to_ascii_uppercase(),
+            ])),
+            _ => Err(format!("Invalid currency: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Only ascii letters (see from_str)
+        f.write_str(std::str::from_utf8(&self.0).unwrap_or_default())
+    }
+}
+
+impl Serialize for Currency {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Currency {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
 }
 
 /// A Transaction that can be applied to an Account
-#[derive(Debug, Deserialize)]
+///
+/// Csv header: `type,client,tx,amount[,timestamp][,currency]` (legacy column names: `client_id`, `transaction_id`, `amt`)
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct Transaction {
-    #[serde(rename(deserialize = "type"))]
+    #[serde(rename = "type")]
     pub kind: TransactionKind,
     /// a client id (assume 1 client = 1 account)
+    #[serde(alias = "client_id", deserialize_with = "deserialize_client")]
     pub client: u16,
     /// a transaction id (globally unique)
+    #[serde(alias = "transaction_id", deserialize_with = "deserialize_tx")]
     pub tx: u32,
     /// amount of money
-    #[serde(deserialize_with = "csv::invalid_option")]
+    #[serde(alias = "amt", deserialize_with = "deserialize_amount")]
     pub amount: Option<f64>, // TODO: f32 or f64?
+    /// optional timestamp (e.g. unix epoch), a Dispute cannot be older than its transaction
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+    /// optional currency (None: the single default currency), 1 account per client & currency
+    #[serde(default)]
+    pub currency: Option<Currency>,
     /// Is this transaction already referenced by a Dispute? (for Resolve & Chargeback)
     #[serde(skip)]
     pub under_dispute: bool,
+    /// Amount held by the current Dispute (the full amount unless a partial Dispute)
+    #[serde(skip)]
+    pub disputed_amount: Option<f64>,
+    /// Is this transaction reversed by a Reversal? (then it cannot be disputed)
+    #[serde(skip)]
+    pub reversed: bool,
+    /// Values of extra csv columns (see [CsvFormat::extra_columns]): (column, value)
+    #[serde(skip)]
+    pub extras: Vec<(String, String)>,
 }
 
 impl Transaction {
-    /// Init a Transaction from scratch (only for unit tests)
-    /// Use `CsvReader` to get a list of Transaction
+    /// Init a Transaction from scratch (e.g. to apply it without csv, see [crate::accounts::Accounts::apply_all])
+    /// Use `CsvReader` to get a list of Transaction from a csv file
     pub fn new(kind: TransactionKind, client: u16, tx: u32, amount: Option<f64>) -> Self {
         Self {
             kind,
             client,
             tx,
             amount,
+            timestamp: None,
+            currency: None,
             under_dispute: false,
+            disputed_amount: None,
+            reversed: false,
+            extras: vec![],
+        }
+    }
+
+    /// Same transaction with a timestamp
+    pub fn with_timestamp(mut self, timestamp: u64) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    /// Same transaction in a given currency
+    pub fn with_currency(mut self, currency: Currency) -> Self {
+        self.currency = Some(currency);
+        self
+    }
+
+    /// Is this transaction a replay of `other`? (same kind, client, tx, amount, timestamp and currency)
+    pub fn is_replay_of(&self, other: &Transaction) -> bool {
+        self.kind == other.kind
+            && self.client == other.client
+            && self.tx == other.tx
+            && self.amount == other.amount
+            && self.timestamp == other.timestamp
+            && self.currency == other.currency
+    }
+}
+
+/// Check csv header: only known columns (see [CSV_COLUMNS]) or allowed extra ones,
+/// no duplicate & no missing one
+pub(crate) fn validate_headers<'a, I>(headers: I, extra_columns: &[String]) -> Result<(), String>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let mut fields: Vec<&str> = vec![];
+    for header in headers {
+        if extra_columns.iter().any(|c| c == header) {
+            if fields.contains(&header) {
+                return Err(format!("duplicate column: {:?}", header));
+            }
+            fields.push(header);
+            continue;
+        }
+        let field = CSV_COLUMNS
+            .iter()
+            .find(|(column, _)| *column == header)
+            .map(|(_, field)| *field)
+            .ok_or_else(|| format!("unexpected column: {:?}", header))?;
+        if fields.contains(&field) {
+            return Err(format!("duplicate column: {:?}", header));
+        }
+        fields.push(field);
+    }
+
+    // Report all missing columns at once
+    let missing: Vec<String> = CSV_REQUIRED_FIELDS
+        .iter()
+        .filter(|f| !fields.contains(f))
+        .map(|f| format!("{:?}", f))
+        .collect();
+    match missing.len() {
+        0 => Ok(()),
+        1 => Err(format!("missing column: {}", missing[0])),
+        _ => Err(format!("missing columns: {}", missing.join(", "))),
+    }
+}
+
+/// Deserialize an id, reporting the offending value if it does not fit in `T`
+fn deserialize_id<'de, D, T>(deserializer: D, name: &str) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: TryFrom<i64>,
+{
+    let value = i64::deserialize(deserializer)?;
+    T::try_from(value).map_err(|_| {
+        de::Error::custom(format!(
+            "{} {} is out of range ({})",
+            name,
+            value,
+            std::any::type_name::<T>()
+        ))
+    })
+}
+
+/// Client id must fit in a u16 (no silent wrapping)
+fn deserialize_client<'de, D>(deserializer: D) -> Result<u16, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_id(deserializer, "client id")
+}
+
+/// Transaction id must fit in a u32 (no silent wrapping)
+fn deserialize_tx<'de, D>(deserializer: D) -> Result<u32, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserialize_id(deserializer, "tx id")
+}
+
+/// Deserialize an amount: a number (e.g. in JSON), or a string allowing a leading `+` and
+/// surrounding whitespace (e.g. ` +25.11 `)
+///
+/// Like [csv::invalid_option], an invalid amount is read as None
+/// (but an amount out of f64 range is an error)
+fn deserialize_amount<'de, D>(deserializer: D) -> Result<Option<f64>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    deserializer.deserialize_option(AmountVisitor)
+}
+
+/// Visitor of [deserialize_amount] (a csv field looking like a number is visited as a number)
+struct AmountVisitor;
+
+impl AmountVisitor {
+    /// An absurdly large amount (e.g. 1e400) would silently saturate to infinity
+    fn in_range<E: de::Error>(amount: f64, text: &str) -> Result<Option<f64>, E> {
+        if amount.is_infinite() {
+            let text: String = text.trim().chars().take(RECORD_SNIPPET_LEN).collect();
+            Err(E::custom(format!("amount {} is out of range (f64)", text)))
+        } else {
+            Ok(Some(amount))
+        }
+    }
+}
+
+impl<'de> de::Visitor<'de> for AmountVisitor {
+    type Value = Option<f64>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an amount (a number or a string)")
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_any(self)
+    }
+
+    fn visit_bool<E: de::Error>(self, _v: bool) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+        Ok(Some(v as f64))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+        Ok(Some(v as f64))
+    }
+
+    fn visit_u128<E: de::Error>(self, v: u128) -> Result<Self::Value, E> {
+        Ok(Some(v as f64))
+    }
+
+    fn visit_i128<E: de::Error>(self, v: i128) -> Result<Self::Value, E> {
+        Ok(Some(v as f64))
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Self::Value, E> {
+        Self::in_range(v, &v.to_string())
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        let amount = v.trim();
+        let amount = match amount.strip_prefix('+') {
+            // a sign only once (e.g. reject "+-25.11")
+            Some(unsigned) if unsigned.trim_start().starts_with(['+', '-']) => return Ok(None),
+            Some(unsigned) => unsigned.trim_start(),
+            None => amount,
+        };
+        match amount.parse::<f64>() {
+            Ok(amount) => Self::in_range(amount, v),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+/// Write transactions as csv (header: type, client, tx, amount, timestamp, currency), e.g. to re-export them
+pub fn write_transactions<'a, I, W>(transactions: I, into: &mut W) -> Result<(), csv::Error>
+where
+    I: IntoIterator<Item = &'a Transaction>,
+    W: Write,
+{
+    let mut wtr = csv::Writer::from_writer(into);
+    for transaction in transactions {
+        wtr.serialize(transaction)?;
+    }
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Sort transactions in a deterministic order, whatever their original order (e.g. to combine
+/// several sources): by timestamp (if any), then tx id, then a transaction before the
+/// Dispute / Resolve / Reversal / Chargeback referencing it
+///
+/// The sort is stable: transactions with the same key keep their original order (e.g. a
+/// dispute, resolve, dispute cycle on a tx is not reordered)
+pub fn sort_transactions(transactions: &mut [Transaction]) {
+    let is_reference = |kind: TransactionKind| {
+        matches!(
+            kind,
+            TransactionKind::Dispute
+                | TransactionKind::Resolve
+                | TransactionKind::Reversal
+                | TransactionKind::Chargeback
+        )
+    };
+    transactions.sort_by_key(|t| (t.timestamp, t.tx, is_reference(t.kind)));
+}
+
+/// A csv error with the (truncated) offending record, as read from the csv file
+#[derive(Debug, thiserror::Error)]
+#[error("{error} (record: {record:?})")]
+pub struct RecordError {
+    #[source]
+    pub error: csv::Error,
+    /// Offending record (fields joined with ',', truncated)
+    pub record: String,
+}
+
+impl RecordError {
+    pub(crate) fn new(error: csv::Error, record: &ByteRecord) -> Self {
+        Self {
+            error,
+            record: record_snippet(record),
+        }
+    }
+}
+
+/// Record fields joined with ',' (truncated to [RECORD_SNIPPET_LEN] chars)
+fn record_snippet(record: &ByteRecord) -> String {
+    let fields: Vec<String> = record
+        .iter()
+        .map(|f| String::from_utf8_lossy(f).into_owned())
+        .collect();
+    let mut record = fields.join(",");
+    if record.chars().count() > RECORD_SNIPPET_LEN {
+        record = record.chars().take(RECORD_SNIPPET_LEN).collect::<String>() + "...";
+    }
+    record
+}
+
+/// What to do with a record with an unknown `type` (see [CsvReader::on_unknown_kind])
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnknownKind {
+    /// Return an error (like any other invalid record)
+    #[default]
+    Fail,
+    /// Skip the record (logged as a warning)
+    Skip,
+}
+
+impl FromStr for UnknownKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(UnknownKind::Fail),
+            "skip" => Ok(UnknownKind::Skip),
+            _ => Err(format!("Invalid unknown kind policy: {}", s)),
+        }
+    }
+}
+
+/// Format of the input csv (see [CsvReader::from_reader_with_format])
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CsvFormat {
+    /// field delimiter (default: `,`)
+    pub delimiter: u8,
+    /// amounts use `,` as decimal separator (e.g. `25,11`)
+    pub decimal_comma: bool,
+    /// extra (optional) columns allowed in header, their values are kept in
+    /// [Transaction::extras] (e.g. `region`), any other unknown column is still an error
+    pub extra_columns: Vec<String>,
+}
+
+impl Default for CsvFormat {
+    fn default() -> Self {
+        Self {
+            delimiter: b',',
+            decimal_comma: false,
+            extra_columns: vec![],
+        }
+    }
+}
+
+impl CsvFormat {
+    /// European style csv: `;` delimited, with `,` as decimal separator (e.g. `deposit;1;1;25,11`)
+    pub fn decimal_comma() -> Self {
+        Self {
+            delimiter: b';',
+            decimal_comma: true,
+            ..Self::default()
         }
     }
 }
 
-/// Our csv reader & iterator (over `Transaction`)
-pub struct CsvReader {
+/// Same record with `,` swapped for `.` in the amount column (e.g. `25,11` -> `25.11`)
+fn with_decimal_point(record: &ByteRecord, amount_index: usize) -> ByteRecord {
+    record
+        .iter()
+        .enumerate()
+        .map(|(i, field)| {
+            if i == amount_index {
+                field
+                    .iter()
+                    .map(|b| if *b == b',' { b'.' } else { *b })
+                    .collect()
+            } else {
+                field.to_vec()
+            }
+        })
+        .collect()
+}
+
+/// Our csv reader & iterator (over `Transaction`), reading a csv file by default
+/// (see [CsvReader::from_reader] for any other source, e.g. a socket)
+pub struct CsvReader<R = Box<dyn Read>> {
     // csv_path: PathBuf,
-    rdr: Reader<File>,
+    rdr: Reader<R>,
+    headers: ByteRecord,
+    record: ByteRecord,        // reused for every record (avoid allocations)
+    type_index: Option<usize>, // index of the `type` column
+    on_unknown_kind: UnknownKind,
+    decimal_comma_index: Option<usize>, // index of the amount column (only with a decimal comma)
+    skip_invalid_utf8: bool,
+    extra_indexes: Vec<(String, usize)>, // extra columns found in header, with their index
 }
 
 impl CsvReader {
+    /// Read a csv file (see [open_input_file] for supported files)
     pub fn new(csv_path: PathBuf) -> Result<Self, std::io::Error> {
-        // let mut rdr = csv::Reader::from_reader(csv_path);
+        Self::from_reader(open_input_file(&csv_path)?)
+    }
+}
+
+/// Compression of an input file, from its extension (see [open_input_file])
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Compression {
+    /// `.csv` or any other extension: read as is
+    None,
+    /// `.gz`
+    Gzip,
+}
+
+impl Compression {
+    fn of(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some(e) if e.eq_ignore_ascii_case("gz") => Compression::Gzip,
+            _ => Compression::None,
+        }
+    }
+}
+
+/// Open an input file, dispatching on its extension: a gzip file (`.gz`) is decompressed on
+/// the fly (including a multi member one, e.g. concatenated gzip files), any other file is read
+/// as is (no decompression overhead)
+pub fn open_input_file(path: &Path) -> Result<Box<dyn Read>, io::Error> {
+    let file = File::open(path)?;
+    Ok(match Compression::of(path) {
+        Compression::None => Box::new(file),
+        Compression::Gzip => Box::new(MultiGzDecoder::new(file)),
+    })
+}
+
+/// Input csv files of a path: the file itself or, for a directory, its `*.csv` files
+/// sorted by name (any other file is ignored)
+pub fn input_files(path: &Path) -> Result<Vec<PathBuf>, io::Error> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    let mut files = vec![];
+    for entry in std::fs::read_dir(path)? {
+        let file = entry?.path();
+        if file.is_file() && file.extension().is_some_and(|ext| ext == "csv") {
+            files.push(file);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
 
-        let rdr = csv::ReaderBuilder::new()
+impl<R: Read> CsvReader<R> {
+    /// Read csv from any source (note: the csv header is read, thus waited for, here)
+    pub fn from_reader(reader: R) -> Result<Self, std::io::Error> {
+        Self::from_reader_with_format(reader, CsvFormat::default())
+    }
+
+    /// Same as [CsvReader::from_reader] for another csv format (e.g. [CsvFormat::decimal_comma])
+    pub fn from_reader_with_format(reader: R, format: CsvFormat) -> Result<Self, std::io::Error> {
+        if format.decimal_comma && format.delimiter == b',' {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "a decimal comma requires another delimiter than a comma",
+            ));
+        }
+
+        // Records end with \n, \r\n or \r (mixed line endings are fine), and Trim::All
+        // also trims a stray \r left in a field (e.g. a quoted one)
+        // A quoted field can contain a line break (or an escaped quote: "") without splitting
+        // its record (see [CsvReader::next])
+        let mut rdr = csv::ReaderBuilder::new()
+            .delimiter(format.delimiter)
+            .terminator(Terminator::CRLF)
+            .quoting(true)
+            .double_quote(true)
             .trim(Trim::All)
             .has_headers(true)
-            .from_path(csv_path)?;
+            .from_reader(reader);
+
+        // Header sniffing
+        let headers = rdr.headers()?;
+        if headers.is_empty() {
+            // Note: an empty file is valid (no transactions), like a header only file
+            debug!("Empty csv file");
+        } else {
+            if headers.iter().any(|h| LEGACY_COLUMNS.contains(&h)) {
+                debug!("Legacy csv header: {:?}", headers);
+            }
+            validate_headers(headers.iter(), &format.extra_columns).map_err(|e| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Csv header: {}", e),
+                )
+            })?;
+        }
+        let headers = rdr.byte_headers()?.clone();
+        let type_index = headers.iter().position(|h| h == b"type");
+        let decimal_comma_index = format
+            .decimal_comma
+            .then(|| headers.iter().position(|h| h == b"amount" || h == b"amt"))
+            .flatten();
+        let extra_indexes = format
+            .extra_columns
+            .iter()
+            .filter_map(|c| {
+                let index = headers.iter().position(|h| h == c.as_bytes())?;
+                Some((c.clone(), index))
+            })
+            .collect();
 
         Ok(CsvReader {
             // csv_path,
             rdr,
+            headers,
+            record: ByteRecord::new(),
+            type_index,
+            on_unknown_kind: UnknownKind::default(),
+            decimal_comma_index,
+            skip_invalid_utf8: false,
+            extra_indexes,
         })
     }
+
+    /// What to do with a record with an unknown `type` (default: [UnknownKind::Fail])
+    pub fn on_unknown_kind(mut self, on_unknown_kind: UnknownKind) -> Self {
+        self.on_unknown_kind = on_unknown_kind;
+        self
+    }
+
+    /// Skip (and log as a warning) a record with invalid UTF-8 (e.g. a stray byte), instead
+    /// of returning an error (default: false)
+    ///
+    /// Note: the header must be valid UTF-8
+    pub fn skip_invalid_utf8(mut self, skip_invalid_utf8: bool) -> Self {
+        self.skip_invalid_utf8 = skip_invalid_utf8;
+        self
+    }
+
+    /// Why the current record should be skipped (if it should)
+    fn skip_reason(&self) -> Option<&'static str> {
+        if self.on_unknown_kind == UnknownKind::Skip && self.has_unknown_kind() {
+            Some("unknown type")
+        } else if self.skip_invalid_utf8
+            && self.record.iter().any(|f| std::str::from_utf8(f).is_err())
+        {
+            Some("invalid UTF-8")
+        } else {
+            None
+        }
+    }
+
+    /// Values of the extra columns of the current record (see [CsvFormat::extra_columns])
+    fn extras(&self) -> Vec<(String, String)> {
+        self.extra_indexes
+            .iter()
+            .map(|(column, i)| {
+                let value = self.record.get(*i).unwrap_or_default();
+                (column.clone(), String::from_utf8_lossy(value).into_owned())
+            })
+            .collect()
+    }
+
+    /// Is the `type` of the current record unknown?
+    fn has_unknown_kind(&self) -> bool {
+        self.type_index
+            .and_then(|i| self.record.get(i))
+            .is_some_and(|t| {
+                !TransactionKind::ALL
+                    .iter()
+                    .any(|k| k.name().as_bytes() == t)
+                    && !KIND_ALIASES.iter().any(|a| a.as_bytes() == t)
+            })
+    }
+
+    /// Drain the reader, counting transactions (stop at the first invalid record)
+    pub fn try_count(self) -> Result<usize, RecordError> {
+        try_count(self)
+    }
+
+    /// Iterate over valid transactions only: invalid records are logged (warn) then skipped
+    pub fn into_lenient(self) -> impl Iterator<Item = Transaction> {
+        lenient(self)
+    }
+}
+
+/// Drain transactions read by any reader (e.g. [CsvReader::try_count]), counting them
+/// (stop at the first invalid record)
+pub fn try_count<I>(transactions: I) -> Result<usize, RecordError>
+where
+    I: IntoIterator<Item = Result<Transaction, RecordError>>,
+{
+    transactions
+        .into_iter()
+        .try_fold(0, |count, t| t.map(|_| count + 1))
+}
+
+/// Valid transactions only of any reader (e.g. [CsvReader::into_lenient]): invalid records
+/// are logged (warn) then skipped
+pub fn lenient<I>(transactions: I) -> impl Iterator<Item = Transaction>
+where
+    I: IntoIterator<Item = Result<Transaction, RecordError>>,
+{
+    transactions.into_iter().filter_map(|t| match t {
+        Ok(transaction) => Some(transaction),
+        Err(e) => {
+            warn!("Skipping invalid record: {}", e);
+            None
+        }
+    })
 }
 
-impl Iterator for CsvReader {
-    type Item = Result<Transaction, csv::Error>;
+impl<R: Read> Iterator for CsvReader<R> {
+    type Item = Result<Transaction, RecordError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        for result in self.rdr.deserialize() {
-            return Some(result);
+        let mut read = self.rdr.read_byte_record(&mut self.record);
+        while matches!(read, Ok(true)) {
+            let Some(reason) = self.skip_reason() else {
+                break;
+            };
+            warn!(
+                "Skipping record with {}: {:?}",
+                reason,
+                record_snippet(&self.record)
+            );
+            read = self.rdr.read_byte_record(&mut self.record);
         }
 
-        None
+        match read {
+            Ok(true) => {
+                // A line break in a (quoted) field is fine for csv, but not in a Transaction
+                // field (only in an extra column): reject it rather than silently misreading it
+                if let Some(column) = self
+                    .record
+                    .iter()
+                    .zip(self.headers.iter())
+                    .enumerate()
+                    .filter(|(i, _)| !self.extra_indexes.iter().any(|(_, j)| j == i))
+                    .find_map(|(_, (f, h))| {
+                        f.iter().any(|b| matches!(b, b'\n' | b'\r')).then_some(h)
+                    })
+                {
+                    let error = io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("line break in column {:?}", String::from_utf8_lossy(column)),
+                    );
+                    return Some(Err(RecordError::new(error.into(), &self.record)));
+                }
+
+                if let Some(amount_index) = self.decimal_comma_index {
+                    self.record = with_decimal_point(&self.record, amount_index);
+                }
+
+                Some(
+                    self.record
+                        .deserialize(Some(&self.headers))
+                        .map(|mut transaction: Transaction| {
+                            transaction.extras = self.extras();
+                            transaction
+                        })
+                        .map_err(|e| RecordError::new(e, &self.record)),
+                )
+            }
+            Ok(false) => None,
+            Err(e) => Some(Err(RecordError::new(e, &self.record))),
+        }
     }
 }
 
@@ -100,6 +802,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::map_identity)]
     fn csv_read_valid_sample() -> Result<(), std::io::Error> {
         let csv_1 = PathBuf::from("resources/sample_1.csv");
         let csv_reader = CsvReader::new(csv_1)?;
@@ -111,6 +814,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::map_identity)]
     fn csv_read_with_errors() -> Result<(), std::io::Error> {
         let csv_1 = PathBuf::from("resources/sample_1_with_errors.csv");
         let csv_reader = CsvReader::new(csv_1)?;
@@ -120,6 +824,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::map_identity)]
     fn csv_read_valid_with_spaces() -> Result<(), std::io::Error> {
         let csv_2 = PathBuf::from("resources/sample_2.csv");
         let csv_reader = CsvReader::new(csv_2)?;
@@ -129,4 +834,537 @@ mod tests {
         assert_eq!(transactions.unwrap().len(), 5);
         Ok(())
     }
+
+    #[test]
+    fn csv_read_integer_amounts() -> Result<(), Box<dyn std::error::Error>> {
+        let csv_3 = PathBuf::from("resources/sample_3_integer_amounts.csv");
+        let csv_reader = CsvReader::new(csv_3)?;
+        let transactions: Vec<Transaction> = csv_reader.collect::<Result<_, _>>()?;
+
+        let amounts: Vec<Option<f64>> = transactions.iter().map(|t| t.amount).collect();
+        assert_eq!(
+            amounts,
+            vec![Some(100.0), Some(100.0), Some(2.0), Some(1.5), Some(50.0)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn csv_read_header_variants() -> Result<(), Box<dyn std::error::Error>> {
+        let csv_1 = PathBuf::from("resources/sample_1.csv");
+        let expected: Vec<Transaction> = CsvReader::new(csv_1)?.collect::<Result<_, _>>()?;
+
+        for sample in [
+            "resources/sample_1_legacy_header.csv",
+            "resources/sample_1_mixed_header.csv",
+        ] {
+            let transactions: Vec<Transaction> =
+                CsvReader::new(PathBuf::from(sample))?.collect::<Result<_, _>>()?;
+
+            assert_eq!(transactions.len(), expected.len());
+            assert!(transactions
+                .iter()
+                .zip(expected.iter())
+                .all(|(t, e)| t.is_replay_of(e)));
+        }
+
+        // Unknown column name: rejected before reading any record
+        let csv_unknown = PathBuf::from("resources/sample_1_unknown_header.csv");
+        assert!(CsvReader::new(csv_unknown).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn csv_read_empty() -> Result<(), Box<dyn std::error::Error>> {
+        for sample in ["resources/empty.csv", "resources/header_only.csv"] {
+            let csv_reader = CsvReader::new(PathBuf::from(sample))?;
+            let transactions: Vec<Transaction> = csv_reader.collect::<Result<_, _>>()?;
+            assert!(transactions.is_empty());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn csv_write_transactions() -> Result<(), Box<dyn std::error::Error>> {
+        let csv_1 = PathBuf::from("resources/sample_1.csv");
+        let transactions: Vec<Transaction> = CsvReader::new(csv_1)?.collect::<Result<_, _>>()?;
+
+        let mut output: Vec<u8> = Vec::new();
+        write_transactions(&transactions, &mut output)?;
+
+        assert_eq!(
+            std::str::from_utf8(&output)?,
+            "type,client,tx,amount,timestamp,currency\n\
+             deposit,1,1,1.0,,\n\
+             deposit,2,2,2.0,,\n\
+             deposit,1,3,2.0,,\n\
+             withdrawal,1,4,1.5,,\n\
+             withdrawal,2,5,1.9,,\n"
+        );
+
+        // Internal state (e.g. under_dispute) is not written
+        let mut transaction = Transaction::new(TransactionKind::Dispute, 2, 2, None);
+        transaction.under_dispute = true;
+        let mut output: Vec<u8> = Vec::new();
+        write_transactions([&transaction], &mut output)?;
+        assert_eq!(
+            std::str::from_utf8(&output)?,
+            "type,client,tx,amount,timestamp,currency\ndispute,2,2,,,\n"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn csv_read_error_record() -> Result<(), std::io::Error> {
+        // Last row has too few fields
+        let csv_1 = PathBuf::from("resources/sample_1_with_errors.csv");
+        let csv_reader = CsvReader::new(csv_1)?;
+        let e = csv_reader.filter_map(|t| t.err()).next().unwrap();
+
+        assert_eq!(e.record, "withdrawal,2,5");
+        assert!(e.to_string().contains("withdrawal,2,5"));
+        Ok(())
+    }
+
+    #[test]
+    fn csv_read_timestamps() -> Result<(), Box<dyn std::error::Error>> {
+        let csv_4 = PathBuf::from("resources/sample_4_timestamps.csv");
+        let transactions: Vec<Transaction> = CsvReader::new(csv_4)?.collect::<Result<_, _>>()?;
+
+        let timestamps: Vec<Option<u64>> = transactions.iter().map(|t| t.timestamp).collect();
+        assert_eq!(
+            timestamps,
+            vec![Some(1650000000), Some(1650000100), None, Some(1650000050)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn csv_read_ids_out_of_range() -> Result<(), std::io::Error> {
+        let csv_1 = PathBuf::from("resources/sample_1_ids_out_of_range.csv");
+        let errors: Vec<String> = CsvReader::new(csv_1)?
+            .filter_map(|t| t.err())
+            .map(|e| e.to_string())
+            .collect();
+
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].contains("client id 70000 is out of range"));
+        assert!(errors[1].contains("tx id 5000000000 is out of range"));
+        Ok(())
+    }
+
+    #[test]
+    fn csv_read_lenient() -> Result<(), std::io::Error> {
+        // 2 invalid records (in the middle) are skipped
+        let csv_1 = PathBuf::from("resources/sample_1_ids_out_of_range.csv");
+        let transactions: Vec<Transaction> = CsvReader::new(csv_1)?.into_lenient().collect();
+
+        let txs: Vec<u32> = transactions.iter().map(|t| t.tx).collect();
+        assert_eq!(txs, vec![1, 4]);
+        Ok(())
+    }
+
+    #[test]
+    fn csv_read_crlf() -> Result<(), Box<dyn std::error::Error>> {
+        let lf: Vec<Transaction> =
+            CsvReader::new(PathBuf::from("resources/sample_1.csv"))?.collect::<Result<_, _>>()?;
+
+        // CRLF, mixed line endings & a stray \r in a (quoted) type field
+        for sample in [
+            "resources/sample_1_crlf.csv",
+            "resources/sample_1_mixed_line_endings.csv",
+        ] {
+            let transactions: Vec<Transaction> =
+                CsvReader::new(PathBuf::from(sample))?.collect::<Result<_, _>>()?;
+            assert_eq!(transactions.len(), 5);
+            assert_eq!(transactions, lf);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn csv_read_embedded_newlines() -> Result<(), std::io::Error> {
+        let csv_1 = PathBuf::from("resources/sample_1_embedded_newlines.csv");
+        let results: Vec<Result<Transaction, RecordError>> = CsvReader::new(csv_1)?.collect();
+
+        // Quoted fields with a line break do not split their record
+        assert_eq!(results.len(), 5);
+        assert_eq!(results[1].as_ref().ok().and_then(|t| t.amount), Some(2.0));
+        for (i, column) in [(2, "type"), (3, "amount")] {
+            match &results[i] {
+                Err(e) => {
+                    assert!(e
+                        .to_string()
+                        .contains(&format!("line break in column {:?}", column)));
+                }
+                _ => {
+                    panic!("No error??");
+                }
+            }
+        }
+        assert!(results[4].is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn csv_read_from_reader() -> Result<(), Box<dyn std::error::Error>> {
+        let expected: Vec<Transaction> =
+            CsvReader::new(PathBuf::from("resources/sample_1.csv"))?.collect::<Result<_, _>>()?;
+
+        let csv = std::fs::read("resources/sample_1.csv")?;
+        let transactions: Vec<Transaction> =
+            CsvReader::from_reader(csv.as_slice())?.collect::<Result<_, _>>()?;
+        assert_eq!(transactions, expected);
+
+        assert!(CsvReader::from_reader("type,client,amout\n".as_bytes()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn csv_read_unknown_kind() -> Result<(), Box<dyn std::error::Error>> {
+        let csv_1 = PathBuf::from("resources/sample_1_unknown_kind.csv");
+
+        // Fail (default)
+        let results: Vec<Result<Transaction, RecordError>> =
+            CsvReader::new(csv_1.clone())?.collect();
+        assert_eq!(results.len(), 3);
+        assert!(results[0].is_ok() && results[2].is_ok());
+        match &results[1] {
+            Err(e) => {
+                assert!(e.to_string().contains("purchase"));
+            }
+            _ => {
+                panic!("No error??");
+            }
+        }
+
+        // Skip
+        let transactions: Vec<Transaction> = CsvReader::new(csv_1)?
+            .on_unknown_kind(UnknownKind::Skip)
+            .collect::<Result<_, _>>()?;
+        let txs: Vec<u32> = transactions.iter().map(|t| t.tx).collect();
+        assert_eq!(txs, vec![1, 3]);
+
+        // Other invalid records still fail
+        let csv_2 = PathBuf::from("resources/sample_1_with_errors.csv");
+        assert!(CsvReader::new(csv_2)?
+            .on_unknown_kind(UnknownKind::Skip)
+            .any(|t| t.is_err()));
+
+        // Notes are known
+        let kinds: Vec<TransactionKind> =
+            CsvReader::new(PathBuf::from("resources/sample_1_notes.csv"))?
+                .on_unknown_kind(UnknownKind::Skip)
+                .map(|t| t.map(|t| t.kind))
+                .collect::<Result<_, _>>()?;
+        assert_eq!(
+            kinds,
+            vec![
+                TransactionKind::Deposit,
+                TransactionKind::Note,
+                TransactionKind::Note,
+                TransactionKind::Deposit
+            ]
+        );
+
+        assert_eq!("skip".parse::<UnknownKind>(), Ok(UnknownKind::Skip));
+        assert!("ignore".parse::<UnknownKind>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn csv_read_amount_signs() -> Result<(), Box<dyn std::error::Error>> {
+        let csv_6 = PathBuf::from("resources/sample_6_amount_signs.csv");
+        let transactions: Vec<Transaction> = CsvReader::new(csv_6)?.collect::<Result<_, _>>()?;
+
+        let amounts: Vec<Option<f64>> = transactions.iter().map(|t| t.amount).collect();
+        assert_eq!(
+            amounts,
+            vec![Some(25.11), Some(25.11), Some(-25.11), Some(25.11), None]
+        );
+
+        // A negative deposit is then rejected
+        let mut accounts = crate::accounts::Accounts::new();
+        assert!(matches!(
+            accounts.handle_transaction(transactions[2].clone()),
+            Err(crate::accounts::TransactionError::InvalidAmount(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn csv_read_amount_out_of_range() -> Result<(), std::io::Error> {
+        let csv_1 = PathBuf::from("resources/sample_1_amount_out_of_range.csv");
+        let results: Vec<Result<Transaction, RecordError>> = CsvReader::new(csv_1)?.collect();
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().ok().and_then(|t| t.amount), Some(1e30));
+        for result in &results[1..] {
+            match result {
+                Err(e) => {
+                    assert!(e.to_string().contains("is out of range (f64)"), "{}", e);
+                }
+                _ => {
+                    panic!("No error??");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn csv_try_count() -> Result<(), Box<dyn std::error::Error>> {
+        let csv_1 = PathBuf::from("resources/sample_1.csv");
+        assert_eq!(CsvReader::new(csv_1)?.try_count()?, 5);
+
+        let csv_1 = PathBuf::from("resources/sample_1_with_errors.csv");
+        assert!(CsvReader::new(csv_1)?.try_count().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn csv_read_currencies() -> Result<(), Box<dyn std::error::Error>> {
+        let csv_7 = PathBuf::from("resources/sample_7_currencies.csv");
+        let transactions: Vec<Transaction> = CsvReader::new(csv_7)?.collect::<Result<_, _>>()?;
+
+        let currencies: Vec<Option<String>> = transactions
+            .iter()
+            .map(|t| t.currency.map(|c| c.to_string()))
+            .collect();
+        assert_eq!(
+            currencies,
+            vec![
+                Some("USD".to_string()),
+                Some("EUR".to_string()),
+                Some("USD".to_string()),
+                None
+            ]
+        );
+
+        assert!("EURO".parse::<Currency>().is_err());
+        assert!("E1R".parse::<Currency>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn csv_invalid_headers() {
+        for (sample, error) in [
+            (
+                "resources/sample_1_duplicate_header.csv",
+                "duplicate column: \"amount\"",
+            ),
+            (
+                "resources/sample_1_misspelled_header.csv",
+                "unexpected column: \"amout\"",
+            ),
+            (
+                "resources/sample_1_missing_amount.csv",
+                "missing column: \"amount\"",
+            ),
+        ] {
+            match CsvReader::new(PathBuf::from(sample)) {
+                Err(e) => {
+                    assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+                    assert!(e.to_string().contains(error), "{}", e);
+                }
+                Ok(_) => {
+                    panic!("No error??");
+                }
+            }
+        }
+
+        assert!(validate_headers(["type", "client_id", "tx", "amt"], &[]).is_ok());
+        assert!(validate_headers(["type", "client", "client_id", "tx", "amount"], &[]).is_err());
+        assert_eq!(
+            validate_headers(["type", "client", "tx"], &[]),
+            Err("missing column: \"amount\"".to_string())
+        );
+        assert_eq!(
+            validate_headers(["client", "type"], &[]),
+            Err("missing columns: \"tx\", \"amount\"".to_string())
+        );
+        let extra_columns = ["region".to_string()];
+        assert!(
+            validate_headers(["type", "client", "tx", "amount", "region"], &extra_columns).is_ok()
+        );
+        assert!(validate_headers(
+            ["type", "client", "tx", "amount", "region", "region"],
+            &extra_columns
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn sort_transactions_order() {
+        let mut transactions = vec![
+            Transaction::new(TransactionKind::Dispute, 1, 1, None),
+            Transaction::new(TransactionKind::Chargeback, 1, 1, None),
+            Transaction::new(TransactionKind::Deposit, 2, 2, Some(1.0)),
+            Transaction::new(TransactionKind::Deposit, 1, 1, Some(1.0)),
+        ];
+        sort_transactions(&mut transactions);
+        let order: Vec<(TransactionKind, u32)> =
+            transactions.iter().map(|t| (t.kind, t.tx)).collect();
+        assert_eq!(
+            order,
+            vec![
+                (TransactionKind::Deposit, 1),
+                (TransactionKind::Dispute, 1),
+                (TransactionKind::Chargeback, 1),
+                (TransactionKind::Deposit, 2),
+            ]
+        );
+
+        // Repeated cycle on a tx: input order is kept
+        let mut transactions = vec![
+            Transaction::new(TransactionKind::Dispute, 1, 1, None),
+            Transaction::new(TransactionKind::Resolve, 1, 1, None),
+            Transaction::new(TransactionKind::Dispute, 1, 1, None),
+            Transaction::new(TransactionKind::Deposit, 1, 1, Some(1.0)),
+        ];
+        sort_transactions(&mut transactions);
+        let kinds: Vec<TransactionKind> = transactions.iter().map(|t| t.kind).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                TransactionKind::Deposit,
+                TransactionKind::Dispute,
+                TransactionKind::Resolve,
+                TransactionKind::Dispute,
+            ]
+        );
+
+        // Timestamps first
+        let mut transactions = vec![
+            Transaction::new(TransactionKind::Deposit, 1, 1, Some(1.0)).with_timestamp(20),
+            Transaction::new(TransactionKind::Deposit, 1, 2, Some(1.0)).with_timestamp(10),
+        ];
+        sort_transactions(&mut transactions);
+        assert_eq!(transactions[0].tx, 2);
+    }
+
+    #[test]
+    fn open_input_file_extension() -> Result<(), Box<dyn std::error::Error>> {
+        assert_eq!(
+            Compression::of(Path::new("resources/sample_1.csv")),
+            Compression::None
+        );
+        assert_eq!(Compression::of(Path::new("sample_1")), Compression::None);
+        assert_eq!(
+            Compression::of(Path::new("sample_1.csv.GZ")),
+            Compression::Gzip
+        );
+
+        // Same transactions, plain or gzip
+        let transactions: Vec<Transaction> =
+            CsvReader::new(PathBuf::from("resources/sample_1.csv"))?.collect::<Result<_, _>>()?;
+        assert_eq!(transactions.len(), 5);
+        let gzip_transactions: Vec<Transaction> =
+            CsvReader::new(PathBuf::from("resources/sample_1.csv.gz"))?
+                .collect::<Result<_, _>>()?;
+        assert_eq!(gzip_transactions, transactions);
+        Ok(())
+    }
+
+    #[test]
+    fn csv_read_decimal_comma() -> Result<(), Box<dyn std::error::Error>> {
+        let csv = b"type;client;tx;amount\ndeposit;1;1;25,11\nwithdrawal;1;2;1,5\ndispute;1;1;\n";
+        let transactions: Vec<Transaction> =
+            CsvReader::from_reader_with_format(csv.as_slice(), CsvFormat::decimal_comma())?
+                .collect::<Result<_, _>>()?;
+        let amounts: Vec<Option<f64>> = transactions.iter().map(|t| t.amount).collect();
+        assert_eq!(amounts, vec![Some(25.11), Some(1.5), None]);
+        assert_eq!(transactions[1].kind, TransactionKind::Withdrawal);
+
+        // Without the format: a single (unknown) column
+        assert!(CsvReader::from_reader(csv.as_slice()).is_err());
+        // A decimal comma with comma delimited fields is ambiguous
+        let format = CsvFormat {
+            decimal_comma: true,
+            ..CsvFormat::default()
+        };
+        assert!(CsvReader::from_reader_with_format(csv.as_slice(), format).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn csv_read_invalid_utf8() -> Result<(), Box<dyn std::error::Error>> {
+        let csv_1 = PathBuf::from("resources/sample_1_invalid_utf8.csv");
+
+        // Default: the record is invalid
+        let results: Vec<Result<Transaction, RecordError>> =
+            CsvReader::new(csv_1.clone())?.collect();
+        assert_eq!(results.len(), 5);
+        assert!(results[2].is_err());
+
+        // Skipped
+        let transactions: Vec<Transaction> = CsvReader::new(csv_1)?
+            .skip_invalid_utf8(true)
+            .collect::<Result<_, _>>()?;
+        let txs: Vec<u32> = transactions.iter().map(|t| t.tx).collect();
+        assert_eq!(txs, vec![1, 2, 4, 5]);
+        Ok(())
+    }
+
+    #[test]
+    fn input_files_dir() -> Result<(), Box<dyn std::error::Error>> {
+        let file = Path::new("resources/sample_1.csv");
+        assert_eq!(input_files(file)?, vec![file.to_path_buf()]);
+
+        let dir = std::env::temp_dir().join(format!("toy_pay_input_files_{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub.csv"))?;
+        for name in ["b.csv", "a.csv", "notes.txt", "c.csv.gz"] {
+            std::fs::write(dir.join(name), "type,client,tx,amount\n")?;
+        }
+        let files = input_files(&dir);
+        std::fs::remove_dir_all(&dir)?;
+        assert_eq!(files?, vec![dir.join("a.csv"), dir.join("b.csv")]);
+        Ok(())
+    }
+
+    #[test]
+    fn csv_read_extra_columns() -> Result<(), Box<dyn std::error::Error>> {
+        let csv = b"type,client,tx,amount,region\n\
+                    deposit,1,1,1.0,EU\n\
+                    deposit,2,2,2.0,\n";
+
+        // Unknown column by default
+        assert!(CsvReader::from_reader(csv.as_slice()).is_err());
+
+        let format = CsvFormat {
+            extra_columns: vec!["region".to_string(), "segment".to_string()],
+            ..CsvFormat::default()
+        };
+        let transactions: Vec<Transaction> =
+            CsvReader::from_reader_with_format(csv.as_slice(), format)?
+                .collect::<Result<_, _>>()?;
+        assert_eq!(
+            transactions[0].extras,
+            vec![("region".to_string(), "EU".to_string())]
+        );
+        assert_eq!(
+            transactions[1].extras,
+            vec![("region".to_string(), "".to_string())]
+        );
+
+        // A line break is only allowed in an extra column
+        let csv = b"type,client,tx,amount,region\n\
+                    deposit,1,1,1.0,\"EU\nwest\"\n\
+                    deposit,2,\"2\n2\",2.0,EU\n";
+        let format = CsvFormat {
+            extra_columns: vec!["region".to_string()],
+            ..CsvFormat::default()
+        };
+        let transactions: Vec<Result<Transaction, RecordError>> =
+            CsvReader::from_reader_with_format(csv.as_slice(), format)?.collect();
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(
+            transactions[0].as_ref().map_err(|e| e.to_string())?.extras,
+            vec![("region".to_string(), "EU\nwest".to_string())]
+        );
+        assert!(transactions[1]
+            .as_ref()
+            .is_err_and(|e| e.to_string().contains("line break in column \"tx\"")));
+        Ok(())
+    }
 }
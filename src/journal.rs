@@ -0,0 +1,109 @@
+// std
+use std::io::Write;
+
+// third party libs
+use serde::Serialize;
+
+// internal
+use crate::accounts::{serialize_amount, TransactionOutcome};
+use crate::csv_reader::{Currency, TransactionKind};
+
+/// Append-only csv journal of applied transactions, with the resulting balances
+///
+/// Unlike the per-client history (see [crate::accounts::Accounts::history]), the journal
+/// is global and written as transactions are processed (csv writer is buffered).
+#[derive(Debug)]
+pub struct Journal<W: Write> {
+    wtr: csv::Writer<W>,
+}
+
+/// A journal line (header: type, client, currency, tx, available, held, total, locked)
+#[derive(Debug, Serialize)]
+struct JournalLine {
+    #[serde(rename = "type")]
+    kind: TransactionKind,
+    client: u16,
+    currency: Option<Currency>,
+    tx: u32,
+    #[serde(serialize_with = "serialize_amount")]
+    available: f64,
+    #[serde(serialize_with = "serialize_amount")]
+    held: f64,
+    #[serde(serialize_with = "serialize_amount")]
+    total: f64,
+    locked: bool,
+}
+
+impl<W: Write> Journal<W> {
+    pub fn new(into: W) -> Self {
+        Self {
+            wtr: csv::Writer::from_writer(into),
+        }
+    }
+
+    /// Append an applied transaction (a skipped replay or an ignored note is not journaled)
+    pub fn record(&mut self, tx: u32, outcome: &TransactionOutcome) -> Result<(), csv::Error> {
+        if outcome.replay || outcome.kind == TransactionKind::Note {
+            return Ok(());
+        }
+
+        self.wtr.serialize(JournalLine {
+            kind: outcome.kind,
+            client: outcome.client,
+            currency: outcome.currency,
+            tx,
+            available: outcome.available,
+            held: outcome.held,
+            total: outcome.total,
+            locked: outcome.locked,
+        })
+    }
+
+    /// Flush buffered lines
+    pub fn flush(&mut self) -> Result<(), csv::Error> {
+        self.wtr.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accounts::Accounts;
+    use crate::csv_reader::Transaction;
+    use std::error::Error;
+
+    #[test]
+    fn journal_applied_transactions() -> Result<(), Box<dyn Error>> {
+        let mut accounts = Accounts::new();
+        let mut output: Vec<u8> = Vec::new();
+        let mut journal = Journal::new(&mut output);
+
+        let deposit = Transaction::new(TransactionKind::Deposit, 1, 1, Some(2.0));
+        for transaction in [
+            deposit.clone(),
+            Transaction::new(TransactionKind::Withdrawal, 1, 2, Some(5.0)), // rejected
+            Transaction::new(TransactionKind::Deposit, 2, 3, Some(1.0)),
+            deposit, // replay
+            Transaction::new(TransactionKind::Dispute, 1, 1, None),
+            Transaction::new(TransactionKind::Chargeback, 1, 1, None),
+        ] {
+            let tx = transaction.tx;
+            if let Ok(outcome) = accounts.handle_transaction_with_outcome(transaction) {
+                journal.record(tx, &outcome)?;
+            }
+        }
+        journal.flush()?;
+        drop(journal);
+
+        assert_eq!(
+            std::str::from_utf8(&output)?,
+            "type,client,currency,tx,available,held,total,locked\n\
+             deposit,1,,1,2.0000,0.0000,2.0000,false\n\
+             deposit,2,,3,1.0000,0.0000,1.0000,false\n\
+             dispute,1,,1,0.0000,2.0000,2.0000,false\n\
+             chargeback,1,,1,0.0000,0.0000,0.0000,true\n"
+        );
+        Ok(())
+    }
+}
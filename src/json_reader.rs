@@ -0,0 +1,315 @@
+//! JSON transactions input (require feature: json)
+//!
+//! A JSON array of flat transaction objects, or a stream of them (NDJSON, e.g. 1 object per line):
+//! `{"type": "deposit", "client": 1, "tx": 1, "amount": 1.0}`. Object keys are the csv columns
+//! (see [CsvReader](crate::csv_reader::CsvReader)) & a `null` value is an empty csv field.
+//!
+//! Note: an NDJSON stream is read object by object, but an array is read at once
+
+// std
+use std::io::{self, BufRead, BufReader, Read};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::vec;
+
+// third party libs
+use csv::ByteRecord;
+use serde::Deserialize;
+use serde_json::de::{IoRead, StreamDeserializer};
+use serde_json::Value;
+
+// internal
+use crate::csv_reader::{
+    lenient, open_input_file, try_count, validate_headers, RecordError, Transaction,
+};
+
+/// Format of an input file (see `--input-format`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InputFormat {
+    /// Csv (see [CsvReader](crate::csv_reader::CsvReader))
+    #[default]
+    Csv,
+    /// JSON array or NDJSON stream (see [JsonReader])
+    Json,
+}
+
+impl InputFormat {
+    /// Format of a file from its extension: `.json` & `.ndjson` are JSON, anything else is csv
+    pub fn of(path: &Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some(e) if e.eq_ignore_ascii_case("json") || e.eq_ignore_ascii_case("ndjson") => {
+                InputFormat::Json
+            }
+            _ => InputFormat::Csv,
+        }
+    }
+}
+
+impl FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(InputFormat::Csv),
+            "json" => Ok(InputFormat::Json),
+            _ => Err(format!("Invalid input format: {}", s)),
+        }
+    }
+}
+
+/// Where the reader is in its input
+enum Input<R> {
+    /// Nothing read yet: an array or a stream of objects
+    Start(BufReader<R>),
+    /// Values of an array (an array is read at once)
+    Array(vec::IntoIter<Value>),
+    /// A stream of values (NDJSON), read one by one
+    Stream(StreamDeserializer<'static, IoRead<BufReader<R>>, Value>),
+    /// End of input (or after a syntax error)
+    Done,
+}
+
+/// Our JSON reader & iterator (over `Transaction`), yielding the same items as a
+/// [CsvReader](crate::csv_reader::CsvReader): each object is deserialized with the same
+/// [Transaction] Deserialize derive, after checking its keys like a csv header
+///
+/// Note: a JSON syntax error ends the iteration (after being returned)
+pub struct JsonReader<R = Box<dyn Read>> {
+    input: Input<R>,
+}
+
+impl JsonReader {
+    /// Read a JSON file (see [open_input_file] for supported files)
+    pub fn new(json_path: PathBuf) -> Result<Self, io::Error> {
+        Ok(Self::from_reader(open_input_file(&json_path)?))
+    }
+}
+
+impl<R: Read> JsonReader<R> {
+    /// Read JSON from any source (nothing is read until the first transaction)
+    pub fn from_reader(reader: R) -> Self {
+        JsonReader {
+            input: Input::Start(BufReader::new(reader)),
+        }
+    }
+
+    /// Drain the reader, counting transactions (stop at the first invalid object)
+    pub fn try_count(self) -> Result<usize, RecordError> {
+        try_count(self)
+    }
+
+    /// Iterate over valid transactions only: invalid objects are logged (warn) then skipped
+    pub fn into_lenient(self) -> impl Iterator<Item = Transaction> {
+        lenient(self)
+    }
+
+    /// Next value of the input (None at its end)
+    #[doc(hidden)]
+    fn next_value(&mut self) -> Option<Result<Value, serde_json::Error>> {
+        if let Input::Start(_) = self.input {
+            if let Input::Start(rdr) = mem::replace(&mut self.input, Input::Done) {
+                match start(rdr) {
+                    Ok(input) => self.input = input,
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+        }
+        match &mut self.input {
+            Input::Array(values) => values.next().map(Ok),
+            Input::Stream(values) => values.next(),
+            Input::Start(_) | Input::Done => None,
+        }
+    }
+}
+
+/// An array (first non whitespace byte: `[`) or a stream of values
+fn start<R: Read>(mut rdr: BufReader<R>) -> Result<Input<R>, serde_json::Error> {
+    let first = loop {
+        let buf = rdr.fill_buf().map_err(serde_json::Error::io)?;
+        match buf.iter().position(|b| !b.is_ascii_whitespace()) {
+            Some(i) => break Some(buf[i]),
+            None if buf.is_empty() => break None,
+            None => {
+                let len = buf.len();
+                rdr.consume(len);
+            }
+        }
+    };
+
+    let mut de = serde_json::Deserializer::from_reader(rdr);
+    if first == Some(b'[') {
+        let values = Vec::<Value>::deserialize(&mut de)?;
+        // Only whitespace can follow an array
+        de.end()?;
+        Ok(Input::Array(values.into_iter()))
+    } else {
+        Ok(Input::Stream(de.into_iter()))
+    }
+}
+
+impl<R: Read> Iterator for JsonReader<R> {
+    type Item = Result<Transaction, RecordError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_value()? {
+            Ok(value) => Some(to_transaction(value)),
+            Err(e) => {
+                self.input = Input::Done;
+                let error = io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("JSON syntax error: {}", e),
+                );
+                Some(Err(RecordError::new(error.into(), &ByteRecord::new())))
+            }
+        }
+    }
+}
+
+/// Deserialize an object, its keys checked like a csv header
+fn to_transaction(value: Value) -> Result<Transaction, RecordError> {
+    let record = ByteRecord::from(vec![value.to_string()]);
+    let invalid = |message: String| {
+        let error = io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("JSON object: {}", message),
+        );
+        RecordError::new(error.into(), &record)
+    };
+
+    match &value {
+        Value::Object(object) => {
+            validate_headers(object.keys().map(String::as_str), &[]).map_err(invalid)?
+        }
+        _ => return Err(invalid("not an object".to_string())),
+    }
+    serde_json::from_value(value).map_err(|e| invalid(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::csv_reader::{CsvReader, TransactionKind};
+
+    #[test]
+    fn json_read_valid_sample() -> Result<(), Box<dyn std::error::Error>> {
+        let transactions: Vec<Transaction> =
+            JsonReader::new(PathBuf::from("resources/sample_1.json"))?.collect::<Result<_, _>>()?;
+        let expected: Vec<Transaction> =
+            CsvReader::new(PathBuf::from("resources/sample_1.csv"))?.collect::<Result<_, _>>()?;
+        assert_eq!(transactions, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn json_read_ndjson() -> Result<(), Box<dyn std::error::Error>> {
+        let json = "{\"type\": \"deposit\", \"client\": 1, \"tx\": 1, \"amount\": \"2.5\"}\n\
+                    {\"tx\":2,\"client\":1,\"type\":\"dispute\",\"amount\":null,\"timestamp\":7}\r\n\
+                    {\"type\": \"deposit\", \"client_id\": 2, \"transaction_id\": 3, \"amt\": 1e1, \"currency\": \"USD\"}\n";
+        let transactions: Vec<Transaction> =
+            JsonReader::from_reader(json.as_bytes()).collect::<Result<_, _>>()?;
+        assert_eq!(
+            transactions,
+            vec![
+                Transaction::new(TransactionKind::Deposit, 1, 1, Some(2.5)),
+                Transaction::new(TransactionKind::Dispute, 1, 2, None).with_timestamp(7),
+                Transaction::new(TransactionKind::Deposit, 2, 3, Some(10.0))
+                    .with_currency("USD".parse()?),
+            ]
+        );
+
+        // Empty inputs
+        assert_eq!(JsonReader::from_reader(" \n".as_bytes()).count(), 0);
+        assert_eq!(JsonReader::from_reader(" [ ]\n".as_bytes()).count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn json_read_with_errors() -> Result<(), Box<dyn std::error::Error>> {
+        // An invalid transaction does not stop the iteration
+        let json = "[{\"type\": \"deposit\", \"client\": 1, \"tx\": 1, \"amount\": 1.0},\n\
+                    {\"type\": \"deposit\", \"client\": 1, \"tx\": 2},\n\
+                    {\"type\": \"deposit\", \"client\": -1, \"tx\": 3, \"amount\": 1.0},\n\
+                    {\"type\": \"deposit\", \"client\": 1, \"tx\": 4, \"amount\": 1.0}]";
+        let transactions: Vec<Result<Transaction, RecordError>> =
+            JsonReader::from_reader(json.as_bytes()).collect();
+        assert_eq!(transactions.len(), 4);
+        assert!(transactions[0].is_ok());
+        assert!(transactions[1]
+            .as_ref()
+            .is_err_and(|e| e.to_string().contains("missing column: \"amount\"")));
+        assert!(transactions[2]
+            .as_ref()
+            .is_err_and(|e| e.to_string().contains("client id -1 is out of range")));
+        assert!(transactions[3].is_ok());
+        assert_eq!(
+            JsonReader::from_reader(json.as_bytes())
+                .into_lenient()
+                .count(),
+            2
+        );
+
+        // A value of another type, nested or not, is an invalid transaction too
+        let json = "[1, {\"type\": [\"deposit\"], \"client\": 1, \"tx\": 1, \"amount\": 1.0}]";
+        let transactions: Vec<Result<Transaction, RecordError>> =
+            JsonReader::from_reader(json.as_bytes()).collect();
+        assert_eq!(transactions.len(), 2);
+        assert!(transactions[0]
+            .as_ref()
+            .is_err_and(|e| e.to_string().contains("JSON object: not an object")));
+        assert!(transactions[1].as_ref().is_err_and(|e| e
+            .to_string()
+            .contains("JSON object: invalid type: sequence")));
+
+        // ... unlike a syntax error
+        for (json, error) in [
+            ("[{\"type\": \"deposit\"", "EOF while parsing an object"),
+            ("{\"type\" 1}", "expected `:`"),
+            ("[] []", "trailing characters"),
+            ("{\"tx\": 1-2}", "expected `,` or `}`"),
+            ("{\"tx\": nul}", "expected ident"),
+            ("{\"type\": \"\\x\"}", "invalid escape"),
+        ] {
+            let mut reader = JsonReader::from_reader(json.as_bytes());
+            match reader.next() {
+                Some(Err(e)) => {
+                    assert!(
+                        e.to_string().contains("JSON syntax error"),
+                        "{}: {}",
+                        json,
+                        e
+                    );
+                    assert!(e.to_string().contains(error), "{}: {}", json, e);
+                }
+                r => {
+                    panic!("Unexpected result for {}: {:?}", json, r);
+                }
+            }
+            assert!(reader.next().is_none());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn json_read_string_escapes() -> Result<(), Box<dyn std::error::Error>> {
+        let json = "{\"type\": \"d\\u0065posit\", \"client\": 1, \"tx\": 1, \"amount\": \"1.0\", \"currency\": \"\\u0055SD\"}";
+        let transactions: Vec<Transaction> =
+            JsonReader::from_reader(json.as_bytes()).collect::<Result<_, _>>()?;
+        assert_eq!(
+            transactions,
+            vec![Transaction::new(TransactionKind::Deposit, 1, 1, Some(1.0))
+                .with_currency("USD".parse()?)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn json_input_format() {
+        assert_eq!(InputFormat::of(Path::new("foo.json")), InputFormat::Json);
+        assert_eq!(InputFormat::of(Path::new("foo.NDJSON")), InputFormat::Json);
+        assert_eq!(InputFormat::of(Path::new("foo.csv")), InputFormat::Csv);
+        assert_eq!(InputFormat::of(Path::new("foo")), InputFormat::Csv);
+        assert_eq!("json".parse(), Ok(InputFormat::Json));
+        assert!("xml".parse::<InputFormat>().is_err());
+    }
+}
@@ -0,0 +1,14 @@
+//! A toy payment library: read transactions from a csv file and apply them to client accounts
+//!
+//! Used by the `homework_toy_pay` cli program (see `main.rs`)
+
+pub mod accounts;
+pub mod csv_reader;
+pub mod journal;
+#[cfg(feature = "json")]
+pub mod json_reader;
+#[cfg(feature = "parallel")]
+pub mod parallel;
+pub mod stats;
+#[cfg(any(test, feature = "strategies"))]
+pub mod strategies;
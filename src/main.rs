@@ -1,68 +1,1483 @@
 //! A toy payment rust cli program
 //! that you can run with: `cargo run -- resources/sample1_csv > output.csv`
 
-mod accounts;
-mod csv_reader;
-
 // std
-use std::path::PathBuf;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+#[cfg(feature = "net")]
+use std::net::TcpListener;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 // third party lib
-use log::{debug, error};
+use log::{debug, error, info, warn};
 // internal
-use crate::accounts::{Accounts, TransactionError};
-use crate::csv_reader::CsvReader;
+use homework_toy_pay::accounts::{
+    Accounts, AccountsSnapshot, QuoteStyle, TransactionError, TxIdScope,
+};
+use homework_toy_pay::csv_reader::{
+    input_files, lenient, open_input_file, sort_transactions, try_count, CsvFormat, CsvReader,
+    RecordError, Transaction, UnknownKind,
+};
+use homework_toy_pay::journal::Journal;
+#[cfg(feature = "json")]
+use homework_toy_pay::json_reader::{InputFormat, JsonReader};
+use homework_toy_pay::stats::{RunReport, RunStats};
 
 /// Our main app error (thanks to thiserror crate)
 #[derive(Debug, thiserror::Error)]
 enum AppError {
     #[error("i/o error: {0}")]
-    IO(#[from] std::io::Error),
+    IO(#[from] io::Error),
     #[error("csv error: {0}")]
     Csv(#[from] csv::Error),
+    #[error("csv error: {0}")]
+    CsvRecord(#[from] RecordError),
     #[error("tx error: {0}")]
     Transaction(#[from] TransactionError),
+    #[error("invalid argument: {0}")]
+    Args(String),
+    #[error("{0} locked account(s)")]
+    LockedAccounts(usize),
+    #[error("output mismatch: {0}")]
+    Verify(String),
+}
+
+impl AppError {
+    /// Process exit code (see Readme)
+    fn exit_code(&self) -> i32 {
+        match self {
+            AppError::Args(_) => 1,
+            // e.g. invalid csv header
+            AppError::IO(e) if e.kind() == io::ErrorKind::InvalidData => 3,
+            AppError::IO(_) => 2,
+            AppError::Csv(_) | AppError::CsvRecord(_) => 3,
+            AppError::Transaction(_) => 4,
+            AppError::LockedAccounts(_) => 5,
+            AppError::Verify(_) => 6,
+        }
+    }
+}
+
+/// Cli options (see [parse_args])
+#[derive(Debug, Default, PartialEq)]
+struct CliOptions {
+    /// Path to the csv file (or directory of csv files, see [input_files]) to process
+    csv_path: PathBuf,
+    /// Max number of transactions kept in memory (see [homework_toy_pay::accounts::AccountsBuilder::max_retained_tx])
+    max_retained_tx: Option<usize>,
+    /// Output only locked accounts
+    only_locked: bool,
+    /// Reject disputes on withdrawals (see [homework_toy_pay::accounts::AccountsBuilder::strict])
+    strict: bool,
+    /// Write run counters (Prometheus text format) to this file
+    metrics_path: Option<PathBuf>,
+    /// Skip (and log) invalid records & rejected transactions instead of stopping
+    skip_errors: bool,
+    /// Number of decimal places of amounts in output (see [homework_toy_pay::accounts::Accounts::set_scale])
+    scale: Option<usize>,
+    /// Tx ids are only unique per client (see [homework_toy_pay::accounts::AccountsBuilder::per_client_tx])
+    per_client_tx: bool,
+    /// Fail (after output) if any account ends up locked
+    fail_on_lock: bool,
+    /// Write a journal of applied transactions (csv) to this file
+    journal_path: Option<PathBuf>,
+    /// Skip transactions with a lower tx id
+    min_tx: Option<u32>,
+    /// Skip transactions with a greater tx id
+    max_tx: Option<u32>,
+    /// Only output the number of transactions in the csv file
+    count: bool,
+    /// Allow disputes on withdrawals (see [homework_toy_pay::accounts::AccountsBuilder::dispute_withdrawals])
+    dispute_withdrawals: bool,
+    /// Print progress (on stderr) every [PROGRESS_INTERVAL] processed transactions
+    progress: bool,
+    /// Read csv from the first tcp connection on this address (instead of the csv file)
+    #[cfg(feature = "net")]
+    listen: Option<String>,
+    /// Format of the input(s) (None: from the file extension, see [InputFormat::of])
+    #[cfg(feature = "json")]
+    input_format: Option<InputFormat>,
+    /// What to do with a csv record with an unknown type (fail or skip)
+    on_unknown_kind: UnknownKind,
+    /// Credit interest at this rate after processing (see [homework_toy_pay::accounts::Accounts::accrue_interest])
+    interest: Option<f64>,
+    /// Output tx ids of processed transactions (instead of accounts)
+    list_tx: bool,
+    /// Print platform totals (on stderr) after processing
+    summary: bool,
+    /// Append a summary comment line (`# ...`) after the csv output
+    footer: bool,
+    /// Only a deposit creates an account (see [homework_toy_pay::accounts::AccountsBuilder::require_known_client])
+    require_known_client: bool,
+    /// A chargeback of a non disputed tx disputes it first (see [homework_toy_pay::accounts::AccountsBuilder::auto_dispute_on_chargeback])
+    auto_dispute_on_chargeback: bool,
+    /// A duplicated resolve / chargeback is skipped (see [homework_toy_pay::accounts::AccountsBuilder::idempotent])
+    idempotent: bool,
+    /// A chargeback freezes all accounts of its client (see [homework_toy_pay::accounts::AccountsBuilder::freeze_on_chargeback])
+    freeze_on_chargeback: bool,
+    /// Quoting of fields in csv output (see [homework_toy_pay::accounts::AccountsBuilder::quote_style])
+    quote_style: QuoteStyle,
+    /// Output amounts as integer minor units (see [homework_toy_pay::accounts::AccountsBuilder::minor_units])
+    minor_units: bool,
+    /// Extra csv columns echoed in output, per client (see [homework_toy_pay::accounts::AccountsBuilder::metadata_columns])
+    extra_columns: Vec<String>,
+    /// Leave empty accounts out of output (see [homework_toy_pay::accounts::AccountsBuilder::suppress_empty])
+    suppress_empty: bool,
+    /// End output lines with CRLF (see [homework_toy_pay::accounts::AccountsBuilder::crlf])
+    crlf: bool,
+    /// Read `;` delimited csv with `,` as decimal separator (see [CsvFormat::decimal_comma])
+    decimal_comma: bool,
+    /// Skip (and log) csv records with invalid UTF-8 (see [CsvReader::skip_invalid_utf8])
+    lossy_utf8: bool,
+    /// Only read the first N transactions
+    limit: Option<usize>,
+    /// Read all transactions then apply them sorted (see [homework_toy_pay::csv_reader::sort_transactions])
+    sorted: bool,
+    /// Compare output with this expected csv file (instead of writing it)
+    verify_path: Option<PathBuf>,
+    /// Scope of deposit & withdrawal tx ids uniqueness (see [homework_toy_pay::accounts::AccountsBuilder::tx_id_scope])
+    tx_id_scope: TxIdScope,
+    /// State file to resume from & state file to save to (see [AccountsSnapshot::write_csv])
+    state: Option<(PathBuf, PathBuf)>,
+}
+
+impl CliOptions {
+    /// Inclusive range of tx ids to apply (see `--min-tx` & `--max-tx`)
+    fn tx_range(&self) -> RangeInclusive<u32> {
+        self.min_tx.unwrap_or(u32::MIN)..=self.max_tx.unwrap_or(u32::MAX)
+    }
+}
+
+/// Parse cli arguments (program name excluded):
+/// `[--max-retained-tx N] [--only-locked] [--strict] [--metrics PATH] [--skip-errors] [--scale N] [--per-client-tx] [--fail-on-lock] [--journal PATH] [--min-tx TX] [--max-tx TX] [--count] [--dispute-withdrawals] [--progress] [--on-unknown-kind fail|skip] [--interest RATE] [--list-tx] [--summary] [--footer] [--require-known-client|--no-auto-create] [--auto-dispute-on-chargeback] [--freeze-on-chargeback] [--idempotent] [--quote always|necessary|never] [--minor-units] [--extra-columns COL1,COL2] [--suppress-empty] [--crlf] [--decimal-comma] [--lossy-utf8] [--limit N] [--sorted] [--verify EXPECTED] [--state IN OUT] [--tx-id-scope global|file] CSV_PATH`
+///
+/// With the `net` feature, `--listen ADDR` can be given instead of CSV_PATH
+///
+/// With the `json` feature, `--input-format csv|json` selects the input format
+fn parse_args<I>(args: I) -> Result<CliOptions, String>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut options = CliOptions::default();
+    let mut csv_path: Option<PathBuf> = None;
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--max-retained-tx" => {
+                options.max_retained_tx = Some(parse_value(&arg, args.next())?);
+            }
+            "--only-locked" => {
+                options.only_locked = true;
+            }
+            "--strict" => {
+                options.strict = true;
+            }
+            "--metrics" => {
+                options.metrics_path = Some(parse_value(&arg, args.next())?);
+            }
+            "--skip-errors" => {
+                options.skip_errors = true;
+            }
+            "--scale" => {
+                options.scale = Some(parse_value(&arg, args.next())?);
+            }
+            "--per-client-tx" => {
+                options.per_client_tx = true;
+            }
+            "--fail-on-lock" => {
+                options.fail_on_lock = true;
+            }
+            "--journal" => {
+                options.journal_path = Some(parse_value(&arg, args.next())?);
+            }
+            "--min-tx" => {
+                options.min_tx = Some(parse_value(&arg, args.next())?);
+            }
+            "--max-tx" => {
+                options.max_tx = Some(parse_value(&arg, args.next())?);
+            }
+            "--count" => {
+                options.count = true;
+            }
+            "--dispute-withdrawals" => {
+                options.dispute_withdrawals = true;
+            }
+            "--progress" => {
+                options.progress = true;
+            }
+            "--on-unknown-kind" => {
+                options.on_unknown_kind = parse_value(&arg, args.next())?;
+            }
+            "--interest" => {
+                options.interest = Some(parse_value(&arg, args.next())?);
+            }
+            "--list-tx" => {
+                options.list_tx = true;
+            }
+            "--summary" => {
+                options.summary = true;
+            }
+            "--footer" => {
+                options.footer = true;
+            }
+            "--require-known-client" | "--no-auto-create" => {
+                options.require_known_client = true;
+            }
+            "--auto-dispute-on-chargeback" => {
+                options.auto_dispute_on_chargeback = true;
+            }
+            "--freeze-on-chargeback" => {
+                options.freeze_on_chargeback = true;
+            }
+            "--idempotent" => {
+                options.idempotent = true;
+            }
+            "--quote" => {
+                options.quote_style = parse_value(&arg, args.next())?;
+            }
+            "--minor-units" => {
+                options.minor_units = true;
+            }
+            "--extra-columns" => {
+                let columns: String = parse_value(&arg, args.next())?;
+                options.extra_columns = columns
+                    .split(',')
+                    .map(|c| c.trim().to_string())
+                    .filter(|c| !c.is_empty())
+                    .collect();
+            }
+            "--suppress-empty" => {
+                options.suppress_empty = true;
+            }
+            "--crlf" => {
+                options.crlf = true;
+            }
+            "--decimal-comma" => {
+                options.decimal_comma = true;
+            }
+            "--lossy-utf8" => {
+                options.lossy_utf8 = true;
+            }
+            "--limit" => {
+                options.limit = Some(parse_value(&arg, args.next())?);
+            }
+            "--sorted" => {
+                options.sorted = true;
+            }
+            "--verify" => {
+                options.verify_path = Some(parse_value(&arg, args.next())?);
+            }
+            "--tx-id-scope" => {
+                options.tx_id_scope = parse_value(&arg, args.next())?;
+            }
+            "--state" => {
+                let state_in = parse_value(&arg, args.next())?;
+                let state_out = parse_value(&arg, args.next())?;
+                options.state = Some((state_in, state_out));
+            }
+            #[cfg(feature = "net")]
+            "--listen" => {
+                options.listen = Some(parse_value(&arg, args.next())?);
+            }
+            #[cfg(feature = "json")]
+            "--input-format" => {
+                options.input_format = Some(parse_value(&arg, args.next())?);
+            }
+            _ if arg.starts_with("--") => {
+                return Err(format!("Unknown option: {}", arg));
+            }
+            _ => {
+                if csv_path.replace(PathBuf::from(&arg)).is_some() {
+                    return Err(format!("Unexpected argument: {}", arg));
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "net")]
+    if options.listen.is_some() {
+        if let Some(csv_path) = csv_path {
+            return Err(format!("Unexpected argument: {}", csv_path.display()));
+        }
+        return Ok(options);
+    }
+
+    options.csv_path = csv_path.ok_or_else(|| "Please provide a csv file path".to_string())?;
+    Ok(options)
+}
+
+/// Parse the value of a cli option (e.g. N for `--max-retained-tx N`)
+fn parse_value<T: FromStr>(option: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or(format!("Missing value for option: {}", option))?;
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value for option {}: {}", option, value))
 }
 
 /// run by [main]
-fn app_main<P>(csv_path: P) -> Result<(), AppError>
+fn app_main(options: &CliOptions) -> Result<RunReport, AppError> {
+    let readers = open_inputs(options)?;
+
+    if options.count {
+        let mut count = 0;
+        for reader in readers {
+            count += try_count(reader)?;
+        }
+        write_count(count, &mut io::stdout().lock())?;
+        return Ok(RunReport {
+            processed: count,
+            ..RunReport::default()
+        });
+    }
+
+    let mut accounts = Accounts::builder()
+        .max_retained_tx(options.max_retained_tx)
+        .strict(options.strict)
+        .dispute_withdrawals(options.dispute_withdrawals)
+        .require_known_client(options.require_known_client)
+        .auto_dispute_on_chargeback(options.auto_dispute_on_chargeback)
+        .freeze_on_chargeback(options.freeze_on_chargeback)
+        .idempotent(options.idempotent)
+        .quote_style(options.quote_style)
+        .minor_units(options.minor_units)
+        .crlf(options.crlf)
+        .suppress_empty(options.suppress_empty)
+        .metadata_columns(options.extra_columns.clone())
+        .per_client_tx(options.per_client_tx)
+        .tx_id_scope(options.tx_id_scope)
+        .build();
+    if let Some(scale) = options.scale {
+        accounts.set_scale(scale).map_err(AppError::Args)?;
+    }
+    if let Some((state_in, _)) = &options.state {
+        accounts.restore(AccountsSnapshot::read_csv(File::open(state_in)?)?);
+    }
+    let mut stats = RunStats::default();
+
+    let mut journal = match &options.journal_path {
+        Some(journal_path) => Some(Journal::new(File::create(journal_path)?)),
+        None => None,
+    };
+
+    let mut progress = options
+        .progress
+        .then(|| Progress::new(PROGRESS_INTERVAL, StderrProgress));
+
+    let processed = read_and_process(
+        readers,
+        options,
+        &mut accounts,
+        &mut stats,
+        &mut journal,
+        &mut progress,
+    );
+    if let Some(journal) = journal.as_mut() {
+        journal.flush()?;
+    }
+
+    if let (Ok(()), Some(rate)) = (&processed, options.interest) {
+        accounts.accrue_interest(rate).map_err(AppError::Args)?;
+    }
+
+    stats.locked_accounts = accounts.report().iter().filter(|r| r.locked).count();
+
+    // Metrics are written even if processing stopped on an error
+    if let Some(metrics_path) = &options.metrics_path {
+        stats.write_prometheus(&mut File::create(metrics_path)?)?;
+    }
+    processed?;
+    if let Some((_, state_out)) = &options.state {
+        accounts
+            .snapshot()
+            .write_csv(&mut File::create(state_out)?)?;
+    }
+    let locked_accounts = stats.locked_accounts;
+    let report = RunReport::new(stats, &accounts);
+
+    if options.summary {
+        eprintln!("{}", report);
+        eprintln!("{}", accounts.aggregate());
+    }
+
+    if let Some(verify_path) = &options.verify_path {
+        verify_output(&accounts, options.only_locked, verify_path)?;
+    } else if options.list_tx {
+        write_tx_ids(&accounts, &mut io::stdout())?;
+    } else {
+        write_output(
+            &accounts,
+            options.only_locked,
+            options.footer,
+            &mut io::stdout(),
+        )?;
+    }
+
+    if options.fail_on_lock && locked_accounts > 0 {
+        return Err(AppError::LockedAccounts(locked_accounts));
+    }
+
+    Ok(report)
+}
+
+/// Transactions of an input (csv, or JSON with the `json` feature)
+type TransactionReader = Box<dyn Iterator<Item = Result<Transaction, RecordError>>>;
+
+/// Input csv(s): the csv file(s) (see [input_files] & [open_input_file]) or, with `--listen`,
+/// the first tcp connection (until it is closed)
+fn open_inputs(options: &CliOptions) -> Result<Vec<TransactionReader>, AppError> {
+    #[cfg(feature = "net")]
+    if let Some(addr) = &options.listen {
+        let listener = TcpListener::bind(addr)?;
+        let (stream, peer) = listener.accept()?;
+        debug!("Reading csv from {}", peer);
+        return Ok(vec![transaction_reader(Box::new(stream), None, options)?]);
+    }
+
+    let mut readers = vec![];
+    for path in input_files(&options.csv_path)? {
+        debug!("Reading csv file {}", path.display());
+        let input = open_input_file(&path)?;
+        readers.push(transaction_reader(input, Some(&path), options)?);
+    }
+    Ok(readers)
+}
+
+/// Reader of an input: csv as configured by cli options or, with the `json` feature,
+/// JSON (see `--input-format`, by default a `.json` or `.ndjson` file)
+#[cfg_attr(not(feature = "json"), allow(unused_variables))]
+fn transaction_reader(
+    input: Box<dyn Read>,
+    path: Option<&Path>,
+    options: &CliOptions,
+) -> Result<TransactionReader, AppError> {
+    #[cfg(feature = "json")]
+    if options
+        .input_format
+        .or(path.map(InputFormat::of))
+        .unwrap_or_default()
+        == InputFormat::Json
+    {
+        return Ok(Box::new(JsonReader::from_reader(input)));
+    }
+
+    let format = CsvFormat {
+        extra_columns: options.extra_columns.clone(),
+        ..if options.decimal_comma {
+            CsvFormat::decimal_comma()
+        } else {
+            CsvFormat::default()
+        }
+    };
+    Ok(Box::new(
+        CsvReader::from_reader_with_format(input, format)?
+            .on_unknown_kind(options.on_unknown_kind)
+            .skip_invalid_utf8(options.lossy_utf8),
+    ))
+}
+
+/// Write the number of transactions (see `--count`)
+fn write_count<W: Write>(count: usize, into: &mut W) -> Result<(), AppError> {
+    match writeln!(into, "{}", count).and_then(|_| into.flush()) {
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
+            debug!("Output closed: {}", e);
+        }
+        res => res?,
+    }
+    Ok(())
+}
+
+/// Write accounts as csv (all or only locked ones), followed by an optional footer line
+/// (see [footer_line])
+///
+/// A closed output (e.g. piped to `head`) is not an error: remaining lines are dropped
+fn write_output<W: Write>(
+    accounts: &Accounts,
+    only_locked: bool,
+    footer: bool,
+    into: &mut W,
+) -> Result<(), AppError> {
+    let res = if only_locked {
+        accounts.output_locked_as_csv(into)
+    } else {
+        accounts.output_as_csv(into)
+    };
+
+    match res {
+        Err(e) if matches!(e.kind(), csv::ErrorKind::Io(e) if e.kind() == io::ErrorKind::BrokenPipe) =>
+        {
+            debug!("Output closed: {}", e);
+            return Ok(());
+        }
+        res => res?,
+    }
+
+    if footer {
+        let footer_line = footer_line(accounts) + accounts.line_ending();
+        match write!(into, "{}", footer_line).and_then(|_| into.flush()) {
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
+                debug!("Output closed: {}", e);
+            }
+            res => res?,
+        }
+    }
+    Ok(())
+}
+
+/// Compare the csv output of accounts with an expected csv file, ignoring line order
+///
+/// On mismatch, the error holds a diff summary (missing & unexpected lines)
+fn verify_output(
+    accounts: &Accounts,
+    only_locked: bool,
+    expected_path: &Path,
+) -> Result<(), AppError> {
+    let mut output: Vec<u8> = Vec::new();
+    write_output(accounts, only_locked, false, &mut output)?;
+    let output = String::from_utf8_lossy(&output);
+    let expected = fs::read_to_string(expected_path)?;
+
+    let (missing, unexpected) = diff_lines(&expected, &output);
+    if missing.is_empty() && unexpected.is_empty() {
+        debug!("Output matches {}", expected_path.display());
+        return Ok(());
+    }
+
+    let mut summary = format!(
+        "{} missing line(s), {} unexpected line(s) (vs {})",
+        missing.len(),
+        unexpected.len(),
+        expected_path.display()
+    );
+    for line in missing {
+        summary.push_str(&format!("\n- {}", line));
+    }
+    for line in unexpected {
+        summary.push_str(&format!("\n+ {}", line));
+    }
+    Err(AppError::Verify(summary))
+}
+
+/// Lines of `expected` not in `actual` & lines of `actual` not in `expected` (both sorted),
+/// line order, line endings & empty lines are ignored
+fn diff_lines<'a>(expected: &'a str, actual: &'a str) -> (Vec<&'a str>, Vec<&'a str>) {
+    let sorted_lines = |text: &'a str| {
+        let mut lines: Vec<&str> = text
+            .lines()
+            .map(|l| l.trim_end_matches('\r'))
+            .filter(|l| !l.is_empty())
+            .collect();
+        lines.sort_unstable();
+        lines
+    };
+    let expected = sorted_lines(expected);
+    let actual = sorted_lines(actual);
+
+    let (mut missing, mut unexpected) = (Vec::new(), Vec::new());
+    let (mut i, mut j) = (0, 0);
+    while i < expected.len() || j < actual.len() {
+        match (expected.get(i), actual.get(j)) {
+            (Some(e), Some(a)) if e == a => {
+                i += 1;
+                j += 1;
+            }
+            (Some(e), Some(a)) if e < a => {
+                missing.push(*e);
+                i += 1;
+            }
+            (Some(e), None) => {
+                missing.push(*e);
+                i += 1;
+            }
+            (_, Some(a)) => {
+                unexpected.push(*a);
+                j += 1;
+            }
+            (None, None) => unreachable!(),
+        }
+    }
+    (missing, unexpected)
+}
+
+/// Summary of all accounts (see `--footer`), as a comment line, e.g.
+/// `# 2 clients, 1 locked, total=50.2200`
+fn footer_line(accounts: &Accounts) -> String {
+    let aggregate = accounts.aggregate();
+    format!(
+        "# {} clients, {} locked, total={:.4}",
+        aggregate.clients, aggregate.locked_count, aggregate.total
+    )
+}
+
+/// Write tx ids of retained transactions (sorted, 1 per line)
+///
+/// A closed output is not an error (see [write_output])
+fn write_tx_ids<W: Write>(accounts: &Accounts, into: &mut W) -> Result<(), AppError> {
+    let mut tx_ids: Vec<u32> = accounts.transaction_ids().collect();
+    tx_ids.sort();
+
+    let res = tx_ids
+        .iter()
+        .try_for_each(|tx| writeln!(into, "{}", tx))
+        .and_then(|_| into.flush());
+    match res {
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
+            debug!("Output closed: {}", e);
+            Ok(())
+        }
+        res => Ok(res?),
+    }
+}
+
+/// Read & apply transactions of the input files (in order, into the same accounts) as configured
+/// by cli options: (at most `--limit`, over all files) transactions are applied as read, or read
+/// first then sorted as a single set (`--sorted`), stopping on first error (unless `--skip-errors`)
+fn read_and_process<I>(
+    readers: Vec<I>,
+    options: &CliOptions,
+    accounts: &mut Accounts,
+    stats: &mut RunStats,
+    journal: &mut Option<Journal<File>>,
+    progress: &mut Option<Progress<StderrProgress>>,
+) -> Result<(), AppError>
 where
-    P: Into<PathBuf>,
+    I: Iterator<Item = Result<Transaction, RecordError>>,
 {
-    let csv_path_: PathBuf = csv_path.into();
-    // println!("csv_path: {:?}", csv_path_);
+    let tx_range = options.tx_range();
+    let mut limit = options.limit.unwrap_or(usize::MAX);
 
-    let csv_reader = CsvReader::new(csv_path_)?;
+    if options.sorted {
+        // Sorted transactions of all files are applied as those of a single file
+        accounts.start_file();
+        let transactions = read_sorted(readers, options.skip_errors, limit)?;
+        return process_lenient_or_not(
+            transactions,
+            options.skip_errors,
+            &tx_range,
+            accounts,
+            stats,
+            journal,
+            progress,
+        );
+    }
 
-    let mut accounts = Accounts::new();
+    for reader in readers {
+        accounts.start_file();
+        let mut read = 0;
+        if options.skip_errors {
+            process_lenient(
+                lenient(reader).take(limit).inspect(|_| read += 1),
+                &tx_range,
+                accounts,
+                stats,
+                journal,
+                progress,
+            )?;
+        } else {
+            process(
+                reader.take(limit).inspect(|_| read += 1),
+                &tx_range,
+                accounts,
+                stats,
+                journal,
+                progress,
+            )?;
+        }
+        limit -= read;
+    }
+    Ok(())
+}
 
-    for transaction_ in csv_reader {
-        let transaction = transaction_?;
-        debug!("Processing tx: {:?}", transaction);
-        accounts.handle_transaction(transaction)?;
+/// Read (at most `limit`, over all files) transactions (with `skip_errors`, invalid records
+/// are skipped) then sort them (see `--sorted`)
+fn read_sorted<I>(
+    readers: Vec<I>,
+    skip_errors: bool,
+    limit: usize,
+) -> Result<Vec<Transaction>, AppError>
+where
+    I: Iterator<Item = Result<Transaction, RecordError>>,
+{
+    let mut transactions: Vec<Transaction> = vec![];
+    for reader in readers {
+        let remaining = limit - transactions.len();
+        if skip_errors {
+            transactions.extend(lenient(reader).take(remaining));
+        } else {
+            for transaction in reader.take(remaining) {
+                transactions.push(transaction?);
+            }
+        }
     }
+    sort_transactions(&mut transactions);
+    Ok(transactions)
+}
 
-    let mut stdout = std::io::stdout();
-    accounts.output_as_csv(Some(&mut stdout))?;
+/// [process_lenient] or [process] (already read) transactions
+fn process_lenient_or_not(
+    transactions: Vec<Transaction>,
+    skip_errors: bool,
+    tx_range: &RangeInclusive<u32>,
+    accounts: &mut Accounts,
+    stats: &mut RunStats,
+    journal: &mut Option<Journal<File>>,
+    progress: &mut Option<Progress<StderrProgress>>,
+) -> Result<(), AppError> {
+    if skip_errors {
+        process_lenient(transactions, tx_range, accounts, stats, journal, progress)
+    } else {
+        process(
+            transactions.into_iter().map(Ok::<_, AppError>),
+            tx_range,
+            accounts,
+            stats,
+            journal,
+            progress,
+        )
+    }
+}
 
+/// Apply all transactions in tx range (stop on first error), counting them in stats
+fn process<I, E>(
+    transactions: I,
+    tx_range: &RangeInclusive<u32>,
+    accounts: &mut Accounts,
+    stats: &mut RunStats,
+    journal: &mut Option<Journal<File>>,
+    progress: &mut Option<Progress<StderrProgress>>,
+) -> Result<(), AppError>
+where
+    I: IntoIterator<Item = Result<Transaction, E>>,
+    AppError: From<E>,
+{
+    for transaction in transactions {
+        let transaction = transaction?;
+        if tx_range.contains(&transaction.tx) {
+            apply(transaction, accounts, stats, journal)?;
+            if let Some(progress) = progress.as_mut() {
+                progress.tick();
+            }
+        }
+    }
     Ok(())
 }
 
+/// Same as [process] but skip (and log) rejected transactions
+/// (invalid records are skipped by [CsvReader::into_lenient])
+fn process_lenient<I>(
+    transactions: I,
+    tx_range: &RangeInclusive<u32>,
+    accounts: &mut Accounts,
+    stats: &mut RunStats,
+    journal: &mut Option<Journal<File>>,
+    progress: &mut Option<Progress<StderrProgress>>,
+) -> Result<(), AppError>
+where
+    I: IntoIterator<Item = Transaction>,
+{
+    for transaction in transactions
+        .into_iter()
+        .filter(|t| tx_range.contains(&t.tx))
+    {
+        let tx = transaction.tx;
+        match apply(transaction, accounts, stats, journal) {
+            Err(AppError::Transaction(e)) => {
+                warn!("Skipping rejected tx {}: {}", tx, e);
+                stats.record_error(format!("tx {}: {}", tx, e));
+            }
+            res => res?,
+        }
+        if let Some(progress) = progress.as_mut() {
+            progress.tick();
+        }
+    }
+    Ok(())
+}
+
+/// Apply a transaction, counting it in stats & journaling it (if applied)
+fn apply(
+    transaction: Transaction,
+    accounts: &mut Accounts,
+    stats: &mut RunStats,
+    journal: &mut Option<Journal<File>>,
+) -> Result<(), AppError> {
+    debug!("Processing tx: {:?}", transaction);
+    let kind = transaction.kind;
+    let tx = transaction.tx;
+    let result = accounts.handle_transaction_with_outcome(transaction);
+    stats.record(kind, result.is_err());
+
+    let outcome = result?;
+    if let Some(journal) = journal.as_mut() {
+        journal.record(tx, &outcome)?;
+    }
+    Ok(())
+}
+
+/// Number of processed transactions between 2 progress lines (see `--progress`)
+const PROGRESS_INTERVAL: usize = 100_000;
+
+/// Where progress lines go (see [Progress])
+trait ProgressSink {
+    fn emit(&mut self, count: usize, elapsed: Duration);
+}
+
+/// Print progress lines on stderr (stdout is the csv output)
+struct StderrProgress;
+
+impl ProgressSink for StderrProgress {
+    fn emit(&mut self, count: usize, elapsed: Duration) {
+        eprintln!(
+            "Processed {} transactions in {:.1}s",
+            count,
+            elapsed.as_secs_f64()
+        );
+    }
+}
+
+/// Count processed transactions & emit a progress line every `interval` ones
+struct Progress<S: ProgressSink> {
+    count: usize,
+    interval: usize,
+    start: Instant,
+    sink: S,
+}
+
+impl<S: ProgressSink> Progress<S> {
+    fn new(interval: usize, sink: S) -> Self {
+        Self {
+            count: 0,
+            interval: interval.max(1),
+            start: Instant::now(),
+            sink,
+        }
+    }
+
+    /// Count 1 processed transaction (applied or rejected)
+    // Note: is_multiple_of requires rust 1.87 (see Readme: tested with rust 1.58)
+    #[allow(clippy::manual_is_multiple_of)]
+    fn tick(&mut self) {
+        self.count += 1;
+        if self.count % self.interval == 0 {
+            self.sink.emit(self.count, self.start.elapsed());
+        }
+    }
+}
+
 /// cli program entry function
 fn main() {
     env_logger::init();
 
-    if let Some(csv_path) = std::env::args().nth(1) {
-        if let Err(e) = app_main(csv_path) {
-            debug!("Error: {:?}", e);
-            let return_code = match e {
-                AppError::IO(_) => 2,
-                AppError::Csv(_) => 3,
-                AppError::Transaction(_) => 4,
+    match parse_args(std::env::args().skip(1)) {
+        Ok(options) => match app_main(&options) {
+            Ok(report) => {
+                info!("Done: {}", report);
+            }
+            Err(e) => {
+                debug!("Error: {:?}", e);
+                if matches!(e, AppError::Args(_) | AppError::Verify(_)) {
+                    error!("Error: {}", e);
+                }
+                std::process::exit(e.exit_code());
+            }
+        },
+        Err(e) => {
+            error!("Error: {}, example: cargo run -- foo.csv", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn cli_parse_args() {
+        let options = parse_args(args(&["foo.csv"])).unwrap();
+        assert_eq!(options.csv_path, PathBuf::from("foo.csv"));
+        assert_eq!(options.max_retained_tx, None);
+        assert!(!options.only_locked);
+
+        let options = parse_args(args(&["--max-retained-tx", "1000", "foo.csv"])).unwrap();
+        assert_eq!(options.csv_path, PathBuf::from("foo.csv"));
+        assert_eq!(options.max_retained_tx, Some(1000));
+
+        let options = parse_args(args(&["foo.csv", "--only-locked", "--strict"])).unwrap();
+        assert_eq!(options.csv_path, PathBuf::from("foo.csv"));
+        assert!(options.only_locked);
+        assert!(options.strict);
+
+        let options = parse_args(args(&["--metrics", "metrics.prom", "foo.csv"])).unwrap();
+        assert_eq!(options.csv_path, PathBuf::from("foo.csv"));
+        assert_eq!(options.metrics_path, Some(PathBuf::from("metrics.prom")));
+        assert!(!options.skip_errors);
+
+        let options = parse_args(args(&["--skip-errors", "foo.csv"])).unwrap();
+        assert!(options.skip_errors);
+
+        let options = parse_args(args(&["--scale", "2", "foo.csv"])).unwrap();
+        assert_eq!(options.scale, Some(2));
+        assert!(!options.per_client_tx);
+
+        let options = parse_args(args(&["--per-client-tx", "foo.csv"])).unwrap();
+        assert!(options.per_client_tx);
+        assert!(!options.fail_on_lock);
+
+        let options = parse_args(args(&["--fail-on-lock", "foo.csv"])).unwrap();
+        assert!(options.fail_on_lock);
+
+        let options = parse_args(args(&["--journal", "journal.csv", "foo.csv"])).unwrap();
+        assert_eq!(options.journal_path, Some(PathBuf::from("journal.csv")));
+        assert_eq!(options.tx_range(), 0..=u32::MAX);
+
+        let options = parse_args(args(&["--min-tx", "2", "--max-tx", "4", "foo.csv"])).unwrap();
+        assert_eq!(options.tx_range(), 2..=4);
+        assert!(!options.count);
+
+        let options = parse_args(args(&["--count", "foo.csv"])).unwrap();
+        assert!(options.count);
+        assert!(!options.dispute_withdrawals);
+
+        let options = parse_args(args(&["--dispute-withdrawals", "foo.csv"])).unwrap();
+        assert!(options.dispute_withdrawals);
+        assert!(!options.progress);
+
+        let options = parse_args(args(&["--progress", "foo.csv"])).unwrap();
+        assert!(options.progress);
+        assert_eq!(options.on_unknown_kind, UnknownKind::Fail);
+
+        let options = parse_args(args(&["--on-unknown-kind", "skip", "foo.csv"])).unwrap();
+        assert_eq!(options.on_unknown_kind, UnknownKind::Skip);
+        assert_eq!(options.interest, None);
+
+        let options = parse_args(args(&["--interest", "0.01", "foo.csv"])).unwrap();
+        assert_eq!(options.interest, Some(0.01));
+        assert!(!options.list_tx);
+
+        let options = parse_args(args(&["--list-tx", "foo.csv"])).unwrap();
+        assert!(options.list_tx);
+        assert!(!options.summary);
+
+        let options = parse_args(args(&["--summary", "foo.csv"])).unwrap();
+        assert!(options.summary);
+        assert!(!options.footer);
+
+        let options = parse_args(args(&["--footer", "foo.csv"])).unwrap();
+        assert!(options.footer);
+        assert!(!options.require_known_client);
+
+        let options = parse_args(args(&["--require-known-client", "foo.csv"])).unwrap();
+        assert!(options.require_known_client);
+        let options = parse_args(args(&["--no-auto-create", "foo.csv"])).unwrap();
+        assert!(options.require_known_client);
+        assert!(!options.auto_dispute_on_chargeback);
+
+        let options = parse_args(args(&["--auto-dispute-on-chargeback", "foo.csv"])).unwrap();
+        assert!(options.auto_dispute_on_chargeback);
+        assert!(!options.freeze_on_chargeback);
+
+        let options = parse_args(args(&["--freeze-on-chargeback", "foo.csv"])).unwrap();
+        assert!(options.freeze_on_chargeback);
+        assert!(!options.idempotent);
+
+        let options = parse_args(args(&["--idempotent", "foo.csv"])).unwrap();
+        assert!(options.idempotent);
+        assert_eq!(options.quote_style, QuoteStyle::Necessary);
+
+        let options = parse_args(args(&["--quote", "always", "foo.csv"])).unwrap();
+        assert_eq!(options.quote_style, QuoteStyle::Always);
+        assert!(!options.minor_units);
+
+        let options = parse_args(args(&["--minor-units", "foo.csv"])).unwrap();
+        assert!(options.minor_units);
+        assert!(!options.crlf);
+
+        let options = parse_args(args(&["--crlf", "foo.csv"])).unwrap();
+        assert!(options.crlf);
+        assert!(!options.suppress_empty);
+
+        let options = parse_args(args(&["--suppress-empty", "foo.csv"])).unwrap();
+        assert!(options.suppress_empty);
+        assert!(options.extra_columns.is_empty());
+
+        let options = parse_args(args(&["--extra-columns", "region, segment", "foo.csv"])).unwrap();
+        assert_eq!(options.extra_columns, vec!["region", "segment"]);
+        assert!(!options.decimal_comma);
+
+        let options = parse_args(args(&["--decimal-comma", "foo.csv"])).unwrap();
+        assert!(options.decimal_comma);
+        assert!(!options.lossy_utf8);
+
+        let options = parse_args(args(&["--lossy-utf8", "foo.csv"])).unwrap();
+        assert!(options.lossy_utf8);
+        assert_eq!(options.limit, None);
+
+        let options = parse_args(args(&["--limit", "2", "foo.csv"])).unwrap();
+        assert_eq!(options.limit, Some(2));
+        assert!(!options.sorted);
+
+        let options = parse_args(args(&["--sorted", "foo.csv"])).unwrap();
+        assert!(options.sorted);
+        assert_eq!(options.verify_path, None);
+
+        let options = parse_args(args(&["--verify", "expected.csv", "foo.csv"])).unwrap();
+        assert_eq!(options.verify_path, Some(PathBuf::from("expected.csv")));
+        assert_eq!(options.state, None);
+
+        let options = parse_args(args(&["--state", "in.csv", "out.csv", "foo.csv"])).unwrap();
+        assert_eq!(
+            options.state,
+            Some((PathBuf::from("in.csv"), PathBuf::from("out.csv")))
+        );
+        assert!(parse_args(args(&["--state", "in.csv"])).is_err());
+        assert_eq!(options.tx_id_scope, TxIdScope::Global);
+
+        let options = parse_args(args(&["--tx-id-scope", "file", "foo.csv"])).unwrap();
+        assert_eq!(options.tx_id_scope, TxIdScope::File);
+        assert!(parse_args(args(&["--tx-id-scope", "client", "foo.csv"])).is_err());
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn cli_parse_args_input_format() {
+        let options = parse_args(args(&["foo.csv"])).unwrap();
+        assert_eq!(options.input_format, None);
+        let options = parse_args(args(&["--input-format", "json", "foo.csv"])).unwrap();
+        assert_eq!(options.input_format, Some(InputFormat::Json));
+        assert!(parse_args(args(&["--input-format", "xml", "foo.csv"])).is_err());
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn app_main_json() {
+        // By extension
+        let options = parse_args(args(&[
+            "--verify",
+            "resources/sample_1_expected.csv",
+            "resources/sample_1.json",
+        ]))
+        .unwrap();
+        assert!(app_main(&options).is_ok());
+
+        // Or as configured
+        let options =
+            parse_args(args(&["--input-format", "json", "resources/sample_1.csv"])).unwrap();
+        match app_main(&options) {
+            Err(e @ AppError::CsvRecord(_)) => {
+                assert_eq!(e.exit_code(), 3);
+            }
+            r => {
+                panic!("Unexpected result: {:?}", r);
+            }
+        }
+    }
+
+    #[cfg(feature = "net")]
+    #[test]
+    fn cli_parse_args_listen() {
+        let options = parse_args(args(&["--listen", "127.0.0.1:7878"])).unwrap();
+        assert_eq!(options.listen.as_deref(), Some("127.0.0.1:7878"));
+
+        assert!(parse_args(args(&["--listen", "127.0.0.1:7878", "foo.csv"])).is_err());
+        assert!(parse_args(args(&["--listen"])).is_err());
+    }
+
+    #[test]
+    fn cli_parse_args_invalid() {
+        assert!(parse_args(args(&[])).is_err());
+        assert!(parse_args(args(&["foo.csv", "bar.csv"])).is_err());
+        assert!(parse_args(args(&["--foo", "foo.csv"])).is_err());
+        assert!(parse_args(args(&["foo.csv", "--max-retained-tx"])).is_err());
+        assert!(parse_args(args(&["foo.csv", "--metrics"])).is_err());
+        assert!(parse_args(args(&["--scale", "two", "foo.csv"])).is_err());
+        assert!(parse_args(args(&["--on-unknown-kind", "ignore", "foo.csv"])).is_err());
+        assert!(parse_args(args(&["--quote", "minimal", "foo.csv"])).is_err());
+        assert!(parse_args(args(&["--max-retained-tx", "-1", "foo.csv"])).is_err());
+    }
+
+    #[test]
+    fn app_main_fail_on_lock() {
+        let options = parse_args(args(&[
+            "--fail-on-lock",
+            "resources/sample_5_chargeback.csv",
+        ]))
+        .unwrap();
+        match app_main(&options) {
+            Err(e @ AppError::LockedAccounts(1)) => {
+                assert_eq!(e.exit_code(), 5);
+            }
+            r => {
+                panic!("Unexpected result: {:?}", r);
+            }
+        }
+
+        // Without the flag or without chargeback: success
+        let options = parse_args(args(&["resources/sample_5_chargeback.csv"])).unwrap();
+        assert!(app_main(&options).is_ok());
+        let options = parse_args(args(&["--fail-on-lock", "resources/sample_1.csv"])).unwrap();
+        assert!(app_main(&options).is_ok());
+    }
+
+    #[test]
+    fn process_tx_range() -> Result<(), Box<dyn std::error::Error>> {
+        let csv_reader = CsvReader::new(PathBuf::from("resources/sample_1.csv"))?;
+        let mut accounts = Accounts::new();
+        let mut stats = RunStats::default();
+        process(
+            csv_reader,
+            &(2..=4),
+            &mut accounts,
+            &mut stats,
+            &mut None,
+            &mut None,
+        )?;
+
+        // tx 1 (deposit) & 5 (withdrawal) are skipped
+        assert_eq!(stats.total(), 3);
+        let report = accounts.report();
+        assert_eq!(report.len(), 2);
+        assert_eq!((report[0].client, report[0].total), (1, 0.5));
+        assert_eq!((report[1].client, report[1].total), (2, 2.0));
+        Ok(())
+    }
+
+    /// Writer closed by its reader
+    struct BrokenPipeWriter;
+
+    impl Write for BrokenPipeWriter {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::from(io::ErrorKind::BrokenPipe))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Err(io::Error::from(io::ErrorKind::BrokenPipe))
+        }
+    }
+
+    #[test]
+    fn write_output_broken_pipe() {
+        let mut accounts = Accounts::new();
+        accounts
+            .handle_transaction(Transaction::new(
+                homework_toy_pay::csv_reader::TransactionKind::Deposit,
+                1,
+                1,
+                Some(1.0),
+            ))
+            .unwrap();
+
+        assert!(write_output(&accounts, false, false, &mut BrokenPipeWriter).is_ok());
+        assert!(write_output(&accounts, false, true, &mut BrokenPipeWriter).is_ok());
+    }
+
+    #[test]
+    fn write_count_broken_pipe() -> Result<(), Box<dyn std::error::Error>> {
+        assert!(write_count(5, &mut BrokenPipeWriter).is_ok());
+
+        let mut output: Vec<u8> = Vec::new();
+        write_count(5, &mut output)?;
+        assert_eq!(std::str::from_utf8(&output)?, "5\n");
+        Ok(())
+    }
+
+    #[test]
+    fn write_output_footer() -> Result<(), Box<dyn std::error::Error>> {
+        let mut accounts = Accounts::new();
+        for transaction in CsvReader::new(PathBuf::from("resources/sample_5_chargeback.csv"))? {
+            let _ = accounts.handle_transaction(transaction?);
+        }
+
+        let mut output: Vec<u8> = Vec::new();
+        write_output(&accounts, false, false, &mut output)?;
+        assert!(!std::str::from_utf8(&output)?.contains('#'));
+
+        let mut output: Vec<u8> = Vec::new();
+        write_output(&accounts, false, true, &mut output)?;
+        let output = std::str::from_utf8(&output)?;
+        assert_eq!(output.lines().last(), Some(footer_line(&accounts).as_str()));
+        assert_eq!(
+            footer_line(&accounts),
+            "# 2 clients, 1 locked, total=4.0000"
+        );
+        // Csv body is unchanged
+        assert_eq!(output.lines().count(), 4);
+        Ok(())
+    }
+
+    impl ProgressSink for Vec<usize> {
+        fn emit(&mut self, count: usize, _elapsed: Duration) {
+            self.push(count);
+        }
+    }
+
+    #[test]
+    fn progress_tick() {
+        let mut progress = Progress::new(3, Vec::new());
+        for _ in 0..10 {
+            progress.tick();
+        }
+        assert_eq!(progress.count, 10);
+        assert_eq!(progress.sink, vec![3, 6, 9]);
+    }
+
+    #[test]
+    fn app_main_verify() {
+        let options = parse_args(args(&[
+            "--verify",
+            "resources/sample_1_expected.csv",
+            "resources/sample_1.csv",
+        ]))
+        .unwrap();
+        assert!(app_main(&options).is_ok());
+
+        let options = parse_args(args(&[
+            "--verify",
+            "resources/sample_1_expected_mismatch.csv",
+            "resources/sample_1.csv",
+        ]))
+        .unwrap();
+        match app_main(&options) {
+            Err(e @ AppError::Verify(_)) => {
+                assert_eq!(e.exit_code(), 6);
+                let summary = e.to_string();
+                assert!(summary.contains("1 missing line(s), 1 unexpected line(s)"));
+                assert!(summary.contains("\n- 2,,0.2000,0.0000,0.2000,false,0"));
+                assert!(summary.contains("\n+ 2,,0.1000,0.0000,0.1000,false,0"));
+            }
+            r => {
+                panic!("Unexpected result: {:?}", r);
+            }
+        }
+    }
+
+    #[test]
+    fn verify_diff_lines() {
+        let (missing, unexpected) = diff_lines("a\nb\nb\r\n\nc\n", "c\nb\nd\na\n");
+        assert_eq!(missing, vec!["b"]);
+        assert_eq!(unexpected, vec!["d"]);
+        assert_eq!(diff_lines("a\nb", "b\na\n"), (vec![], vec![]));
+    }
+
+    #[test]
+    fn process_sorted() -> Result<(), Box<dyn std::error::Error>> {
+        let output_of = |csv_path: &str, sorted: bool| -> Result<String, AppError> {
+            let csv_reader = CsvReader::new(PathBuf::from(csv_path))?;
+            let mut accounts = Accounts::new();
+            let transactions = if sorted {
+                read_sorted(vec![csv_reader], true, usize::MAX)?
+            } else {
+                csv_reader.into_lenient().collect()
             };
-            std::process::exit(return_code);
+            process_lenient_or_not(
+                transactions,
+                true,
+                &(0..=u32::MAX),
+                &mut accounts,
+                &mut RunStats::default(),
+                &mut None,
+                &mut None,
+            )?;
+            let mut output: Vec<u8> = Vec::new();
+            accounts.output_as_csv(&mut output)?;
+            let mut lines: Vec<String> = String::from_utf8_lossy(&output)
+                .lines()
+                .map(String::from)
+                .collect();
+            lines.sort();
+            Ok(lines.join("\n"))
+        };
+
+        // Same transactions in another order: different results...
+        let ordered = output_of("resources/sample_8_ordered.csv", false)?;
+        assert_ne!(
+            output_of("resources/sample_8_unordered.csv", false)?,
+            ordered
+        );
+        // ... unless sorted
+        assert_eq!(
+            output_of("resources/sample_8_unordered.csv", true)?,
+            ordered
+        );
+        assert_eq!(output_of("resources/sample_8_ordered.csv", true)?, ordered);
+        assert!(ordered.contains("1,,6.0000,2.0000,8.0000,false,1"));
+
+        // Strict mode: sorted transactions are all applied
+        let options = parse_args(args(&["--sorted", "resources/sample_8_unordered.csv"])).unwrap();
+        assert!(app_main(&options).is_ok());
+        let options = parse_args(args(&["resources/sample_8_unordered.csv"])).unwrap();
+        assert!(app_main(&options).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn read_and_process_limit() -> Result<(), Box<dyn std::error::Error>> {
+        for extra_args in [&[][..], &["--skip-errors"], &["--sorted"]] {
+            let mut cli_args = vec!["--limit", "2", "resources/sample_1.csv"];
+            cli_args.extend_from_slice(extra_args);
+            let options = parse_args(args(&cli_args))?;
+
+            let csv_reader = CsvReader::new(options.csv_path.clone())?;
+            let mut accounts = Accounts::new();
+            let mut stats = RunStats::default();
+            read_and_process(
+                vec![csv_reader],
+                &options,
+                &mut accounts,
+                &mut stats,
+                &mut None,
+                &mut None,
+            )?;
+
+            // Only the first 2 deposits (tx 1 & 2) are applied
+            assert_eq!(stats.total(), 2);
+            let report = accounts.report();
+            assert_eq!(report.len(), 2);
+            assert_eq!((report[0].client, report[0].total), (1, 1.0));
+            assert_eq!((report[1].client, report[1].total), (2, 2.0));
         }
-    } else {
-        error!("Error, Please provide a csv file path, example: cargo run -- foo.csv");
-        std::process::exit(1);
+        Ok(())
+    }
+
+    #[test]
+    fn app_main_report() {
+        let options = parse_args(args(&[
+            "--skip-errors",
+            "resources/sample_5_chargeback.csv",
+        ]))
+        .unwrap();
+        let report = app_main(&options).unwrap();
+        assert_eq!(report.accounts, 2);
+        assert_eq!(report.locked, 1);
+        assert_eq!(report.rejected, report.errors.len());
+
+        let options = parse_args(args(&["--count", "resources/sample_1.csv"])).unwrap();
+        assert_eq!(app_main(&options).unwrap().processed, 5);
+    }
+
+    #[test]
+    fn app_main_state() -> Result<(), Box<dyn std::error::Error>> {
+        let state_1 =
+            std::env::temp_dir().join(format!("toy_pay_state_1_{}.csv", std::process::id()));
+        let state_2 =
+            std::env::temp_dir().join(format!("toy_pay_state_2_{}.csv", std::process::id()));
+
+        // Day 1: no previous state
+        let options = parse_args(args(&[
+            "--state",
+            "/dev/null",
+            state_1.to_str().ok_or("Invalid path")?,
+            "resources/sample_1.csv",
+        ]))?;
+        app_main(&options)?;
+
+        // Day 2: dispute of a day 1 deposit
+        let options = parse_args(args(&[
+            "--state",
+            state_1.to_str().ok_or("Invalid path")?,
+            state_2.to_str().ok_or("Invalid path")?,
+            "resources/sample_1_day2.csv",
+        ]))?;
+        let report = app_main(&options)?;
+        assert_eq!(report.processed, 2);
+        assert_eq!(report.rejected, 0);
+
+        let mut accounts = Accounts::new();
+        accounts.restore(AccountsSnapshot::read_csv(File::open(&state_2)?)?);
+        fs::remove_file(&state_1)?;
+        fs::remove_file(&state_2)?;
+
+        let report = accounts.report();
+        let client_1 = report
+            .iter()
+            .find(|r| r.client == 1)
+            .ok_or("Cannot client client account")?;
+        assert_eq!(client_1.available, 5.5);
+        assert_eq!(client_1.held, 1.0);
+        assert_eq!(client_1.total, 6.5);
+        assert_eq!(client_1.disputed_count, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn app_main_dir() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir().join(format!("toy_pay_dir_{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        fs::copy("resources/sample_1.csv", dir.join("2024-01-01.csv"))?;
+        fs::copy("resources/sample_1_day2.csv", dir.join("2024-01-02.csv"))?;
+        fs::write(dir.join("readme.txt"), "not a csv file")?;
+
+        let options = parse_args(args(&[
+            "--verify",
+            "resources/sample_1_dir_expected.csv",
+            dir.to_str().ok_or("Invalid path")?,
+        ]))?;
+        let report = app_main(&options);
+        let options = parse_args(args(&["--count", dir.to_str().ok_or("Invalid path")?]))?;
+        let count = app_main(&options);
+        fs::remove_dir_all(&dir)?;
+
+        let report = report?;
+        assert_eq!(report.processed, 7);
+        assert_eq!(report.rejected, 0);
+        assert_eq!(count?.processed, 7);
+        Ok(())
+    }
+
+    #[test]
+    fn app_main_dir_limit_sorted() -> Result<(), Box<dyn std::error::Error>> {
+        let dir = std::env::temp_dir().join(format!("toy_pay_dir_sorted_{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        // A dispute of a deposit from the next file
+        fs::write(dir.join("a.csv"), "type,client,tx,amount\ndispute,1,2,\n")?;
+        fs::write(
+            dir.join("b.csv"),
+            "type,client,tx,amount\ndeposit,1,2,3.0\ndeposit,1,3,1.0\n",
+        )?;
+        let dir_path = dir.to_str().ok_or("Invalid path")?;
+        let expected = std::env::temp_dir().join(format!(
+            "toy_pay_dir_sorted_expected_{}.txt",
+            std::process::id()
+        ));
+        fs::write(
+            &expected,
+            "client,currency,available,held,total,locked,disputed_count\n1,,1.0000,3.0000,4.0000,false,1\n",
+        )?;
+
+        // Limit applies over all files
+        let limited = app_main(&parse_args(args(&[
+            "--skip-errors",
+            "--limit",
+            "2",
+            dir_path,
+        ]))?);
+        let sorted = app_main(&parse_args(args(&[
+            "--sorted",
+            "--verify",
+            expected.to_str().ok_or("Invalid path")?,
+            dir_path,
+        ]))?);
+        let sorted_limited = app_main(&parse_args(args(&["--sorted", "--limit", "2", dir_path]))?);
+        let unsorted = app_main(&parse_args(args(&[dir_path]))?);
+        fs::remove_dir_all(&dir)?;
+        fs::remove_file(&expected)?;
+
+        let limited = limited?;
+        assert_eq!(limited.processed, 2);
+        assert_eq!(limited.rejected, 1);
+
+        // Transactions of all files are sorted as a single set
+        let sorted = sorted?;
+        assert_eq!(sorted.processed, 3);
+        assert_eq!(sorted.rejected, 0);
+        assert_eq!(sorted_limited?.processed, 2);
+        assert!(unsorted.is_err());
+        Ok(())
     }
 }
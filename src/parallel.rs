@@ -0,0 +1,377 @@
+//! Multi threaded transactions processing (require feature: parallel)
+//!
+//! Transactions of distinct clients are independent so clients are sharded across threads
+//! (shard = client id % number of shards). Each shard processes its transactions in input order.
+//! Tx ids are globally unique thus all shards share a tx index (k: tx, v: client id),
+//! unless tx ids are only unique per client (see [crate::accounts::AccountsBuilder::per_client_tx]).
+//!
+//! Ordering guarantees (same accounts & same rejected transactions as the serial engine, whatever
+//! the interleaving):
+//! * A Dispute, Resolve or Chargeback is handled by the shard of its client, after every earlier
+//!   transaction of this client (input order). A tx of another client cannot be referenced
+//!   (rejected, see [TransactionError::ClientMismatch]) thus this shard is the one of the tx
+//!   owner: the dispute lifecycle of a given tx is serialized without any lock
+//! * Clients using the same tx id (deposits / withdrawals) are grouped in the same shard
+//!   (shard = smallest client id of the group % number of shards): the first of them in
+//!   input order gets the tx id, like in the serial engine, instead of the fastest shard
+//! * The shared tx index lock is only held to check & reserve a tx id, never while applying
+//!   a transaction
+
+// std
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::thread;
+// internal
+use crate::accounts::{Accounts, TransactionError};
+use crate::csv_reader::{Transaction, TransactionKind};
+
+impl Accounts {
+    /// Handle transactions using `shards` threads, returning every rejected transaction
+    /// (as its index in `transactions` and its [TransactionError]), sorted by index
+    ///
+    /// Unlike the serial path (see [Accounts::handle_transaction]), a rejected transaction
+    /// does not stop the processing.
+    ///
+    /// Notes:
+    /// * A Dispute (or Resolve / Chargeback) referencing a transaction of another client is
+    ///   rejected, like in the serial path, but with [TransactionError::UnknownTransaction]
+    ///   (or [TransactionError::NonDisputableKind]) if this transaction is handled by another
+    ///   shard, instead of [TransactionError::ClientMismatch]
+    /// * The tx id of a transaction applied by a shard stays reserved for this client
+    ///   (even if evicted, see [Accounts::with_max_retained_tx])
+    pub fn handle_transactions_parallel(
+        &mut self,
+        transactions: Vec<Transaction>,
+        shards: usize,
+    ) -> Vec<(usize, TransactionError)> {
+        let shards = shards.max(1);
+
+        // tx index shared by all shards (k: tx, v: client id)
+        let tx_index: Mutex<HashMap<u32, u16>> = Mutex::new(
+            self.tx_ids_by_client()
+                .into_iter()
+                .map(|(client, tx)| (tx, client))
+                .collect(),
+        );
+
+        let groups = if self.per_client_tx() {
+            HashMap::new()
+        } else {
+            client_groups(&transactions)
+        };
+        let shard_of =
+            |client: u16| groups.get(&client).copied().unwrap_or(client) as usize % shards;
+
+        let shard_accounts: Vec<Accounts> = (0..shards)
+            .map(|shard| self.split_off_clients(|client| shard_of(client) == shard))
+            .collect();
+
+        // Work queues: dispatch transactions to shards (keyed by client group)
+        let mut queues: Vec<Vec<(usize, Transaction)>> = (0..shards).map(|_| vec![]).collect();
+        for (i, transaction) in transactions.into_iter().enumerate() {
+            queues[shard_of(transaction.client)].push((i, transaction));
+        }
+
+        let mut rejected = thread::scope(|scope| {
+            let handles: Vec<_> = shard_accounts
+                .into_iter()
+                .zip(queues)
+                .map(|(mut accounts, queue)| {
+                    let tx_index = &tx_index;
+                    scope.spawn(move || {
+                        let mut rejected = vec![];
+                        for (i, transaction) in queue {
+                            if let Err(e) =
+                                handle_shard_transaction(&mut accounts, tx_index, transaction)
+                            {
+                                rejected.push((i, e));
+                            }
+                        }
+                        (accounts, rejected)
+                    })
+                })
+                .collect();
+
+            let mut rejected = vec![];
+            for handle in handles {
+                let (accounts, shard_rejected) = handle.join().expect("Shard thread panicked");
+                self.absorb(accounts);
+                rejected.extend(shard_rejected);
+            }
+            rejected
+        });
+
+        rejected.sort_by_key(|(i, _)| *i);
+        rejected
+    }
+}
+
+/// Group clients using the same tx id (deposits / withdrawals) in `transactions`
+///
+/// Return the group (its smallest client id) of every grouped client (other clients are alone)
+fn client_groups(transactions: &[Transaction]) -> HashMap<u16, u16> {
+    // Union find (k: client id, v: parent client id)
+    fn find(parents: &mut HashMap<u16, u16>, client: u16) -> u16 {
+        let mut root = client;
+        while let Some(&parent) = parents.get(&root) {
+            if parent == root {
+                break;
+            }
+            root = parent;
+        }
+        // Path compression
+        let mut current = client;
+        while current != root {
+            current = parents.insert(current, root).unwrap_or(root);
+        }
+        root
+    }
+
+    let mut parents: HashMap<u16, u16> = HashMap::new();
+    let mut first_client: HashMap<u32, u16> = HashMap::new();
+    for transaction in transactions.iter().filter(|t| {
+        matches!(
+            t.kind,
+            TransactionKind::Deposit | TransactionKind::Withdrawal
+        )
+    }) {
+        let first = *first_client
+            .entry(transaction.tx)
+            .or_insert(transaction.client);
+        if first != transaction.client {
+            let (a, b) = (
+                find(&mut parents, first),
+                find(&mut parents, transaction.client),
+            );
+            parents.insert(a.max(b), a.min(b));
+            parents.insert(a.min(b), a.min(b));
+        }
+    }
+
+    let clients: Vec<u16> = parents.keys().copied().collect();
+    clients
+        .into_iter()
+        .map(|client| (client, find(&mut parents, client)))
+        .collect()
+}
+
+/// Handle a transaction in a shard, checking first the tx id against the shared tx index
+fn handle_shard_transaction(
+    accounts: &mut Accounts,
+    tx_index: &Mutex<HashMap<u32, u16>>,
+    transaction: Transaction,
+) -> Result<(), TransactionError> {
+    let tx = transaction.tx;
+    let mut reserved = false;
+
+    if !accounts.per_client_tx()
+        && matches!(
+            transaction.kind,
+            TransactionKind::Deposit | TransactionKind::Withdrawal
+        )
+    {
+        // Reserve the tx id for this client (so no other shard can use it)
+        let mut tx_index = tx_index.lock().expect("Tx index lock poisoned");
+        match tx_index.get(&tx) {
+            Some(client) if *client != transaction.client => {
+                return Err(TransactionError::InvalidTransaction(tx));
+            }
+            Some(_) => {}
+            None => {
+                tx_index.insert(tx, transaction.client);
+                reserved = true;
+            }
+        }
+    }
+
+    let res = accounts.handle_transaction(transaction);
+
+    if res.is_err() && reserved {
+        tx_index.lock().expect("Tx index lock poisoned").remove(&tx);
+    }
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::strategies;
+    use proptest::prelude::*;
+    use std::time::Instant;
+
+    /// Generate a mix of deposits, withdrawals, disputes, resolves & chargebacks
+    /// (by blocks of 10 transactions for the same client)
+    fn generate_transactions(count: u32, clients: u16) -> Vec<Transaction> {
+        (1..=count)
+            .map(|tx| {
+                let client = ((tx - 1) / 10 % clients as u32) as u16;
+                match tx % 10 {
+                    1..=4 => Transaction::new(TransactionKind::Deposit, client, tx, Some(10.0)),
+                    5 | 6 => Transaction::new(TransactionKind::Withdrawal, client, tx, Some(4.0)),
+                    7 => Transaction::new(TransactionKind::Dispute, client, tx - 6, None),
+                    8 => Transaction::new(TransactionKind::Resolve, client, tx - 7, None),
+                    9 => Transaction::new(TransactionKind::Dispute, client, tx - 7, None),
+                    _ => Transaction::new(TransactionKind::Chargeback, client, tx - 8, None),
+                }
+            })
+            .collect()
+    }
+
+    /// Sorted csv output
+    fn output_lines(accounts: &Accounts) -> Vec<String> {
+        let mut output: Vec<u8> = Vec::new();
+        accounts.output_as_csv(&mut output).unwrap();
+        let mut lines: Vec<String> = std::str::from_utf8(&output)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect();
+        lines.sort();
+        lines
+    }
+
+    #[test]
+    fn parallel_same_as_serial() {
+        let transactions = generate_transactions(10_000, 97);
+
+        let mut serial = Accounts::new();
+        let serial_rejected: Vec<usize> = transactions
+            .iter()
+            .cloned()
+            .enumerate()
+            .filter_map(|(i, t)| serial.handle_transaction(t).err().map(|_| i))
+            .collect();
+
+        for shards in [1, 4, 7] {
+            let mut parallel = Accounts::new();
+            let rejected: Vec<usize> = parallel
+                .handle_transactions_parallel(transactions.clone(), shards)
+                .into_iter()
+                .map(|(i, _)| i)
+                .collect();
+
+            assert_eq!(rejected, serial_rejected);
+            assert_eq!(output_lines(&parallel), output_lines(&serial));
+        }
+    }
+
+    #[test]
+    fn parallel_non_unique_tx_across_shards() {
+        let mut accounts = Accounts::new();
+        accounts
+            .handle_transaction(Transaction::new(TransactionKind::Deposit, 1, 1, Some(1.0)))
+            .unwrap();
+
+        let rejected = accounts.handle_transactions_parallel(
+            vec![
+                Transaction::new(TransactionKind::Deposit, 2, 1, Some(2.0)),
+                Transaction::new(TransactionKind::Deposit, 2, 2, Some(2.0)),
+            ],
+            2,
+        );
+
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].0, 0);
+        assert!(matches!(
+            rejected[0].1,
+            TransactionError::InvalidTransaction(1)
+        ));
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn parallel_stress_same_as_serial(
+            transactions in strategies::transactions(2_000, 20),
+            shards in 2..9usize
+        ) {
+            let mut serial = Accounts::new();
+            let serial_rejected: Vec<usize> = transactions
+                .iter()
+                .cloned()
+                .enumerate()
+                .filter_map(|(i, t)| serial.handle_transaction(t).err().map(|_| i))
+                .collect();
+
+            let mut parallel = Accounts::new();
+            let rejected: Vec<usize> = parallel
+                .handle_transactions_parallel(transactions, shards)
+                .into_iter()
+                .map(|(i, _)| i)
+                .collect();
+
+            prop_assert_eq!(rejected, serial_rejected);
+            prop_assert_eq!(output_lines(&parallel), output_lines(&serial));
+        }
+    }
+
+    #[test]
+    fn parallel_cross_client_dispute() {
+        // Clients 1 & 2 in distinct shards, 1 & 3 in the same one
+        let transactions = vec![
+            Transaction::new(TransactionKind::Deposit, 1, 1, Some(5.0)),
+            Transaction::new(TransactionKind::Deposit, 2, 2, Some(3.0)),
+            Transaction::new(TransactionKind::Deposit, 3, 3, Some(1.0)),
+            Transaction::new(TransactionKind::Dispute, 2, 1, None),
+            Transaction::new(TransactionKind::Dispute, 3, 1, None),
+            Transaction::new(TransactionKind::Chargeback, 2, 1, None),
+            Transaction::new(TransactionKind::Dispute, 1, 1, None),
+        ];
+
+        let mut serial = Accounts::new();
+        let serial_rejected: Vec<usize> = transactions
+            .iter()
+            .cloned()
+            .enumerate()
+            .filter_map(|(i, t)| serial.handle_transaction(t).err().map(|_| i))
+            .collect();
+        assert_eq!(serial_rejected, vec![3, 4, 5]);
+
+        let mut parallel = Accounts::new();
+        let rejected: Vec<usize> = parallel
+            .handle_transactions_parallel(transactions, 2)
+            .into_iter()
+            .map(|(i, _)| i)
+            .collect();
+        assert_eq!(rejected, serial_rejected);
+        assert_eq!(output_lines(&parallel), output_lines(&serial));
+    }
+
+    #[test]
+    fn parallel_client_groups() {
+        let transactions = vec![
+            Transaction::new(TransactionKind::Deposit, 3, 1, Some(1.0)),
+            Transaction::new(TransactionKind::Deposit, 5, 1, Some(1.0)),
+            Transaction::new(TransactionKind::Deposit, 7, 2, Some(1.0)),
+            Transaction::new(TransactionKind::Withdrawal, 5, 2, Some(1.0)),
+            Transaction::new(TransactionKind::Deposit, 9, 3, Some(1.0)),
+            Transaction::new(TransactionKind::Dispute, 4, 3, None),
+        ];
+        let mut groups: Vec<(u16, u16)> = client_groups(&transactions).into_iter().collect();
+        groups.sort();
+        assert_eq!(groups, vec![(3, 3), (5, 3), (7, 3)]);
+    }
+
+    #[test]
+    #[ignore]
+    fn parallel_bench() {
+        // cargo test --release --features parallel parallel_bench -- --ignored --nocapture
+        let transactions = generate_transactions(2_000_000, 10_000);
+
+        let now = Instant::now();
+        let mut serial = Accounts::new();
+        for transaction in transactions.iter().cloned() {
+            let _ = serial.handle_transaction(transaction);
+        }
+        println!("serial: {:?}", now.elapsed());
+
+        for shards in [2, 4, 8] {
+            let now = Instant::now();
+            let mut parallel = Accounts::new();
+            parallel.handle_transactions_parallel(transactions.clone(), shards);
+            println!("parallel ({} shards): {:?}", shards, now.elapsed());
+        }
+    }
+}
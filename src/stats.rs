@@ -0,0 +1,233 @@
+// std
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
+
+// internal
+use crate::accounts::Accounts;
+use crate::csv_reader::{Transaction, TransactionKind};
+
+/// Prefix of all metric names (see [RunStats::write_prometheus])
+const METRICS_PREFIX: &str = "toy_pay";
+
+/// Max number of error messages kept by [RunStats::record_error] (all are counted)
+const MAX_REPORTED_ERRORS: usize = 100;
+
+/// Counters of a processing run (transactions per kind, rejected ones, locked accounts)
+#[derive(Debug, Clone, Default)]
+pub struct RunStats {
+    by_kind: HashMap<TransactionKind, usize>,
+    /// transactions rejected by [crate::accounts::Accounts::handle_transaction]
+    pub rejected: usize,
+    /// accounts locked at the end of the run
+    pub locked_accounts: usize,
+    /// first [MAX_REPORTED_ERRORS] rejection messages (see [RunStats::record_error])
+    errors: Vec<String>,
+}
+
+impl RunStats {
+    /// Count a processed transaction (rejected or not)
+    pub fn record(&mut self, kind: TransactionKind, rejected: bool) {
+        *self.by_kind.entry(kind).or_default() += 1;
+        if rejected {
+            self.rejected += 1;
+        }
+    }
+
+    /// Keep the message of a rejected transaction (only the first ones are kept)
+    pub fn record_error(&mut self, message: String) {
+        if self.errors.len() < MAX_REPORTED_ERRORS {
+            self.errors.push(message);
+        }
+    }
+
+    /// Number of processed transactions
+    pub fn total(&self) -> usize {
+        self.by_kind.values().sum()
+    }
+
+    /// Number of processed transactions of a given kind
+    pub fn count(&self, kind: TransactionKind) -> usize {
+        self.by_kind.get(&kind).copied().unwrap_or(0)
+    }
+
+    /// Write counters as text in the Prometheus exposition format
+    pub fn write_prometheus<W: Write>(&self, into: &mut W) -> std::io::Result<()> {
+        writeln!(
+            into,
+            "# HELP {0}_transactions_total Processed transactions\n\
+             # TYPE {0}_transactions_total counter\n\
+             {0}_transactions_total {1}",
+            METRICS_PREFIX,
+            self.total()
+        )?;
+
+        writeln!(
+            into,
+            "# HELP {0}_transactions_by_kind_total Processed transactions per type\n\
+             # TYPE {0}_transactions_by_kind_total counter",
+            METRICS_PREFIX
+        )?;
+        for kind in TransactionKind::ALL {
+            writeln!(
+                into,
+                "{}_transactions_by_kind_total{{kind=\"{}\"}} {}",
+                METRICS_PREFIX,
+                kind.name(),
+                self.count(kind)
+            )?;
+        }
+
+        writeln!(
+            into,
+            "# HELP {0}_transactions_rejected_total Rejected transactions\n\
+             # TYPE {0}_transactions_rejected_total counter\n\
+             {0}_transactions_rejected_total {1}",
+            METRICS_PREFIX, self.rejected
+        )?;
+
+        writeln!(
+            into,
+            "# HELP {0}_locked_accounts Locked accounts\n\
+             # TYPE {0}_locked_accounts gauge\n\
+             {0}_locked_accounts {1}",
+            METRICS_PREFIX, self.locked_accounts
+        )?;
+
+        into.flush()
+    }
+}
+
+/// Result of a whole run: counters & final state summary (see [process_all])
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RunReport {
+    /// processed transactions (applied or rejected)
+    pub processed: usize,
+    /// rejected transactions
+    pub rejected: usize,
+    /// accounts at the end of the run
+    pub accounts: usize,
+    /// locked accounts at the end of the run
+    pub locked: usize,
+    /// messages of the first rejected transactions
+    pub errors: Vec<String>,
+}
+
+impl RunReport {
+    /// Report of a run from its counters & the resulting accounts
+    pub fn new(stats: RunStats, accounts: &Accounts) -> Self {
+        let report = accounts.report();
+        Self {
+            processed: stats.total(),
+            rejected: stats.rejected,
+            accounts: report.len(),
+            locked: report.iter().filter(|r| r.locked).count(),
+            errors: stats.errors,
+        }
+    }
+}
+
+impl fmt::Display for RunReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "processed: {}, rejected: {}, accounts: {}, locked: {}",
+            self.processed, self.rejected, self.accounts, self.locked
+        )
+    }
+}
+
+/// Apply all transactions to accounts: a rejected transaction is counted (with its error
+/// message), then skipped
+pub fn process_all<I>(accounts: &mut Accounts, transactions: I) -> RunReport
+where
+    I: IntoIterator<Item = Transaction>,
+{
+    let mut stats = RunStats::default();
+    for transaction in transactions {
+        let kind = transaction.kind;
+        let tx = transaction.tx;
+        let result = accounts.handle_transaction(transaction);
+        stats.record(kind, result.is_err());
+        if let Err(e) = result {
+            stats.record_error(format!("tx {}: {}", tx, e));
+        }
+    }
+    RunReport::new(stats, accounts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+
+    #[test]
+    fn stats_write_prometheus() -> Result<(), Box<dyn Error>> {
+        let mut stats = RunStats::default();
+        stats.record(TransactionKind::Deposit, false);
+        stats.record(TransactionKind::Deposit, false);
+        stats.record(TransactionKind::Withdrawal, true);
+        stats.record(TransactionKind::Dispute, false);
+        stats.record(TransactionKind::Chargeback, false);
+        stats.locked_accounts = 1;
+
+        let mut output: Vec<u8> = Vec::new();
+        stats.write_prometheus(&mut output)?;
+        let output = String::from_utf8(output)?;
+        let metrics: Vec<&str> = output.lines().filter(|l| !l.starts_with('#')).collect();
+
+        assert_eq!(
+            metrics,
+            vec![
+                "toy_pay_transactions_total 5",
+                "toy_pay_transactions_by_kind_total{kind=\"deposit\"} 2",
+                "toy_pay_transactions_by_kind_total{kind=\"withdrawal\"} 1",
+                "toy_pay_transactions_by_kind_total{kind=\"dispute\"} 1",
+                "toy_pay_transactions_by_kind_total{kind=\"resolve\"} 0",
+                "toy_pay_transactions_by_kind_total{kind=\"chargeback\"} 1",
+                "toy_pay_transactions_by_kind_total{kind=\"fee\"} 0",
+                "toy_pay_transactions_by_kind_total{kind=\"reversal\"} 0",
+                "toy_pay_transactions_by_kind_total{kind=\"close\"} 0",
+                "toy_pay_transactions_by_kind_total{kind=\"note\"} 0",
+                "toy_pay_transactions_rejected_total 1",
+                "toy_pay_locked_accounts 1",
+            ]
+        );
+        assert!(output.contains("# TYPE toy_pay_locked_accounts gauge\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn stats_process_all_report() {
+        let mut accounts = Accounts::new();
+        let report = process_all(
+            &mut accounts,
+            [
+                Transaction::new(TransactionKind::Deposit, 1, 1, Some(2.0)),
+                Transaction::new(TransactionKind::Deposit, 2, 2, Some(1.0)),
+                Transaction::new(TransactionKind::Withdrawal, 2, 3, Some(5.0)),
+                Transaction::new(TransactionKind::Dispute, 1, 1, None),
+                Transaction::new(TransactionKind::Chargeback, 1, 1, None),
+                Transaction::new(TransactionKind::Resolve, 1, 9, None),
+            ],
+        );
+
+        assert_eq!(
+            report,
+            RunReport {
+                processed: 6,
+                rejected: 2,
+                accounts: 2,
+                locked: 1,
+                errors: vec![
+                    "tx 3: Invalid amount: 5".to_string(),
+                    "tx 9: Account (client id: 1) is locked".to_string()
+                ],
+            }
+        );
+        assert_eq!(
+            report.to_string(),
+            "processed: 6, rejected: 2, accounts: 2, locked: 1"
+        );
+    }
+}
@@ -0,0 +1,111 @@
+//! proptest strategies generating transactions (require feature: strategies, or tests)
+//!
+//! A transaction is first generated as a [Step] (kind, client, amount, ...), then turned into
+//! a [Transaction] by a [TransactionsBuilder]: tx ids are unique (unless a small tx id is reused
+//! on purpose, maybe by another client) & disputes, resolves & chargebacks reference earlier
+//! deposits (mostly of their own client). A failing case shrinks by removing or simplifying steps.
+
+// third party
+use proptest::collection::vec;
+use proptest::prelude::*;
+// internal
+use crate::csv_reader::{Transaction, TransactionKind};
+
+/// First tx id of generated transactions (reused tx ids are below)
+const FIRST_TX: u32 = 1000;
+
+/// A generated transaction, before its tx id (or the tx it references) is resolved
+#[derive(Debug, Clone)]
+pub struct Step {
+    kind: TransactionKind,
+    client: u16,
+    quarters: u32,         // amount as a multiple of 0.25 (exact f64 sums)
+    reference: usize,      // picks the referenced deposit (dispute, resolve, chargeback)
+    reuse_tx: Option<u32>, // tx id (below FIRST_TX) of a deposit or withdrawal, maybe already used
+    other_client: bool,    // reference a deposit of any client
+}
+
+/// Strategy of a [Step] of one of `clients` clients (client ids: 0..clients): mostly deposits
+/// & withdrawals, few chargebacks (as an account is frozen once locked)
+pub fn step(clients: u16) -> impl Strategy<Value = Step> {
+    (
+        prop_oneof![
+            20 => Just(TransactionKind::Deposit),
+            8 => Just(TransactionKind::Withdrawal),
+            6 => Just(TransactionKind::Dispute),
+            5 => Just(TransactionKind::Resolve),
+            1 => Just(TransactionKind::Chargeback),
+        ],
+        0..clients.max(1),
+        1..=400u32,
+        any::<usize>(),
+        proptest::option::weighted(0.1, 1..=50u32),
+        proptest::bool::weighted(0.1),
+    )
+        .prop_map(
+            |(kind, client, quarters, reference, reuse_tx, other_client)| Step {
+                kind,
+                client,
+                quarters,
+                reference,
+                reuse_tx,
+                other_client,
+            },
+        )
+}
+
+/// Strategy of up to `count` transactions of `clients` clients (see [step])
+pub fn transactions(count: usize, clients: u16) -> impl Strategy<Value = Vec<Transaction>> {
+    vec(step(clients), 0..=count).prop_map(move |steps| {
+        let mut builder = TransactionsBuilder::new(clients);
+        steps.into_iter().filter_map(|s| builder.build(s)).collect()
+    })
+}
+
+/// Turn [Step]s into transactions, in order (see [TransactionsBuilder::build])
+#[derive(Debug)]
+pub struct TransactionsBuilder {
+    next_tx: u32,
+    deposits: Vec<Vec<u32>>, // index: client id, v: tx ids of its deposits
+}
+
+impl TransactionsBuilder {
+    pub fn new(clients: u16) -> Self {
+        Self {
+            next_tx: FIRST_TX,
+            deposits: vec![vec![]; clients.max(1) as usize],
+        }
+    }
+
+    /// Transaction of a step (None: a dispute, resolve or chargeback without any deposit
+    /// to reference)
+    pub fn build(&mut self, step: Step) -> Option<Transaction> {
+        match step.kind {
+            TransactionKind::Deposit | TransactionKind::Withdrawal => {
+                let tx = step.reuse_tx.unwrap_or_else(|| {
+                    self.next_tx += 1;
+                    self.next_tx
+                });
+                if step.kind == TransactionKind::Deposit {
+                    self.deposits[step.client as usize].push(tx);
+                }
+                let amount = Some(0.25 * step.quarters as f64);
+                Some(Transaction::new(step.kind, step.client, tx, amount))
+            }
+            kind => {
+                let clients = self.deposits.len();
+                let (owner, reference) = if step.other_client {
+                    (step.reference % clients, step.reference / clients)
+                } else {
+                    (step.client as usize, step.reference)
+                };
+                let deposits = &self.deposits[owner];
+                if deposits.is_empty() {
+                    return None;
+                }
+                let tx = deposits[reference % deposits.len()];
+                Some(Transaction::new(kind, step.client, tx, None))
+            }
+        }
+    }
+}
@@ -0,0 +1,72 @@
+//! End to end: the binary is run on csv files, checking its output & exit code
+
+use std::process::{Command, Output};
+
+use homework_toy_pay::accounts::ACCOUNT_CSV_HEADER;
+
+/// Run the binary with these arguments (until it exits)
+fn run(args: &[&str]) -> std::io::Result<Output> {
+    Command::new(env!("CARGO_BIN_EXE_homework_toy_pay"))
+        .args(args)
+        .output()
+}
+
+#[test]
+fn cli_sample_1() -> Result<(), Box<dyn std::error::Error>> {
+    let output = run(&["resources/sample_1.csv"])?;
+    assert_eq!(output.status.code(), Some(0));
+
+    let stdout = String::from_utf8(output.stdout)?;
+    let mut lines: Vec<&str> = stdout.lines().collect();
+    lines[1..].sort();
+    assert_eq!(
+        lines,
+        [
+            ACCOUNT_CSV_HEADER,
+            "1,,1.5000,0.0000,1.5000,false,0",
+            "2,,0.1000,0.0000,0.1000,false,0",
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn cli_exit_codes() -> Result<(), Box<dyn std::error::Error>> {
+    for (args, code) in [
+        (vec![], 1),
+        (vec!["--unknown-option", "resources/sample_1.csv"], 1),
+        (vec!["resources/does_not_exist.csv"], 2),
+        (vec!["resources/sample_1_with_errors.csv"], 3),
+        (
+            vec!["--fail-on-lock", "resources/sample_5_chargeback.csv"],
+            5,
+        ),
+    ] {
+        let output = run(&args)?;
+        assert_eq!(output.status.code(), Some(code), "args: {:?}", args);
+        if code != 5 {
+            assert!(output.stdout.is_empty(), "args: {:?}", args);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn cli_gzip_input() -> Result<(), Box<dyn std::error::Error>> {
+    // Decompressed on the fly: same output as the plain csv file
+    let plain = run(&["resources/sample_1.csv"])?;
+    let output = run(&["resources/sample_1.csv.gz"])?;
+    assert_eq!(output.status.code(), Some(0));
+    assert!(!output.stdout.is_empty());
+
+    let sorted_lines = |stdout: Vec<u8>| -> Result<Vec<String>, std::string::FromUtf8Error> {
+        let mut lines: Vec<String> = String::from_utf8(stdout)?
+            .lines()
+            .map(String::from)
+            .collect();
+        lines.sort();
+        Ok(lines)
+    };
+    assert_eq!(sorted_lines(output.stdout)?, sorted_lines(plain.stdout)?);
+    Ok(())
+}
@@ -0,0 +1,61 @@
+//! `--listen` mode: transactions are streamed over a local tcp connection
+#![cfg(feature = "net")]
+
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use homework_toy_pay::accounts::ACCOUNT_CSV_HEADER;
+
+#[test]
+fn listen_single_connection() -> Result<(), Box<dyn std::error::Error>> {
+    // Find a free local port
+    let addr = TcpListener::bind("127.0.0.1:0")?.local_addr()?;
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_homework_toy_pay"))
+        .args(["--listen", &addr.to_string()])
+        .stdout(Stdio::piped())
+        .spawn()?;
+
+    // Wait for the program to listen
+    let start = Instant::now();
+    let mut stream = loop {
+        match TcpStream::connect(addr) {
+            Ok(stream) => break stream,
+            Err(_) if start.elapsed() < Duration::from_secs(10) => {
+                thread::sleep(Duration::from_millis(20));
+            }
+            Err(e) => {
+                child.kill()?;
+                return Err(e.into());
+            }
+        }
+    };
+
+    stream.write_all(b"type,client,tx,amount\n")?;
+    stream.write_all(b"deposit,1,1,1.0\ndeposit,2,2,2.0\n")?;
+    stream.write_all(b"withdrawal,1,3,0.5\n")?;
+    stream.shutdown(Shutdown::Write)?;
+
+    let mut output = String::new();
+    child
+        .stdout
+        .take()
+        .ok_or("No stdout")?
+        .read_to_string(&mut output)?;
+    assert!(child.wait()?.success());
+
+    let mut lines: Vec<&str> = output.lines().collect();
+    lines[1..].sort();
+    assert_eq!(
+        lines,
+        [
+            ACCOUNT_CSV_HEADER,
+            "1,,0.5000,0.0000,0.5000,false,0",
+            "2,,2.0000,0.0000,2.0000,false,0",
+        ]
+    );
+    Ok(())
+}